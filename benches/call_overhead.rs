@@ -0,0 +1,129 @@
+// Measures the cost the profiler adds to a call of a small Lua function.
+//
+// The module is loaded the way Lua programs load it, so build it first: `cargo bench` does that.
+use criterion::{criterion_group, criterion_main, Criterion};
+use lua::{State, ThreadStatus};
+
+const CALLS: usize = 1000;
+
+const SETUP: &str = r#"
+package.cpath = "./target/release/lib?.so;./target/release/?.dll;" .. package.cpath
+local lprofile = require("liblprofile")
+
+local function f(x)
+  return x + 1
+end
+
+local function run()
+  local x = 0
+
+  for _ = 1, CALLS, 1 do
+    x = f(x)
+  end
+
+  return x
+end
+
+function plain()
+  run()
+end
+
+function profiled()
+  lprofile.Profiler()(run)
+end
+
+function profiledSelfOnly()
+  lprofile.Profiler({selfOnly = true})(run)
+end
+
+function profiledTsc()
+  lprofile.Profiler({clock = "tsc"})(run)
+end
+
+function profiledCallsOnly()
+  lprofile.Profiler({callsOnly = true})(run)
+end
+
+-- the sessions of a game loop, which reuse the profiler
+local reused = lprofile.Profiler()
+
+function profiledReused()
+  reused(run)
+end
+
+local frames = lprofile.Profiler()
+
+function profiledFrame()
+  frames:frame(run)
+end
+
+-- every closure is a separate function, whose name is looked up when it's first called
+local closures = {}
+
+for i = 1, CALLS, 1 do
+  closures[i] = function(x)
+    return x + i
+  end
+end
+
+local function runClosures()
+  local x = 0
+
+  for i = 1, CALLS, 1 do
+    x = closures[i](x)
+  end
+
+  return x
+end
+
+function profiledClosures()
+  lprofile.Profiler()(runClosures)
+end
+
+function profiledClosuresSkipNames()
+  lprofile.Profiler({skipNames = true})(runClosures)
+end
+"#;
+
+fn setup() -> State {
+    let mut state = State::new();
+    state.open_libs();
+
+    let code = SETUP.replace("CALLS", &CALLS.to_string());
+
+    match state.do_string(&code) {
+        ThreadStatus::Ok => state,
+        _ => panic!("{}", state.to_str(-1).unwrap_or("failed to load the module")),
+    }
+}
+
+fn bench(c: &mut Criterion) {
+    let mut state = setup();
+    let mut group = c.benchmark_group("1000 calls");
+
+    let names = [
+        "plain",
+        "profiled",
+        "profiledSelfOnly",
+        "profiledCallsOnly",
+        "profiledTsc",
+        "profiledReused",
+        "profiledFrame",
+        "profiledClosures",
+        "profiledClosuresSkipNames",
+    ];
+
+    for name in &names {
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                state.get_global(name);
+                state.call(0, 0);
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
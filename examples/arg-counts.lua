@@ -0,0 +1,62 @@
+local lprofile = require("liblprofile")
+
+if _VERSION == "Lua 5.1" then
+  -- Lua 5.1 doesn't report the parameters of the functions
+  local ok, err = pcall(lprofile.Profiler, {argCounts = true})
+  assert(not ok and err:find("option 'argCounts' isn't supported with Lua 5.1", 1, true), err)
+  print("OK")
+
+  return
+end
+
+local function fixed(a, b)
+  return a, b
+end
+
+local function variadic(first, ...)
+  return select("#", ...)
+end
+
+local result = lprofile.Profiler({argCounts = true})(function()
+  fixed(1)
+  fixed(1, 2)
+  fixed(1, 2, 3)
+
+  variadic()
+  variadic(1)
+  variadic(1, 2, 3)
+  variadic(1, 2, 3)
+
+  math.max(1)
+  math.max(1, 2, 3)
+  math.max(1, 2, 3)
+end)
+
+local function find(pattern)
+  for _, v in ipairs(result) do
+    if v.name:find(pattern) then
+      return v
+    end
+  end
+end
+
+-- the fixed parameters are adjusted before the hook sees them
+local counts = find("function fixed ").argCounts
+assert(counts[2] == 3, counts[2])
+assert(counts[1] == nil and counts[3] == nil)
+
+-- the varargs are counted as they were passed
+counts = find("function variadic ").argCounts
+assert(counts[1] == 2, counts[1])
+assert(counts[3] == 2, counts[3])
+assert(counts[0] == nil and counts[2] == nil)
+
+-- C functions get the arguments as passed
+counts = find("function max ").argCounts
+assert(counts[1] == 1 and counts[3] == 2)
+
+-- nothing is counted without the option
+local plain = lprofile.Profiler()(fixed, 1)
+assert(plain.root.argCounts == nil)
+
+print("OK")
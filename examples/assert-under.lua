@@ -0,0 +1,60 @@
+local lprofile = require("liblprofile")
+
+local function fast(n)
+  return n + 1
+end
+
+local function slow()
+  local deadline = os.clock() + 0.05
+
+  while os.clock() < deadline do end
+end
+
+local function run(n)
+  for i = 1, n, 1 do
+    fast(i)
+  end
+
+  slow()
+end
+
+local function nameOf(pattern)
+  local result = lprofile.Profiler()(run, 1)
+
+  for _, v in ipairs(result) do
+    if v.name:find(pattern) then
+      return v.name
+    end
+  end
+end
+
+local fastName, slowName = nameOf("function fast "), nameOf("function slow ")
+
+-- within the budgets, the result is returned
+local result = lprofile.assertUnder(run, {[fastName] = 10, [slowName] = 10}, 100)
+assert(result:get(fastName).calls == 100)
+
+-- over a budget, the error names the function and the excess
+local ok, err = pcall(lprofile.assertUnder, run, {[fastName] = 10, [slowName] = 0.001}, 100)
+assert(not ok)
+assert(err.code == "BUDGET_EXCEEDED", tostring(err))
+assert(err.message:find(slowName, 1, true), err.message)
+assert(err.message:find("over its budget of 0.001000 s", 1, true), err.message)
+assert(not err.message:find(fastName, 1, true), err.message)
+
+-- every function over its budget is reported
+ok, err = pcall(lprofile.assertUnder, run, {[fastName] = 1e-9, [slowName] = 1e-9}, 100)
+assert(not ok and err.code == "BUDGET_EXCEEDED")
+assert(err.message:find(fastName, 1, true) and err.message:find(slowName, 1, true))
+
+ok, err = pcall(lprofile.assertUnder, run, {["no such function"] = 1}, 1)
+assert(not ok and err.code == "NOT_FOUND", tostring(err))
+
+ok, err = pcall(lprofile.assertUnder, run, {[fastName] = -1}, 1)
+assert(not ok and tostring(err):find("must be a positive number"), tostring(err))
+
+-- the errors of the function propagate
+ok, err = pcall(lprofile.assertUnder, error, {}, "oops")
+assert(not ok and err == "oops", tostring(err))
+
+print("OK")
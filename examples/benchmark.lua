@@ -0,0 +1,42 @@
+local lprofile = require("liblprofile")
+
+local calls = 0
+
+local function square(t, n)
+  calls = calls + 1
+  t.last = n * n
+
+  return t.last
+end
+
+local args = {}
+local stats, result = lprofile.benchmark(square, {warmup = 5, iterations = 20}, args, 3)
+
+-- the warmup calls run too, with the same arguments
+assert(calls == 25, calls)
+assert(args.last == 9)
+
+assert(stats.count == 20, stats.count)
+assert(stats.min <= stats.mean and stats.mean <= stats.max)
+assert(stats.stddev >= 0)
+
+-- only the profiled calls are in the result
+assert(result.root.calls == 20, result.root.calls)
+
+-- the options and the arguments may be left out
+local bare = 0
+stats, result = lprofile.benchmark(function()
+  bare = bare + 1
+end)
+
+assert(bare == 110, bare)
+assert(stats.count == 100 and result.root.calls == 100, stats.count)
+
+for _, options in ipairs({{warmup = 0}, {iterations = 1.5}, {iterations = "many"}}) do
+  assert(not pcall(lprofile.benchmark, square, options, args, 1))
+end
+
+-- the errors of the function are propagated
+assert(not pcall(lprofile.benchmark, error, {iterations = 1}, "oops"))
+
+print("OK")
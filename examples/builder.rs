@@ -0,0 +1,122 @@
+// Configures profilers with `ProfilerBuilder` and runs a script with each of them, checking that
+// the options reach the results.
+use std::time::Duration;
+
+use lprofile::{ProfilerBuilder, ProfilingResult, Recursion, TailCalls, Threshold};
+use lua::{State, ThreadStatus};
+
+const WORK: &str = r#"
+local function f(n)
+  local t = {}
+
+  for i = 1, n do
+    t[i] = i * i
+  end
+
+  return #t
+end
+
+-- in the protected mode, the result follows the status
+result = select(-1, profiler(f, 100000))
+assert(result.root, "no root entry")
+assert(result.root.calls == 1, "unexpected number of calls")
+"#;
+
+fn run(builder: ProfilerBuilder, check: &str) -> Result<(), String> {
+    let mut state = State::new();
+    state.open_libs();
+
+    builder.build(&mut state);
+    state.set_global("profiler");
+
+    match state.do_string(&format!("{}\n{}", WORK, check)) {
+        ThreadStatus::Ok => Ok(()),
+        _ => Err(state.to_str(-1).unwrap_or("unknown error").to_owned()),
+    }
+}
+
+// Reads the result of a session from Rust rather than from the result table.
+fn check_native_result() {
+    let mut state = State::new();
+    state.open_libs();
+
+    ProfilerBuilder::new().build(&mut state);
+    state.set_global("profiler");
+
+    if state.do_string(WORK) != ThreadStatus::Ok {
+        panic!("native result failed: {}", state.to_str(-1).unwrap_or("unknown error"));
+    }
+
+    state.get_global("profiler");
+    let result = ProfilingResult::from_profiler(&mut state, -1).expect("no finished session");
+    state.pop(1);
+
+    let root = result.get(result.root().unwrap()).unwrap();
+    assert_eq!(root.calls(), 1);
+    assert!(root.total_time() <= result.total_time().unwrap());
+    assert!(result.warnings().is_empty());
+
+    let sorted = result.by_self_time();
+    assert_eq!(sorted.len(), result.entries().count());
+    assert!(sorted
+        .windows(2)
+        .all(|pair| pair[0].1.total_self_time() >= pair[1].1.total_self_time()));
+
+    // not a profiler
+    state.push(1);
+    assert!(ProfilingResult::from_profiler(&mut state, -1).is_none());
+    state.pop(1);
+
+    assert!(result.to_string().contains("session time"));
+}
+
+fn main() {
+    let cases = [
+        ("default", ProfilerBuilder::new(), "assert(result.root.samples == nil)"),
+        (
+            "sampling",
+            ProfilerBuilder::new().sample(100),
+            "assert(result.root.samples ~= nil)",
+        ),
+        (
+            "memory",
+            ProfilerBuilder::new().memory(true).code_size(true),
+            "assert(result.root.bytesAllocated > 0 and result.root.codeSize > 0)",
+        ),
+        (
+            "self-only",
+            ProfilerBuilder::new().self_only(true).precision(3),
+            "assert(result.root.totalTime == nil)",
+        ),
+        (
+            "threshold",
+            ProfilerBuilder::new()
+                .threshold(Threshold::Time(Duration::new(0, 0)))
+                .recursion(Recursion::All)
+                .tail_calls(TailCalls::Replace),
+            "assert(#result.violations > 0)",
+        ),
+        (
+            "window",
+            ProfilerBuilder::new()
+                .window(Duration::from_secs(10))
+                .max_functions(8),
+            "assert(result.recent)",
+        ),
+        (
+            "protected",
+            ProfilerBuilder::new().protected(true),
+            "assert(profiler(error, 'oops') == false)",
+        ),
+    ];
+
+    for (name, builder, check) in cases.iter().cloned() {
+        if let Err(msg) = run(builder, check) {
+            panic!("{} failed: {}", name, msg);
+        }
+    }
+
+    check_native_result();
+
+    println!("OK");
+}
@@ -0,0 +1,59 @@
+local lprofile = require("liblprofile")
+
+-- table.sort is a C function that calls back into Lua: the comparator runs above a C frame
+local comparisons = 0
+
+local function comparator(lhs, rhs)
+  comparisons = comparisons + 1
+
+  local x = 0
+
+  for i = 1, 100, 1 do
+    x = x + i
+  end
+
+  return lhs < rhs
+end
+
+local result = lprofile.Profiler()(function()
+  for _ = 1, 10, 1 do
+    local t = {}
+
+    for i = 1, 100, 1 do
+      t[i] = (i * 7919) % 101
+    end
+
+    table.sort(t, comparator)
+  end
+end)
+
+local line = debug.getinfo(comparator, "S").linedefined
+local cmp, sort
+
+for _, v in ipairs(result) do
+  if v.name:find(":" .. line .. ")", 1, true) then
+    cmp = v
+  elseif v.name:find("sort", 1, true) and v.isBuiltin then
+    sort = v
+  end
+end
+
+assert(cmp, "the comparator is not in the profile")
+assert(sort, "table.sort is not in the profile")
+assert(cmp.calls == comparisons, "the comparator calls are miscounted")
+assert(sort.calls == 10)
+
+-- the comparator runs within table.sort, but its time is not the sort's self-time
+assert(cmp.totalTime <= sort.totalTime, "the comparator outlived table.sort")
+assert(sort.totalSelfTime + cmp.totalTime <= sort.totalTime * 1.001 + 1e-6,
+  "the comparator's time is attributed to table.sort")
+assert(#result.warnings == 0, table.concat(result.warnings, "\n"))
+
+-- the sort is the only C function, and the root the only Lua function besides the comparator
+local summary = result.summary
+assert(math.abs(summary.cTime - sort.totalSelfTime) < 1e-9)
+assert(math.abs(summary.luaTime - cmp.totalSelfTime - result.root.totalSelfTime) < 1e-6)
+assert(summary.mainTime == 0)
+assert(math.abs(summary.cShare + summary.luaShare - 1) < 1e-6)
+
+print("OK")
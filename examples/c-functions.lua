@@ -0,0 +1,53 @@
+local lprofile = require("liblprofile")
+
+local function lua(s)
+  return s
+end
+
+local result = lprofile.Profiler()(function()
+  -- a light C function
+  local n = string.len("abc")
+
+  -- C closures with upvalues
+  for _ in ("a b"):gmatch("%a") do end
+
+  local co = coroutine.wrap(function() coroutine.yield(1) end)
+  co()
+
+  return lua(n)
+end)
+
+local cFunctions = {}
+
+for _, v in ipairs(result) do
+  if v.domain == "C" then
+    table.insert(cFunctions, v)
+  elseif v.domain == "Lua" then
+    assert(v.line and v.line > 0, v.name)
+    assert(v.name:find(":" .. v.line .. ")$"), v.name)
+  end
+end
+
+local function find(pattern)
+  for _, v in ipairs(cFunctions) do
+    if v.name:find(pattern) then
+      return v
+    end
+  end
+end
+
+-- string.len, gmatch, the gmatch iterator, wrap, and the wrapped coroutine
+assert(find("function len "), "no string.len")
+assert(find("function gmatch "), "no gmatch")
+assert(find("function co "), "no coroutine.wrap closure")
+
+for _, v in ipairs(cFunctions) do
+  assert(v.line == nil, v.name)
+  assert(v.source == "[C]" and v.sourceType == "C" and v.isBuiltin, v.name)
+  -- no line number, and the source is shown as is
+  assert(v.name:find("C function"), v.name)
+  assert(v.name:find("%(%[C%]%)$"), v.name)
+  assert(not v.name:find(":%-?%d+%)$"), v.name)
+end
+
+print("OK")
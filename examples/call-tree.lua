@@ -0,0 +1,52 @@
+local lprofile = require("liblprofile")
+
+local function leaf()
+  local x = 0
+
+  for i = 1, 1000, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function walk(depth)
+  leaf()
+
+  if depth > 0 then
+    walk(depth - 1)
+  end
+end
+
+local profiler = lprofile.Profiler()
+profiler(function()
+  walk(10)
+  leaf()
+end)
+
+local tree = profiler:tree()
+local lines = {}
+
+for line in tree:gmatch("[^\n]+") do
+  lines[#lines + 1] = line
+end
+
+-- the profiled function comes first, unindented
+assert(not lines[1]:find("^ "), tree)
+
+-- the recursion is collapsed after its first level
+local recursive = 0
+
+for _, line in ipairs(lines) do
+  if line:find("[recursive]", 1, true) then
+    recursive = recursive + 1
+    assert(line:find("function walk", 1, true), tree)
+  end
+end
+
+assert(recursive == 1, tree)
+
+-- the root, walk, the leaf under walk, the recursion, and the leaf under the root
+assert(#lines == 5, tree)
+
+print("OK")
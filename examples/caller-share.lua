@@ -0,0 +1,61 @@
+local lprofile = require("liblprofile")
+
+local function spin(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function heavy()
+  return spin(1000000)
+end
+
+local function light()
+  return spin(10000)
+end
+
+local result = lprofile.Profiler()(function()
+  heavy()
+  light()
+end)
+
+local shares, sum = {}, 0
+
+for _, edge in ipairs(result.edges) do
+  if edge.caller == result.root then
+    shares[edge.callee] = edge.callerShare
+    sum = sum + edge.callerShare
+  end
+
+  assert(edge.callerShare >= 0 and edge.callerShare <= 1, edge.callerShare)
+  assert(math.abs(edge.callerShare - edge.totalTime / edge.caller.totalTime) < 1e-9)
+end
+
+local function shareOf(f)
+  local line = debug.getinfo(f, "S").linedefined
+
+  for entry, share in pairs(shares) do
+    if entry.name:find(":" .. line .. ")", 1, true) then
+      return share
+    end
+  end
+end
+
+local heavyShare, lightShare = shareOf(heavy), shareOf(light)
+
+-- the calls of the root are most of its time, and the heavy one dominates
+assert(sum <= 1 and sum > 0.9, sum)
+assert(heavyShare and lightShare and heavyShare > lightShare * 10)
+
+-- the share is left out with the total times
+local selfOnly = lprofile.Profiler({selfOnly = true})(heavy)
+
+for _, edge in ipairs(selfOnly.edges) do
+  assert(edge.callerShare == nil)
+end
+
+print("OK")
@@ -0,0 +1,65 @@
+local lprofile = require("liblprofile")
+
+local function leaf(n)
+  return n + 1
+end
+
+local function branch(n)
+  for i = 1, n, 1 do
+    leaf(i)
+  end
+end
+
+local function run()
+  for i = 1, 10, 1 do
+    branch(i)
+  end
+end
+
+local full = lprofile.Profiler()(run)
+local result = lprofile.Profiler({callsOnly = true})(run)
+
+local function byName(result)
+  local entries = {}
+
+  for _, v in ipairs(result) do
+    entries[v.name] = v
+  end
+
+  return entries
+end
+
+local fullNames = byName(full)
+local names = byName(result)
+
+-- the calls are counted like in the full mode
+for name, v in pairs(names) do
+  assert(fullNames[name], name)
+  assert(v.calls == fullNames[name].calls, name)
+end
+
+assert(result.root.calls == 1)
+assert(names[full.root.name] == result.root)
+
+for _, v in ipairs(full) do
+  assert(v == full.host or names[v.name], v.name)
+end
+
+-- nothing but the calls is tracked
+for _, v in ipairs(result) do
+  assert(v.id and v.name and v.calls)
+  assert(v.totalTime == nil and v.totalSelfTime == nil and v.source == nil)
+end
+
+assert(result.host == nil)
+assert(#result.edges == 0)
+assert(result.criticalPath == nil)
+assert(result.totalTime)
+assert(result.summary.returnEvents == 0)
+assert(result.summary.reliability.mode == "calls")
+
+local ok, err = pcall(lprofile.Profiler, {callsOnly = true, memory = true})
+assert(not ok)
+assert(err:find("cannot be combined with 'memory'"), err)
+
+print("OK")
@@ -0,0 +1,59 @@
+local lprofile = require("liblprofile")
+
+local function deep(n)
+  if n == 0 then
+    error("too deep")
+  end
+
+  -- not a tail call, so every level keeps its frame until the error unwinds it
+  local result = deep(n - 1)
+
+  return result
+end
+
+local function work()
+  local x = 0
+
+  for i = 1, 1000000, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function guarded()
+  assert(not pcall(deep, 20))
+  assert(not xpcall(deep, debug.traceback, 20))
+
+  -- the unwound frames must be gone by now
+  return work()
+end
+
+local result = lprofile.Profiler()(guarded)
+
+local function entryOf(name)
+  for _, v in ipairs(result) do
+    if v.name:find("function " .. name .. " ", 1, true) then
+      return v
+    end
+  end
+end
+
+local deepEntry, workEntry = entryOf("deep"), entryOf("work")
+
+assert(deepEntry.calls == 42, deepEntry.calls)
+assert(deepEntry.errors == 42, deepEntry.errors)
+
+-- the unwound frames end at the next event, long before the work after the catch
+assert(deepEntry.totalTime < workEntry.totalTime / 10, deepEntry.totalTime)
+
+-- and the work is attributed to the function that caught the error
+for _, edge in ipairs(result.edges) do
+  if edge.callee == workEntry then
+    assert(edge.caller == result.root, edge.caller.name)
+  end
+end
+
+assert(#result.warnings == 0, table.concat(result.warnings, "\n"))
+
+print("OK")
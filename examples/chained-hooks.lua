@@ -0,0 +1,61 @@
+local lprofile = require("liblprofile")
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local lines, counts = 0, 0
+
+-- e.g. a sandbox limiting the number of instructions
+debug.sethook(function(event)
+  if event == "line" then
+    lines = lines + 1
+  else
+    counts = counts + 1
+  end
+end, "l", 1000)
+
+lprofile.Profiler({chainHooks = true, sample = 100})(work, 100000)
+assert(lines > 0, "the line events weren't forwarded")
+assert(counts > 0, "the count events weren't forwarded")
+
+-- the previous hook is reinstalled after the session
+local hook, mask, count = debug.gethook()
+assert(hook and mask == "l" and count == 1000)
+
+-- by default, the previous hook is paused during the session
+lines, counts = 0, 0
+lprofile.Profiler()(work, 100000)
+assert(lines == 0 and counts == 0)
+
+-- the chained hook may raise an error, which ends the session like one of the profiled function
+local budget = 10
+
+debug.sethook(function()
+  budget = budget - 1
+
+  if budget < 0 then
+    error("out of instructions")
+  end
+end, "", 1000)
+
+local profiler = lprofile.Profiler({chainHooks = true})
+local ok, err = pcall(profiler, work, 1e7)
+assert(not ok and tostring(err):find("out of instructions", 1, true), err)
+
+-- the error unwinds `work` like any other, and the profiler still accounts for the call
+local root = profiler:result().root
+assert(root.calls == 1 and root.errors == 1, root.calls .. ", " .. root.errors)
+
+hook = debug.gethook()
+assert(hook, "the previous hook wasn't reinstalled")
+
+debug.sethook()
+
+print("OK")
@@ -0,0 +1,15 @@
+local function fib(n)
+  if n < 2 then
+    return n
+  end
+
+  return fib(n - 1) + fib(n - 2)
+end
+
+local a = 0
+
+for i = 1, 10e6, 1 do
+  a = a + i
+end
+
+return fib(25) + a
@@ -0,0 +1,14 @@
+local lprofile = require("liblprofile")
+
+local resolution = lprofile.clockResolution
+assert(math.type(resolution) == "float")
+-- no clock is that precise, and none used for profiling is that coarse
+assert(resolution > 0 and resolution < 0.1, resolution)
+
+local result = lprofile.Profiler({precision = 3})(function() end)
+
+-- measured once, and not rounded
+assert(result.summary.clockResolution == resolution)
+
+print(("clock resolution: %.9f s"):format(resolution))
+print("OK")
@@ -0,0 +1,48 @@
+local lprofile = require("liblprofile")
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function run()
+  for _ = 1, 10, 1 do
+    work(100000)
+  end
+end
+
+local system = lprofile.Profiler()(run)
+assert(system.summary.clock == "system")
+assert(system.summary.clockResolution == lprofile.clockResolution)
+
+-- the counter may be unavailable, in which case the system clock is used
+local tsc = lprofile.Profiler({clock = "tsc"})(run)
+local clock = tsc.summary.clock
+assert(clock == "tsc" or clock == "system", clock)
+assert(tsc.summary.clockResolution > 0)
+
+-- the system clock never goes backwards, and the counter rarely does
+assert(system.summary.clockAnomalies == 0)
+assert(math.type(tsc.summary.clockAnomalies) == "integer" and tsc.summary.clockAnomalies >= 0)
+
+-- both clocks measure the same thing
+for _, result in ipairs({system, tsc}) do
+  assert(result.root.totalTime > 0)
+  assert(result.root.totalTime <= result.totalTime)
+  assert(result.root.totalSelfTime <= result.root.totalTime)
+end
+
+local ratio = tsc.root.totalTime / system.root.totalTime
+assert(ratio > 0.2 and ratio < 5, ratio)
+
+local ok, err = pcall(lprofile.Profiler, {clock = "cpu"})
+assert(not ok)
+assert(err:find("option 'clock' must be one of: system, tsc"), err)
+
+print(("clock: %s, resolution: %.9f s"):format(clock, tsc.summary.clockResolution))
+print("OK")
@@ -0,0 +1,87 @@
+local lprofile = require("liblprofile")
+
+local isEven, isOdd
+
+function isEven(n)
+  if n == 0 then
+    return true
+  end
+
+  local result = isOdd(n - 1)
+
+  return result
+end
+
+function isOdd(n)
+  if n == 0 then
+    return false
+  end
+
+  local result = isEven(n - 1)
+
+  return result
+end
+
+local function fact(n)
+  if n <= 1 then
+    return 1
+  end
+
+  local result = n * fact(n - 1)
+
+  return result
+end
+
+local function leaf() end
+
+local profiler = lprofile.Profiler()
+
+profiler(function()
+  for _ = 1, 10, 1 do
+    isEven(20)
+    fact(10)
+    leaf()
+  end
+end)
+
+local cycles = profiler:collapseRecursion()
+assert(#cycles == 2, #cycles)
+
+local byMembers = {}
+
+for i, cycle in ipairs(cycles) do
+  assert(cycle.name == ("<cycle %d>"):format(i))
+
+  local names = {}
+
+  for _, member in ipairs(cycle.members) do
+    assert(member.id:match("^0x%x+$"))
+    names[#names + 1] = member.name:match("function (%w+) ")
+  end
+
+  table.sort(names)
+  byMembers[table.concat(names, ",")] = cycle
+end
+
+-- isEven and isOdd call each other: 10 outer calls, 20 nested ones each time
+local mutual = assert(byMembers["isEven,isOdd"])
+assert(mutual.calls == 10, mutual.calls)
+assert(mutual.recursiveCalls == 200, mutual.recursiveCalls)
+
+-- fact calls itself
+local direct = assert(byMembers["fact"])
+assert(direct.calls == 10 and direct.recursiveCalls == 90, direct.recursiveCalls)
+
+-- the time of the whole recursion is counted once
+for _, cycle in ipairs(cycles) do
+  assert(cycle.totalTime >= cycle.totalSelfTime)
+  assert(cycle.totalTime <= profiler:result().totalTime)
+end
+
+for i = 2, #cycles, 1 do
+  assert(cycles[i - 1].totalTime >= cycles[i].totalTime)
+end
+
+assert(not pcall(lprofile.Profiler().collapseRecursion, lprofile.Profiler()))
+
+print("OK")
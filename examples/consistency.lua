@@ -0,0 +1,44 @@
+local lprofile = require("liblprofile")
+
+local function fib(n)
+  if n < 2 then
+    return n
+  end
+
+  return fib(n - 1) + fib(n - 2)
+end
+
+local function countdown(n)
+  if n > 0 then
+    return countdown(n - 1)
+  end
+end
+
+-- the accounting of real sessions is consistent whatever the options
+for _, options in ipairs({{}, {recursion = "all"}, {tailCalls = "replace"}, {sample = 100}}) do
+  local result = lprofile.Profiler(options)(function()
+    fib(15)
+    countdown(100)
+  end)
+
+  for _, v in ipairs(result) do
+    assert(v.totalSelfTime <= v.totalTime, v.name)
+  end
+
+  assert(#result.warnings == 0, table.concat(result.warnings, "\n"))
+end
+
+-- a result whose self-time exceeds the total time is flagged
+local broken = table.concat({
+  "lprofile 1",
+  "time\t1000",
+  "function\t1\t500\t900\tLua\tfile\tbroken.lua\t1\t\tbroken",
+  "function\t1\t900\t500\tLua\tfile\tbroken.lua\t5\t\tfine",
+  "",
+}, "\n")
+
+local result = lprofile.deserialize(broken)
+assert(#result.warnings == 1, #result.warnings)
+assert(result.warnings[1]:find("broken", 1, true), result.warnings[1])
+
+print("OK")
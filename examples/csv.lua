@@ -0,0 +1,110 @@
+local lprofile = require("liblprofile")
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+-- the name has a comma, so the cell is quoted
+local weird = load("local work = ... return work(1000)", "=a, \"b\"")
+
+local profiler = lprofile.Profiler()
+
+local ok, err = pcall(profiler.csv, profiler)
+assert(not ok and err.code == "NOT_FINISHED")
+
+profiler(function()
+  work(100000)
+  weird(work)
+end)
+
+-- splits a line into its cells, unquoting them
+local function parse(line)
+  local cells = {}
+  local i = 1
+
+  while i <= #line + 1 do
+    if line:sub(i, i) == '"' then
+      local cell = ""
+      i = i + 1
+
+      while true do
+        local quote = line:find('"', i, true)
+        cell = cell .. line:sub(i, quote - 1)
+
+        if line:sub(quote + 1, quote + 1) == '"' then
+          cell = cell .. '"'
+          i = quote + 2
+        else
+          i = quote + 2
+          break
+        end
+      end
+
+      cells[#cells + 1] = cell
+    else
+      local comma = line:find(",", i, true) or #line + 1
+      cells[#cells + 1] = line:sub(i, comma - 1)
+      i = comma + 1
+    end
+  end
+
+  return cells
+end
+
+local csv = profiler:csv()
+local rows = {}
+
+for line in csv:gmatch("[^\n]+") do
+  rows[#rows + 1] = parse(line)
+end
+
+local header = rows[1]
+assert(table.concat(header, ",", 1, 8) == "id,name,source,line,calls,totalTime,cumulativeTime,totalSelfTime")
+
+local column = {}
+
+for i, name in ipairs(header) do
+  column[name] = i
+end
+
+local result = profiler:result()
+assert(#rows - 1 == result.summary.functionCount)
+
+local found = false
+
+for i = 2, #rows, 1 do
+  local row = rows[i]
+  assert(#row == #header, i)
+
+  if row[column.source] == 'a, "b"' then
+    found = true
+    assert(row[column.calls] == "1")
+  end
+
+  -- sorted by the self-time
+  if i > 2 then
+    assert(tonumber(row[column.totalSelfTime]) <= tonumber(rows[i - 1][column.totalSelfTime]))
+  end
+end
+
+assert(found, csv)
+
+-- the columns follow the options
+profiler = lprofile.Profiler({callsOnly = true})
+profiler(work, 10)
+assert(profiler:csv():match("^[^\n]*") == "id,name,source,line,calls")
+
+profiler = lprofile.Profiler({selfOnly = true, memory = true})
+profiler(work, 10)
+local first = profiler:csv():match("^[^\n]*")
+assert(not first:find("totalTime") and first:find("bytesAllocated"), first)
+
+assert(lprofile.features.csv)
+
+print("OK")
@@ -0,0 +1,73 @@
+local lprofile = require("liblprofile")
+
+local function handleMessage(message)
+  local x = 0
+
+  for i = 1, message.size, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local messages = {
+  {type = "ping", size = 10},
+  {type = "data", size = 100000},
+  {type = "ping", size = 10},
+}
+
+local profiler = lprofile.Profiler({
+  key = function(f, message)
+    if f == handleMessage then
+      return message.type
+    end
+  end,
+})
+
+local result = profiler(function()
+  for _, message in ipairs(messages) do
+    handleMessage(message)
+  end
+end)
+
+local byName = {}
+
+for _, v in ipairs(result) do
+  byName[v.name] = v
+end
+
+-- the calls of handleMessage are split by the type of the message
+assert(byName.ping.calls == 2, "expected 2 pings, got " .. byName.ping.calls)
+assert(byName.data.calls == 1)
+assert(byName.data.sourceType == "other")
+assert(byName.data.totalSelfTime > byName.ping.totalSelfTime)
+
+-- the other functions are keyed by their identity
+assert(result.root.name ~= "ping" and result.root.name ~= "data")
+
+-- more parameters than the stack is guaranteed to hold are passed as well, including with
+-- Lua 5.1, which doesn't report their number
+local load, unpack = loadstring or load, table.unpack or unpack
+local params, args = {}, {}
+
+for i = 1, 60, 1 do
+  params[i] = "p" .. i
+  args[i] = i
+end
+
+local wide = load("return function(" .. table.concat(params, ", ") .. ") end")()
+local received
+
+lprofile.Profiler({
+  key = function(f, ...)
+    if f == wide then
+      received = {...}
+    end
+  end,
+})(function()
+  wide(unpack(args))
+end)
+
+assert(#received == 60 and received[60] == 60, #received)
+
+print("OK")
@@ -0,0 +1,49 @@
+local lprofile = require("liblprofile")
+
+local function serialized(functions)
+  local lines = {"lprofile 1", "time\t1000"}
+
+  for _, v in ipairs(functions) do
+    table.insert(lines, ("function\t%s\t100\t100\tLua\tfile\tdiff.lua\t%d\t\t%s"):format(
+      v[1], v[2], v[3]
+    ))
+  end
+
+  table.insert(lines, "")
+
+  return table.concat(lines, "\n")
+end
+
+local function byChange(deltas)
+  local changes = {}
+
+  for _, v in ipairs(deltas) do
+    assert(changes[v.change] == nil, v.change)
+    changes[v.change] = v
+  end
+
+  return changes
+end
+
+-- the functions defined on the same line are compared as one
+local before = serialized({{1, 1, "a"}, {2, 1, "b"}, {1, 5, "c"}})
+local after = serialized({{3, 1, "a"}, {4, 1, "b"}, {1, 5, "c"}})
+local deltas = lprofile.diff(before, after)
+assert(#deltas == 2, #deltas)
+
+for _, v in ipairs(deltas) do
+  assert(v.change ~= "removed" and v.change ~= "added", v.name .. ": " .. v.change)
+end
+
+assert(deltas[1].calls + deltas[2].calls == 4)
+
+-- the counts too large for a Lua integer don't overflow the differences
+local huge = "18446744073709551615"
+deltas = byChange(lprofile.diff(serialized({{huge, 1, "a"}}), serialized({{1, 5, "b"}})))
+assert(deltas.added.calls == 1, deltas.added.calls)
+assert(deltas.removed.calls == -math.maxinteger, deltas.removed.calls)
+
+deltas = lprofile.diff(serialized({{1, 1, "a"}}), serialized({{huge, 1, "a"}}))
+assert(#deltas == 1 and deltas[1].calls == math.maxinteger - 1, deltas[1].calls)
+
+print("OK")
@@ -0,0 +1,64 @@
+local profiler = require("liblprofile").Profiler()
+
+local function h(x)
+  local a = 0
+
+  for i = 1, x, 1 do
+    a = a + i
+  end
+
+  error("thrown from h: " .. a)
+end
+
+local function g(x)
+  return h(x) + 1
+end
+
+local function f(x)
+  return g(x) + 1
+end
+
+print(pcall(profiler, function()
+  f(10e6)
+end))
+
+-- the result of the failed session is kept
+local failed = profiler:result()
+assert(failed.root.calls == 1)
+assert(#failed > 0)
+
+-- the profiler can be reused after a session ends with an error
+local result = profiler(function()
+  pcall(f, 10e6)
+end)
+
+table.sort(result, function(lhs, rhs)
+  return lhs.totalTime < rhs.totalTime
+end)
+
+print("Name", "# of calls", "Total time", "Total time, excluding inner calls")
+
+for _, v in ipairs(result) do
+  print(("%s\t%d\t%.6f s\t%.6f s"):format(v.name, v.calls, v.totalTime, v.totalSelfTime))
+end
+
+print("total time:", result.totalTime)
+
+-- the error unwinds h, g and f, and their returns are accounted for all the same
+for _, name in ipairs({"h", "g", "f"}) do
+  local found = false
+
+  for _, v in ipairs(result) do
+    if v.name:find("function " .. name .. " ", 1, true) then
+      assert(v.calls == 1, name)
+      assert(v.totalSelfTime > 0, name)
+      found = true
+    end
+  end
+
+  assert(found, name)
+end
+
+assert(#result.warnings == 0, result.warnings[1])
+
+print("OK")
@@ -0,0 +1,38 @@
+local lprofile = require("liblprofile")
+
+local function mayThrow(i)
+  if i % 3 == 0 then
+    error("divisible by 3")
+  end
+
+  return i
+end
+
+local function caller(i)
+  local result = mayThrow(i)
+
+  return result
+end
+
+local result = lprofile.Profiler()(function()
+  for i = 1, 30, 1 do
+    pcall(caller, i)
+  end
+end)
+
+local function entryOf(name)
+  for _, v in ipairs(result) do
+    if v.name:find("function " .. name .. " ", 1, true) then
+      return v
+    end
+  end
+end
+
+local thrower, passer = entryOf("mayThrow"), entryOf("caller")
+
+assert(thrower.calls == 30 and thrower.errors == 10, "the errors thrown are miscounted")
+-- the error passes through the caller, so its calls end with an error too
+assert(passer.calls == 30 and passer.errors == 10, "the errors passed through are miscounted")
+assert(result.root.errors == 0)
+
+print("OK")
@@ -0,0 +1,94 @@
+// Collects the calls of a session as spans through an `EventSink`, checking that they nest and
+// match the entries of the result.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Instant;
+
+use lprofile::{EventSink, FunctionKey, ProfilerBuilder, ProfilingResult};
+use lua::{State, ThreadStatus};
+
+const WORK: &str = r#"
+local function leaf(n)
+  local x = 0
+
+  for i = 1, n do
+    x = x + i
+  end
+
+  return x
+end
+
+local function f(n)
+  return leaf(n) + leaf(n)
+end
+
+profiler(function()
+  f(1000)
+  pcall(function() error("unwound") end)
+end)
+"#;
+
+#[derive(Default)]
+struct Spans {
+    open: Vec<(FunctionKey, Instant)>,
+    closed: Vec<(FunctionKey, Instant, Instant)>,
+    max_depth: usize,
+}
+
+impl EventSink for Spans {
+    fn on_enter(&mut self, key: FunctionKey, time: Instant) {
+        self.open.push((key, time));
+        self.max_depth = self.max_depth.max(self.open.len());
+    }
+
+    fn on_exit(&mut self, key: FunctionKey, time: Instant) {
+        let (open, start) = self.open.pop().expect("an exit without an enter");
+        assert_eq!(open, key, "the calls ended out of order");
+        assert!(start <= time);
+
+        self.closed.push((key, start, time));
+    }
+}
+
+fn main() {
+    let spans = Rc::new(RefCell::new(Spans::default()));
+
+    let mut state = State::new();
+    state.open_libs();
+
+    ProfilerBuilder::new().event_sink(spans.clone()).build(&mut state);
+    state.set_global("profiler");
+
+    if state.do_string(WORK) != ThreadStatus::Ok {
+        panic!("session failed: {}", state.to_str(-1).unwrap_or("unknown error"));
+    }
+
+    state.get_global("profiler");
+    let result = ProfilingResult::from_profiler(&mut state, -1).expect("no finished session");
+    state.pop(1);
+
+    let spans = spans.borrow();
+    assert!(spans.open.is_empty(), "some calls never ended");
+    assert!(spans.max_depth >= 3);
+
+    // every call of the result is a span
+    let mut calls = HashMap::new();
+
+    for &(key, _, _) in &spans.closed {
+        *calls.entry(key).or_insert(0) += 1;
+    }
+
+    for (key, entry) in result.entries() {
+        assert_eq!(calls.get(&key).copied(), Some(entry.calls()), "{}", key.id());
+    }
+
+    let leaf = result
+        .entries()
+        .find(|(_, v)| v.name().and_then(|name| name.name()) == Some("leaf"))
+        .map(|(k, _)| k)
+        .unwrap();
+    assert_eq!(calls[&leaf], 2);
+
+    println!("OK");
+}
@@ -0,0 +1,87 @@
+local lprofile = require("liblprofile")
+
+local path = os.tmpname()
+
+local function read()
+  local file = assert(io.open(path, "rb"))
+  local data = file:read("a")
+  file:close()
+
+  return data
+end
+
+local function f(x)
+  return x + 1
+end
+
+local midway
+
+local result = lprofile.Profiler({flushFile = path, flushEvents = 100})(function()
+  for i = 1, 1000, 1 do
+    f(i)
+  end
+
+  -- the snapshots are there before the session ends
+  midway = read()
+end)
+
+assert(#result.warnings == 0, result.warnings[1])
+assert(select(2, midway:gsub("lprofile 1\n", "")) >= 10)
+
+-- the last snapshot is the whole session
+local data = read()
+local last = data:match(".*()lprofile 1\n")
+local final = lprofile.deserialize(data:sub(last))
+
+local function calls(result)
+  for _, v in ipairs(result) do
+    if v.name:find("function f ") then
+      return v.calls
+    end
+  end
+
+  return 0
+end
+
+assert(calls(final) == 1000, calls(final))
+
+-- the earlier ones have the calls made so far
+local first = lprofile.deserialize(midway:sub(1, (midway:find("lprofile 1\n", 2) or 0) - 1))
+assert(calls(first) < 1000, calls(first))
+
+os.remove(path)
+
+-- the snapshots can also be taken by time
+lprofile.Profiler({flushFile = path, flushInterval = 0.01})(function()
+  local start = os.clock()
+
+  while os.clock() - start < 0.1 do end
+end)
+
+assert(select(2, read():gsub("lprofile 1\n", "")) > 2)
+os.remove(path)
+
+-- a file that can't be written is reported in the warnings
+result = lprofile.Profiler({flushFile = path .. "/missing/file"})(f, 1)
+assert(#result.warnings == 1, #result.warnings)
+
+assert(not pcall(lprofile.Profiler, {flushEvents = 10}))
+assert(not pcall(lprofile.Profiler, {flushFile = path, stream = print}))
+
+-- the rejected options don't keep the callbacks alive
+local collected = 0
+
+local function callback()
+  local guard = setmetatable({}, {__gc = function() collected = collected + 1 end})
+
+  return function() return guard end
+end
+
+assert(not pcall(lprofile.Profiler, {flushFile = path, stream = callback(), key = callback()}))
+assert(not pcall(lprofile.Profiler, {onNewFunction = callback(), flushEvents = 0}))
+assert(not pcall(lprofile.Profiler, {resolveName = callback(), unknown = true}))
+collectgarbage()
+collectgarbage()
+assert(collected == 4, collected)
+
+print("OK")
@@ -0,0 +1,55 @@
+local lprofile = require("liblprofile")
+
+local function draw(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function update(frame)
+  if frame % 2 == 0 then
+    draw(100)
+  end
+
+  return draw(10), frame
+end
+
+local profiler = lprofile.Profiler()
+local report = {}
+
+for frame = 1, 1000, 1 do
+  local x, returned = profiler:frame(update, frame)
+  assert(x == 55 and returned == frame)
+
+  -- each frame is profiled on its own
+  profiler:result(report, "clear")
+
+  local draws = 0
+
+  for _, v in ipairs(report) do
+    if v.name:find("function draw ") then
+      draws = draws + v.calls
+    end
+  end
+
+  assert(draws == (frame % 2 == 0 and 2 or 1), draws)
+  assert(report.root.calls == 1)
+end
+
+-- errors are propagated, and the frame is still kept
+assert(not pcall(profiler.frame, profiler, function() error("boom") end))
+assert(profiler:result().root.errors == 1)
+
+-- nested calls are part of the running session
+local outer = lprofile.Profiler()(function()
+  assert(select(2, profiler:frame(update, 3)) == 3)
+end)
+
+assert(profiler:result().root.errors == 1)
+assert(#outer > 1)
+
+print("OK")
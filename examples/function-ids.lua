@@ -0,0 +1,40 @@
+local lprofile = require("liblprofile")
+
+local function f() end
+
+local seen = {}
+local profiler = lprofile.Profiler({
+  record = true,
+  onNewFunction = function(_, id)
+    seen[id] = true
+  end,
+})
+
+local result = profiler(function()
+  f()
+end)
+
+-- the ids are hex strings everywhere, so no bits of the addresses are lost
+for _, v in ipairs(result) do
+  assert(type(v.id) == "string")
+
+  if v.id:sub(1, 2) == "0x" then
+    assert(v.id:match("^0x%x+$"), v.id)
+    assert(seen[v.id], v.id)
+  end
+end
+
+for _, event in ipairs(profiler:events()) do
+  assert(event.id == nil or event.id == "other" or event.id:match("^0x%x+$"), event.id)
+end
+
+-- the ids read back from the events match the entries'
+local replayed = lprofile.replay(profiler:events())
+
+for _, v in ipairs(replayed) do
+  if v.id:sub(1, 2) == "0x" then
+    assert(seen[v.id], v.id)
+  end
+end
+
+print("OK")
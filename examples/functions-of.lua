@@ -0,0 +1,54 @@
+local lprofile = require("liblprofile")
+
+local chunk = assert(load([[
+local function first()
+  return 1
+end
+
+local function second()
+  local function nested()
+    return 2
+  end
+
+  return nested
+end
+
+return function()
+  return first() + second()()
+end
+]], "=chunk"))
+
+local functions = lprofile.functionsOf(chunk)
+assert(#functions == 5, #functions)
+
+-- the chunk comes first, then its functions in the order they're defined in
+local expected = {{0, 0}, {1, 3}, {5, 11}, {6, 8}, {13, 15}}
+
+for i, v in ipairs(functions) do
+  assert(v.source == "chunk" and v.sourceType == "other", v.source)
+  assert(v.line == expected[i][1] and v.lastLine == expected[i][2], v.line)
+  assert(v.domain == (i == 1 and "main" or "Lua"), v.domain)
+end
+
+-- they match the entries of the functions once they're called
+local result = lprofile.Profiler()(chunk())
+local lines = {}
+
+for _, v in ipairs(result) do
+  if v.source == "chunk" then
+    lines[v.line] = true
+  end
+end
+
+for i = 2, #functions, 1 do
+  assert(lines[functions[i].line], functions[i].line)
+end
+
+-- stripped bytecode keeps the lines
+local stripped = lprofile.functionsOf(load(string.dump(chunk, true), nil, "b"))
+assert(#stripped == 5 and stripped[3].line == 5 and stripped[3].source == "?")
+
+assert(not pcall(lprofile.functionsOf, print))
+assert(not pcall(lprofile.functionsOf, 42))
+
+print("OK")
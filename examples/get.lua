@@ -0,0 +1,58 @@
+local lprofile = require("liblprofile")
+
+local function bar(n)
+  return n + 1
+end
+
+local function make()
+  return function(n)
+    return n * 2
+  end
+end
+
+-- two closures of the same function share the name
+local first, second = make(), make()
+
+local result = lprofile.Profiler()(function()
+  for i = 1, 10, 1 do
+    bar(i)
+  end
+
+  first(1)
+  second(2)
+  second(3)
+end)
+
+local barEntry, closures
+
+for _, v in ipairs(result) do
+  if v.name:find("function bar ") then
+    barEntry = v
+  elseif v.name:find("anonymous") then
+    closures = closures or {}
+    table.insert(closures, v)
+  end
+end
+
+assert(barEntry and closures and #closures == 2)
+
+-- a single entry is returned as is
+assert(result:get(barEntry.name) == barEntry)
+assert(result:get(barEntry.name).calls == 10)
+assert(result:get(barEntry.id) == barEntry)
+assert(result:get("<host>") == result.host)
+
+-- the entries sharing a name are added up
+local aggregate = result:get(closures[1].name)
+assert(aggregate ~= closures[1] and aggregate ~= closures[2])
+assert(aggregate.name == closures[1].name)
+assert(aggregate.calls == 3, aggregate.calls)
+assert(#aggregate.entries == 2)
+assert(math.abs(aggregate.totalSelfTime
+                - closures[1].totalSelfTime - closures[2].totalSelfTime) < 1e-9)
+
+local entry, err = result:get("no such function")
+assert(entry == nil)
+assert(err.code == "NOT_FOUND", tostring(err))
+
+print("OK")
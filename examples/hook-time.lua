@@ -0,0 +1,68 @@
+local lprofile = require("liblprofile")
+
+local function f()
+  return 1
+end
+
+local function spin(seconds)
+  local start = os.clock()
+
+  while os.clock() - start < seconds do end
+end
+
+-- the callback makes the hook take 50 ms on the first call of f
+local function run(hookTime)
+  local result = lprofile.Profiler({
+    hookTime = hookTime,
+    onNewFunction = function(name)
+      if name:find("function f ") then
+        spin(0.05)
+      end
+    end,
+  })(function()
+    for _ = 1, 10, 1 do
+      f()
+    end
+  end)
+
+  for _, v in ipairs(result) do
+    if v.name:find("function f ") then
+      return result, v
+    end
+  end
+end
+
+local function selfTimes(result)
+  local sum = 0
+
+  for _, v in ipairs(result) do
+    sum = sum + v.totalSelfTime
+  end
+
+  return sum
+end
+
+-- by default, the callback runs after the callee's frame is opened
+local result, entry = run()
+assert(entry.totalSelfTime >= 0.05, entry.totalSelfTime)
+assert(result.hook == nil)
+
+-- the event's time is left out entirely
+result, entry = run("excluded")
+assert(entry.totalSelfTime < 0.01, entry.totalSelfTime)
+assert(result.host.totalSelfTime < 0.01, result.host.totalSelfTime)
+assert(result.hook == nil)
+assert(result.totalTime - selfTimes(result) >= 0.05)
+
+-- or charged to its own entry
+result, entry = run("overhead")
+assert(entry.totalSelfTime < 0.01, entry.totalSelfTime)
+assert(result.hook.id == "hook" and result.hook.name == "<hook>")
+assert(result.hook.calls > 20, result.hook.calls)
+assert(result.hook.totalSelfTime >= 0.05, result.hook.totalSelfTime)
+assert(math.abs(selfTimes(result) - result.totalTime) < 1e-6)
+
+assert(not pcall(lprofile.Profiler, {hookTime = "callee"}))
+assert(not pcall(lprofile.Profiler, {hookTime = "excluded", callsOnly = true}))
+
+print("OK")
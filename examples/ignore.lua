@@ -0,0 +1,66 @@
+local lprofile = require("liblprofile")
+
+local logged = {}
+
+local function log(...)
+  local x = 0
+
+  for i = 1, 1000000, 1 do
+    x = x + i
+  end
+
+  logged[#logged + 1] = table.concat({...}, " ")
+
+  return x
+end
+
+local function work()
+  local x = 0
+
+  for i = 1, 10000, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local result = lprofile.Profiler()(function()
+  for i = 1, 3, 1 do
+    -- the results are passed through
+    assert(lprofile.ignore(log, "iteration", i) == 500000500000)
+    work()
+  end
+end)
+
+assert(#logged == 3 and logged[1] == "iteration 1")
+
+-- the logging is not in the profile
+for _, v in ipairs(result) do
+  assert(not v.name:find("function log ", 1, true), "the ignored call was profiled")
+end
+
+-- its time is charged to <ignored> rather than the caller
+assert(result.ignored)
+assert(result.ignored.calls == 3)
+assert(result.ignored.totalSelfTime > result.root.totalSelfTime)
+
+local sum = 0
+
+for _, v in ipairs(result) do
+  sum = sum + v.totalSelfTime
+end
+
+assert(math.abs(sum - result.totalTime) < 1e-6, "the self-times don't add up to the session time")
+
+-- errors are propagated, and the events after them are profiled again
+local ok = pcall(function()
+  lprofile.Profiler()(function()
+    lprofile.ignore(error, "oops")
+  end)
+end)
+assert(not ok)
+
+-- outside of a session, the function is just called
+assert(lprofile.ignore(function(a, b) return a + b end, 1, 2) == 3)
+
+print("OK")
@@ -0,0 +1,61 @@
+local lprofile = require("liblprofile")
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+-- a runaway script is stopped, and the profile collected so far is kept
+local profiler = lprofile.Profiler({instructionLimit = 100000, protected = true})
+
+local ok, err, partial = profiler(function()
+  while true do
+    work(100)
+  end
+end)
+
+assert(not ok and err.code == "INSTRUCTION_LIMIT_EXCEEDED", tostring(err))
+assert(partial.summary.reliability.instructionLimitExceeded)
+assert(partial.summary.reliability.approximate)
+
+local calls = 0
+
+for _, v in ipairs(partial) do
+  if v.name:find("work") then
+    calls = v.calls
+  end
+end
+
+assert(calls > 0)
+
+-- catching the error doesn't let the script run on
+ok, err = profiler(function()
+  for _ = 1, 10, 1 do
+    pcall(function()
+      while true do end
+    end)
+  end
+end)
+
+assert(not ok and err.code == "INSTRUCTION_LIMIT_EXCEEDED", tostring(err))
+
+-- within the budget, nothing changes
+local ok2, result = profiler(work, 1000)
+assert(ok2 and not result.summary.reliability.instructionLimitExceeded)
+
+-- the profiler is still reusable, and the hook is removed after the session
+assert(debug.gethook() == nil)
+
+-- it works with the calls only counted as well
+ok, err = lprofile.Profiler({instructionLimit = 1000, callsOnly = true, protected = true})(function()
+  while true do end
+end)
+
+assert(not ok and err.code == "INSTRUCTION_LIMIT_EXCEEDED")
+
+print("OK")
@@ -0,0 +1,50 @@
+local lprofile = require("liblprofile")
+
+local cache = {}
+
+-- keeps its tables around
+local function leaky(n)
+  local t = {}
+
+  for i = 1, n, 1 do
+    t[i] = i
+  end
+
+  cache[#cache + 1] = t
+end
+
+-- creates only garbage
+local function tidy(n)
+  local t = {}
+
+  for i = 1, n, 1 do
+    t[i] = i
+  end
+
+  return #t
+end
+
+local result = lprofile.Profiler({liveBytes = true})(function()
+  for _ = 1, 10, 1 do
+    leaky(1000)
+    tidy(1000)
+  end
+end)
+
+local function entryOf(name)
+  for _, v in ipairs(result) do
+    if v.name:find("function " .. name .. " ", 1, true) then
+      return v
+    end
+  end
+end
+
+local leakyEntry, tidyEntry = entryOf("leaky"), entryOf("tidy")
+
+-- both allocate as much, but only the tables of leaky survive
+assert(leakyEntry.bytesAllocated > 0 and tidyEntry.bytesAllocated > 0)
+assert(leakyEntry.liveBytes >= 10 * 1000 * 8, "the cached tables aren't live")
+assert(tidyEntry.liveBytes < leakyEntry.liveBytes / 10, "the garbage is live")
+assert(result.summary.liveBytes >= leakyEntry.liveBytes)
+
+print("OK")
@@ -0,0 +1,15 @@
+local profiler = require("liblprofile").Profiler()
+
+local fromString = load("local x = 0\nfor i = 1, 1e6 do x = x + i end\nreturn x")
+local named = load("return 2 + 2", "=generated")
+local stripped = load(string.dump(function() return 4 end, true), nil, "b")
+
+local result = profiler(function()
+  fromString()
+  named()
+  stripped()
+end)
+
+for _, v in ipairs(result) do
+  print(v.name, v.sourceType, v.source)
+end
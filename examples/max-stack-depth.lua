@@ -0,0 +1,54 @@
+local lprofile = require("liblprofile")
+
+local function deep(n)
+  if n == 0 then
+    local x = 0
+
+    for i = 1, 100000, 1 do
+      x = x + i
+    end
+
+    return x
+  end
+
+  local result = deep(n - 1)
+
+  return result
+end
+
+local function run()
+  for _ = 1, 10, 1 do
+    deep(50)
+  end
+end
+
+local result = lprofile.Profiler({sample = 100, maxStackDepth = 5})(run)
+
+assert(result.summary.reliability.stackTruncated)
+assert(result.truncated.id == "truncated" and result.truncated.name == "...")
+assert(result.truncated.samples > 0 and result.truncated.totalTime > 0)
+assert(result.truncated.calls == 0 and result.truncated.totalSelfTime == 0)
+
+-- the top frames still get the self-time
+local deepEntry
+
+for _, v in ipairs(result) do
+  if v.name:find("function deep ") then
+    deepEntry = v
+  end
+end
+
+assert(deepEntry.totalSelfTime > 0 and deepEntry.selfSamples > 0)
+
+-- the profiled function is at the bottom of the stack, which is cut off most of the time
+assert(result.root.samples < result.truncated.samples)
+
+-- shallow stacks aren't truncated
+local shallow = lprofile.Profiler({sample = 100, maxStackDepth = 100})(run)
+assert(not shallow.summary.reliability.stackTruncated)
+assert(shallow.truncated == nil)
+
+assert(not pcall(lprofile.Profiler, {maxStackDepth = 5}))
+assert(not pcall(lprofile.Profiler, {sample = 100, maxStackDepth = 0}))
+
+print("OK")
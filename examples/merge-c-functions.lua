@@ -0,0 +1,57 @@
+local lprofile = require("liblprofile")
+
+local text = ("lorem ipsum dolor sit amet "):rep(10)
+
+-- keeps the addresses from being reused
+local iterators = {}
+
+local function countWords()
+  local n = 0
+
+  -- every call of gmatch returns a new C closure, which has its own address
+  local iterator = text:gmatch("%a+")
+  iterators[#iterators + 1] = iterator
+
+  for _ in iterator do
+    n = n + 1
+  end
+
+  return n
+end
+
+local function run(options)
+  return lprofile.Profiler(options)(function()
+    for _ = 1, 5, 1 do
+      countWords()
+    end
+  end)
+end
+
+local function iteratorsOf(result)
+  local found = {}
+
+  for _, v in ipairs(result) do
+    if v.isBuiltin and v.name:find("for iterator", 1, true) then
+      found[#found + 1] = v
+    end
+  end
+
+  return found
+end
+
+-- each of the iterators is a separate function
+local separate = iteratorsOf(run({}))
+assert(#separate == 5, #separate)
+
+-- unless they're merged by name
+local merged = iteratorsOf(run({mergeCFunctions = true}))
+assert(#merged == 1, #merged)
+
+-- 50 words and the call that ends each loop
+assert(merged[1].calls == 5 * 51, merged[1].calls)
+
+for _, v in ipairs(separate) do
+  assert(v.calls == 51, v.calls)
+end
+
+print("OK")
@@ -0,0 +1,60 @@
+local lprofile = require("liblprofile")
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local profiler = lprofile.Profiler({
+  metadata = {benchmark = "sum", commit = "abc123", size = 1000, scale = 0.5, warm = false},
+})
+
+local result = profiler(work, 1000)
+local metadata = result.summary.metadata
+
+assert(metadata.benchmark == "sum" and metadata.commit == "abc123")
+assert(math.type(metadata.size) == "integer" and metadata.size == 1000)
+assert(metadata.scale == 0.5 and metadata.warm == false)
+
+-- the types survive serialization
+local restored = lprofile.deserialize(profiler:serialize()).summary.metadata
+assert(restored.benchmark == "sum" and restored.size == 1000 and restored.scale == 0.5)
+assert(math.type(restored.size) == "integer" and restored.warm == false)
+
+-- the values of the first result are kept when merging
+local other = lprofile.Profiler({metadata = {commit = "def456", input = "large"}})
+other(work, 10)
+
+local merged = lprofile.deserialize(lprofile.merge(profiler:serialize(), other:serialize()))
+assert(merged.summary.metadata.commit == "abc123")
+assert(merged.summary.metadata.input == "large")
+
+-- replaced for the next sessions, and removed with nil
+profiler:setMetadata({size = 10})
+assert(profiler(work, 10).summary.metadata.size == 10)
+assert(profiler(work, 10).summary.metadata.benchmark == nil)
+
+profiler:setMetadata(nil)
+assert(profiler(work, 10).summary.metadata == nil)
+
+-- and it applies to the running session as well
+result = profiler(function()
+  profiler:setMetadata({phase = "inside"})
+  work(10)
+end)
+
+assert(result.summary.metadata.phase == "inside")
+
+-- only the values the exports can keep are allowed
+assert(not pcall(lprofile.Profiler, {metadata = {f = print}}))
+assert(not pcall(lprofile.Profiler, {metadata = {[1] = "positional"}}))
+assert(not pcall(lprofile.Profiler, {metadata = {nan = 0 / 0}}))
+assert(not pcall(lprofile.Profiler, {metadata = "string"}))
+assert(not pcall(profiler.setMetadata, profiler, {t = {}}))
+
+print("OK")
@@ -0,0 +1,65 @@
+local lprofile = require("liblprofile")
+
+local function busy(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local mt = {}
+
+mt.__index = function(_, key)
+  busy(1000)
+
+  return key
+end
+
+mt.__add = function(a, b)
+  return a.value + b
+end
+
+local function run()
+  local t = setmetatable({value = 1}, mt)
+
+  for _ = 1, 100, 1 do
+    local _ = t.missing
+  end
+
+  for i = 1, 10, 1 do
+    local _ = t + i
+  end
+end
+
+local result = lprofile.Profiler({metamethods = true})(run)
+local metamethods = result.summary.metamethods
+
+assert(metamethods.__index.calls == 100, metamethods.__index.calls)
+assert(metamethods.__add.calls == 10, metamethods.__add.calls)
+assert(metamethods.__index.totalSelfTime > 0)
+assert(metamethods.__index.totalTime >= metamethods.__index.totalSelfTime)
+
+-- the time of busy is in the total time of __index, but not in its self-time
+local busyTime
+
+for _, v in ipairs(result) do
+  if v.name:find("function busy ") then
+    busyTime = v.totalTime
+  end
+
+  -- the entries of the metamethods are named after their event
+  if v.name:find("^metamethod") then
+    assert(v.name:find("__index") or v.name:find("__add"), v.name)
+  end
+end
+
+assert(metamethods.__index.totalTime >= busyTime)
+
+-- without the option, there's no breakdown
+assert(lprofile.Profiler()(run).summary.metamethods == nil)
+assert(not pcall(lprofile.Profiler, {metamethods = true, callsOnly = true}))
+
+print("OK")
@@ -0,0 +1,60 @@
+local lprofile = require("liblprofile")
+
+local function hot(n)
+  return n * 2
+end
+
+local function cold(n)
+  return n + 1
+end
+
+local function rare(n)
+  return n - 1
+end
+
+local function run(options)
+  return lprofile.Profiler(options)(function()
+    for i = 1, 100, 1 do
+      hot(i)
+    end
+
+    cold(1)
+    cold(2)
+    rare(3)
+  end)
+end
+
+local full = run({})
+local result = run({minCalls = 10})
+
+local names = {}
+
+for _, v in ipairs(result) do
+  names[v.name] = v
+end
+
+-- the root is kept even though it's called once
+assert(result.root)
+assert(result.filtered)
+assert(names["<filtered>"] == result.filtered)
+assert(result.filtered.id == "filtered")
+assert(result.filtered.calls == 3)
+assert(result.filtered.functionCount == 2)
+
+for _, v in ipairs(result) do
+  assert(v == result.root or v == result.filtered or v == result.host or v.calls >= 10,
+         "not filtered: " .. v.name)
+end
+
+-- the root, the hot function, and the two special entries
+assert(#result == 4, #result)
+assert(#full == 5, #full)
+
+for _, edge in ipairs(result.edges) do
+  assert(edge.caller ~= result.filtered and edge.callee ~= result.filtered)
+end
+
+-- nothing is filtered out without the option
+assert(full.filtered == nil)
+
+print("OK")
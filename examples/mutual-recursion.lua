@@ -0,0 +1,59 @@
+local lprofile = require("liblprofile")
+
+local isEven, isOdd
+
+function isEven(n)
+  if n == 0 then
+    return true
+  end
+
+  local result = isOdd(n - 1)
+
+  return result
+end
+
+function isOdd(n)
+  if n == 0 then
+    return false
+  end
+
+  local result = isEven(n - 1)
+
+  return result
+end
+
+local function run()
+  for _ = 1, 1000, 1 do
+    isEven(100)
+  end
+end
+
+local result = lprofile.Profiler()(run)
+
+local function entryOf(result, name)
+  for _, v in ipairs(result) do
+    if v.name:find("function " .. name .. " ", 1, true) then
+      return v
+    end
+  end
+end
+
+local even, odd = entryOf(result, "isEven"), entryOf(result, "isOdd")
+
+-- only the outermost frame of each function counts, so neither can exceed the session
+assert(even.totalTime <= result.root.totalTime, "isEven is counted more than once")
+assert(odd.totalTime <= even.totalTime, "isOdd is counted more than once")
+assert(even.calls == 1000 * 51 and odd.calls == 1000 * 50)
+assert(even.cumulativeTime == even.totalTime)
+
+-- each call of isEven(100) recurses through both functions, under the profiled function
+assert(even.maxRecursion == 51 and odd.maxRecursion == 50)
+assert(result.summary.maxStackDepth == 1 + 101)
+
+-- when every frame counts, the time spent on the stack is still counted once
+local all = lprofile.Profiler({recursion = "all"})(run)
+even = entryOf(all, "isEven")
+assert(even.totalTime > even.cumulativeTime, "nested calls aren't counted")
+assert(even.cumulativeTime <= all.root.totalTime, "isEven is on the stack longer than the session")
+
+print("OK")
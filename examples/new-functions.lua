@@ -0,0 +1,63 @@
+local lprofile = require("liblprofile")
+
+local function leaf(n)
+  return n * 2
+end
+
+local function branch(n)
+  return leaf(n) + leaf(n + 1)
+end
+
+local seen, order = {}, {}
+
+local profiler = lprofile.Profiler({
+  onNewFunction = function(name, id)
+    assert(not seen[id], "announced twice: " .. name)
+    seen[id] = name
+    order[#order + 1] = name
+  end,
+})
+
+local result = profiler(function()
+  for i = 1, 100, 1 do
+    branch(i)
+  end
+end)
+
+-- every entry was announced once, in the order of the first calls
+for _, v in ipairs(result) do
+  if v.id ~= "host" then
+    assert(seen[v.id] == v.name, "not announced: " .. v.name)
+  end
+end
+
+assert(order[1] == result.root.name)
+assert(#order == #result - 1)
+
+-- an error of the callback propagates from the call that triggered it, as it was thrown
+local rejected = false
+
+local failing = lprofile.Profiler({
+  onNewFunction = function(name)
+    if not rejected and name:find("function branch ", 1, true) then
+      rejected = true
+      error({reason = "rejected"})
+    end
+  end,
+  protected = true,
+})
+
+local ok, err = failing(function()
+  branch(1)
+end)
+
+assert(not ok and type(err) == "table" and err.reason == "rejected", tostring(err))
+
+-- and the profiler can still be used
+ok, result = failing(function()
+  branch(1)
+end)
+
+assert(ok and result.root.calls == 1)
+
+print("OK")
@@ -0,0 +1,77 @@
+local lprofile = require("liblprofile")
+
+local function tiny(x)
+  return x + 1
+end
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function run()
+  local x = 0
+
+  for _ = 1, 1000, 1 do
+    x = tiny(x)
+  end
+
+  return x + work(1000000)
+end
+
+local function find(result, name)
+  for _, v in ipairs(result) do
+    if v.name:find(name) then
+      return v
+    end
+  end
+end
+
+-- no call is as long as an hour, so every self-time goes to the root
+local floor = 3600
+local result = lprofile.Profiler({noiseFloor = floor})(run)
+
+for _, name in ipairs({"tiny", "work"}) do
+  local v = find(result, name)
+  assert(v.calls > 0 and v.totalSelfTime == 0, name)
+  assert(v.totalTime > 0 or name == "tiny", name)
+end
+
+-- the time isn't lost: the caller has it all
+local plain = lprofile.Profiler()(run)
+assert(result.root.totalSelfTime > find(plain, "work").totalSelfTime * 0.5)
+assert(result.root.totalSelfTime <= result.root.totalTime)
+
+-- the functions left without self-time are added up
+local dropped = lprofile.Profiler({noiseFloor = floor, dropInsignificant = true})(run)
+assert(find(dropped, "tiny") == nil and find(dropped, "work") == nil)
+assert(dropped.insignificant.id == "insignificant")
+assert(dropped.insignificant.functionCount >= 2)
+assert(dropped.insignificant.calls >= 1001)
+assert(dropped.root.calls == 1)
+
+-- a floor below the long calls only folds the short ones
+local partial = lprofile.Profiler({noiseFloor = 1e-3})(run)
+assert(find(partial, "work").totalSelfTime > 0)
+
+local ok, err = pcall(lprofile.Profiler, {noiseFloor = 1e-6, sample = true})
+assert(not ok and err:find("option 'noiseFloor' cannot be combined with 'sample'"), err)
+
+ok, err = pcall(lprofile.Profiler, {dropInsignificant = true})
+assert(not ok and err:find("option 'dropInsignificant' needs 'noiseFloor'"), err)
+
+ok, err = pcall(lprofile.Profiler, {noiseFloor = 1e-6, callsOnly = true})
+assert(not ok and err:find("cannot be combined with 'noiseFloor'"), err)
+
+-- a time too long for the profiler to hold is rejected
+for _, key in ipairs({"noiseFloor", "deadline", "progress", "flushInterval", "window"}) do
+  ok, err = pcall(lprofile.Profiler, {[key] = 1e300})
+  assert(not ok and err:find("option '" .. key .. "' is too large"), err)
+end
+
+print("OK")
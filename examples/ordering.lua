@@ -0,0 +1,88 @@
+local lprofile = require("liblprofile")
+
+local function c() return 3 end
+local function b() return c() end
+local function a() return b() + c() end
+
+local function run()
+  return lprofile.Profiler()(function()
+    for _ = 1, 10, 1 do
+      a()
+    end
+
+    string.rep("x", 10)
+  end)
+end
+
+local function names(result)
+  local list = {}
+
+  for i, v in ipairs(result) do
+    list[i] = v.name
+  end
+
+  return table.concat(list, "\n")
+end
+
+-- the same code gives the same order
+local first = run()
+assert(names(first) == names(run()), names(first))
+
+-- the functions defined in this file come by their lines
+local lines = {}
+
+for _, v in ipairs(first) do
+  if v.domain == "Lua" and v.rawSource == first.root.rawSource then
+    table.insert(lines, v.line)
+  end
+end
+
+assert(#lines >= 3, #lines)
+
+for i = 2, #lines, 1 do
+  assert(lines[i - 1] <= lines[i], table.concat(lines, " "))
+end
+
+-- the host entry comes after the functions
+local last = first[#first]
+assert(first.host == nil or last == first.host)
+
+-- the iterator follows the same order
+local profiler = lprofile.Profiler()
+local result = profiler(function()
+  for _ = 1, 10, 1 do
+    a()
+  end
+end)
+
+local count = 0
+
+for entry in profiler:iter() do
+  count = count + 1
+  assert(entry.name == result[count].name, entry.name)
+end
+
+assert(count >= 4, count)
+
+-- resetting the profiler ends the loop
+count = 0
+
+for _ in profiler:iter() do
+  count = count + 1
+  profiler:reset()
+end
+
+assert(count == 1, count)
+
+-- and so does another session
+profiler(a)
+count = 0
+
+for _ in profiler:iter() do
+  count = count + 1
+  profiler(a)
+end
+
+assert(count == 1, count)
+
+print("OK")
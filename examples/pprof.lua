@@ -0,0 +1,34 @@
+local lprofile = require("liblprofile")
+
+if not lprofile.features.pprof then
+  print("SKIP: built without the pprof feature")
+  return
+end
+
+local function leaf(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function branch(n)
+  return leaf(n) + leaf(n * 2)
+end
+
+local profiler = lprofile.Profiler()
+profiler(branch, 100000)
+
+local profile = profiler:pprof()
+assert(type(profile) == "string" and #profile > 0)
+
+-- the first field is a sample type, a length-delimited field 1
+assert(profile:byte(1) == 0x0a, "not a pprof profile")
+
+-- the names are in the string table
+assert(profile:find("nanoseconds", 1, true))
+
+print("OK")
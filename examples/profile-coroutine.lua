@@ -0,0 +1,86 @@
+local lprofile = require("liblprofile")
+
+local function square(n)
+  return n * n
+end
+
+local function outside()
+  return 0
+end
+
+local function generator(n)
+  for i = 1, n, 1 do
+    coroutine.yield(square(i), i)
+  end
+
+  return "done", n
+end
+
+local profiler = lprofile.Profiler()
+local co = coroutine.create(generator)
+
+local result, yields, status, count = profiler:profileCoroutine(co, 5)
+outside()
+
+assert(coroutine.status(co) == "dead")
+assert(status == "done" and count == 5)
+assert(#yields == 5, #yields)
+
+for i, v in ipairs(yields) do
+  assert(v.n == 2 and v[1] == i * i and v[2] == i)
+end
+
+local names = {}
+
+for _, v in ipairs(result) do
+  names[v.name] = v
+end
+
+-- the coroutine's function is the root, and the yields don't break its frame
+local root = result.root
+assert(root.name:find("function generator "), root.name)
+assert(root.calls == 1)
+
+local squares = 0
+
+for name, v in pairs(names) do
+  assert(not name:find("function outside "), name)
+
+  if name:find("function square ") then
+    squares = squares + v.calls
+  end
+end
+
+assert(squares == 5, squares)
+assert(profiler:result().root.name == root.name)
+
+-- the errors are propagated, or returned in the protected mode
+local failing = coroutine.create(function()
+  coroutine.yield(1)
+  error("boom", 0)
+end)
+
+assert(not pcall(profiler.profileCoroutine, profiler, failing))
+
+failing = coroutine.create(function()
+  coroutine.yield(1)
+  error("boom", 0)
+end)
+
+local ok, err, failed = lprofile.Profiler({protected = true}):profileCoroutine(failing)
+assert(ok == false and err == "boom" and failed.root)
+
+-- a dead coroutine can't be profiled
+assert(not pcall(profiler.profileCoroutine, profiler, co))
+
+-- while disabled, only the values are returned
+lprofile.setEnabled(false)
+local values = lprofile.Profiler():profileCoroutine(coroutine.create(function()
+  coroutine.yield("a")
+
+  return "b"
+end))
+lprofile.setEnabled(true)
+assert(#values == 1 and values[1][1] == "a")
+
+print("OK")
@@ -0,0 +1,12 @@
+local profiler = require("liblprofile").Profiler()
+
+-- the main chunk of the file is the root of the profile
+local result = profiler(assert(loadfile("examples/chunk.lua")))
+
+print("Root:", result.root.name)
+print("Root total time:", result.root.totalTime)
+print("Session time:", result.totalTime)
+
+for _, v in ipairs(result) do
+  print(("%s\t%d\t%.6f s\t%.6f s"):format(v.name, v.calls, v.totalTime, v.totalSelfTime))
+end
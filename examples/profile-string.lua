@@ -0,0 +1,47 @@
+local lprofile = require("liblprofile")
+
+local source = [[
+local n = ...
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+return work(n)
+]]
+
+-- the chunk name is the source of the functions the code defines
+local result = assert(lprofile.profileString(source, "=generated", 1000))
+assert(result.root.source == "generated" and result.root.sourceType == "other", result.root.source)
+
+local found = false
+
+for _, v in ipairs(result) do
+  if v.name:find("work") then
+    found = true
+    assert(v.calls == 1 and v.source == "generated")
+  end
+end
+
+assert(found)
+
+-- without a chunk name, the code names itself, as with load
+result = assert(lprofile.profileString("return 1"))
+assert(result.root.source == "return 1" and result.root.sourceType == "string")
+
+-- the code that can't be loaded is reported...
+local ok, err = lprofile.profileString("return +", "=broken")
+assert(ok == nil and err.code == "LOAD_FAILED", tostring(err))
+assert(tostring(err):find("broken"), tostring(err))
+
+-- ...while the errors it throws are propagated
+ok, err = pcall(lprofile.profileString, "error('thrown', 0)", "=thrower")
+assert(not ok and err == "thrown", tostring(err))
+
+print("OK")
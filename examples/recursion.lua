@@ -0,0 +1,55 @@
+local lprofile = require("liblprofile")
+
+local function busy(n)
+  local a = 0
+
+  for i = 1, n, 1 do
+    a = a + i
+  end
+
+  return a
+end
+
+local function f(depth)
+  busy(2e6)
+
+  if depth > 0 then
+    f(depth - 1)
+  end
+end
+
+local function find(result, name)
+  for _, v in ipairs(result) do
+    if v.name:find("function " .. name .. " ", 1, true) then
+      return v
+    end
+  end
+end
+
+local totals = {}
+
+for _, policy in ipairs({"outermost", "all"}) do
+  local result = lprofile.Profiler({recursion = policy})(function()
+    f(1)
+  end)
+
+  local v = find(result, "f")
+  assert(v.calls == 2 and find(result, "busy").calls == 2)
+  totals[policy] = v.totalTime
+
+  -- "outermost" reports the span of f(1), which calls busy twice
+  -- "all" adds the time of f(0) once more, so busy is counted three times
+  if policy == "outermost" then
+    assert(v.totalTime == v.cumulativeTime)
+  else
+    local ratio = v.totalTime / v.cumulativeTime
+    assert(ratio > 1.3 and ratio < 1.7, ratio)
+  end
+
+  print(policy, v.totalTime, find(result, "busy").totalTime)
+end
+
+local ratio = totals.all / totals.outermost
+assert(ratio > 1.2 and ratio < 1.8, ratio)
+
+print("OK")
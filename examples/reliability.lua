@@ -0,0 +1,46 @@
+local lprofile = require("liblprofile")
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function spawn()
+  for _ = 1, 10, 1 do
+    (function() return work(100) end)()
+  end
+end
+
+local exact = lprofile.Profiler()(spawn)
+local r = exact.summary.reliability
+assert(r.mode == "exact" and not r.approximate, "an exact session is reported as approximate")
+assert(not r.capped and not r.deadlineExceeded and not r.saturated and not r.abandoned)
+assert(r.overhead >= 0 and r.overhead <= 1)
+
+-- the slowdown follows from the overhead
+assert(r.perturbation >= 1)
+assert(math.abs(r.perturbation * (1 - r.overhead) - 1) < 1e-9)
+
+local sampled = lprofile.Profiler({sample = 100})(spawn)
+assert(sampled.summary.reliability.mode == "sampled")
+assert(sampled.summary.reliability.approximate)
+
+-- the closures beyond the limit share an entry
+local capped = lprofile.Profiler({maxFunctions = 2})(spawn)
+assert(capped.summary.reliability.capped and capped.summary.reliability.approximate)
+
+local ok, err, partial = lprofile.Profiler({deadline = 0.01, protected = true})(function()
+  while true do
+    work(1000)
+  end
+end)
+
+assert(not ok and err.code == "DEADLINE_EXCEEDED")
+assert(partial.summary.reliability.deadlineExceeded)
+
+print("OK")
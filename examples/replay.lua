@@ -0,0 +1,61 @@
+local lprofile = require("liblprofile")
+
+local function leaf(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function branch(n)
+  return leaf(n) + leaf(n * 2)
+end
+
+local profiler = lprofile.Profiler({record = true})
+
+local result = profiler(function()
+  for i = 1, 10, 1 do
+    branch(i * 1000)
+  end
+end)
+
+local events = profiler:events()
+assert(#events > 0, "no events recorded")
+assert(events[1].event == "call" and events[#events].event == "return")
+
+local replayed = lprofile.replay(events)
+
+local byId = {}
+
+for _, v in ipairs(replayed) do
+  byId[v.id] = v
+end
+
+-- the recorded calls and total times are reproduced exactly; the time outside of the profiled
+-- function isn't recorded
+for _, v in ipairs(result) do
+  if v.id ~= "host" then
+    local other = byId[v.id]
+    assert(other, "no replayed entry for " .. v.name)
+    assert(other.calls == v.calls, "the calls of " .. v.name .. " differ")
+    assert(other.totalTime == v.totalTime, "the total time of " .. v.name .. " differs")
+  end
+end
+
+-- replaying again gives the same result
+local again = lprofile.replay(events)
+assert(#again == #replayed)
+
+for _, v in ipairs(again) do
+  assert(byId[v.id].totalSelfTime == v.totalSelfTime)
+end
+
+-- without the option, nothing is kept
+local plain = lprofile.Profiler()
+plain(branch, 10)
+assert(plain:events() == nil)
+
+print("OK")
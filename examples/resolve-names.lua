@@ -0,0 +1,54 @@
+local lprofile = require("liblprofile")
+
+-- the program knows its callbacks by name, Lua doesn't
+local registry = {}
+
+local function register(name, f)
+  local info = debug.getinfo(f, "S")
+  registry[info.source:gsub("^@", "") .. ":" .. info.linedefined] = name
+
+  return f
+end
+
+local callbacks = {
+  register("onOpen", function() return 1 end),
+  register("onClose", function() return 2 end),
+  function() return 3 end,
+}
+
+local resolved = 0
+
+local profiler = lprofile.Profiler({
+  resolveName = function(source, line)
+    resolved = resolved + 1
+
+    return registry[source .. ":" .. line]
+  end,
+})
+
+local result = profiler(function()
+  for _ = 1, 10, 1 do
+    for _, callback in ipairs(callbacks) do
+      -- called from C, so Lua has no name for them
+      pcall(callback)
+    end
+  end
+end)
+
+local names = {}
+
+for _, v in ipairs(result) do
+  names[#names + 1] = v.name
+end
+
+local all = table.concat(names, "\n")
+assert(all:find("function onOpen", 1, true), all)
+assert(all:find("function onClose", 1, true), all)
+
+-- the unregistered callback stays anonymous
+assert(all:find("anonymous", 1, true), all)
+
+-- once per anonymous function: the root and the three callbacks
+assert(resolved == 4, resolved)
+
+print("OK")
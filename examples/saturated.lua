@@ -0,0 +1,20 @@
+local lprofile = require("liblprofile")
+
+-- counters too large for a Lua integer are clamped rather than wrapped around to negative numbers
+local huge = table.concat({
+  "lprofile 1",
+  "time\t1000",
+  "function\t18446744073709551615\t500\t100\tLua\tfile\thuge.lua\t1\t\thuge",
+  "function\t18446744073709551615\t500\t100\tLua\tfile\thuge.lua\t5\t\talsoHuge",
+  "",
+}, "\n")
+
+local result = lprofile.deserialize(huge)
+
+for _, v in ipairs(result) do
+  assert(v.calls == math.maxinteger, v.name .. ": " .. v.calls)
+end
+
+assert(result.summary.totalCalls == math.maxinteger, result.summary.totalCalls)
+
+print("OK")
@@ -0,0 +1,16 @@
+local profiler = require("liblprofile").Profiler()
+
+local result = profiler(function()
+  return 1
+end)
+
+-- only the profiled function is in the results: neither pcall nor the caller of the profiler are
+-- (the last entry is <host>, which accounts for the profiler's own time)
+assert(#result == 2 and result[2] == result.host, "unexpected entries in the profile")
+assert(result[1] == result.root)
+
+for _, v in ipairs(result) do
+  assert(not v.name:find("pcall"), "the profiler's pcall is in the profile")
+end
+
+print("OK")
@@ -0,0 +1,52 @@
+local lprofile = require("liblprofile")
+
+local function child() end
+
+local function leaf()
+  return 1
+end
+
+-- loops run without calls, so they stay within a segment
+local function work()
+  local x = 0
+
+  for i = 1, 2000000, 1 do
+    x = x + i
+  end
+
+  child()
+  child()
+
+  for i = 1, 200000, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local result = lprofile.Profiler({segments = true})(function()
+  work()
+  leaf()
+end)
+
+local function find(pattern)
+  for _, v in ipairs(result) do
+    if v.name:find(pattern) then
+      return v
+    end
+  end
+end
+
+local work = find("function work ")
+assert(work.setupTime > work.teardownTime, "the setup loop is 10 times longer")
+assert(work.setupTime + work.teardownTime <= work.totalSelfTime + 1e-9)
+
+-- a function that made no calls has a single segment, which is neither
+local leaf = find("function leaf ")
+assert(leaf.setupTime == 0 and leaf.teardownTime == 0)
+
+-- the segments are only tracked with the option
+local plain = lprofile.Profiler()(work)
+assert(plain.root.setupTime == nil)
+
+print("OK")
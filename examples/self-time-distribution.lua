@@ -0,0 +1,49 @@
+local lprofile = require("liblprofile")
+
+local function busy(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function a() return busy(100) end
+local function b() return busy(1000) end
+local function c() return busy(10000) end
+
+local result = lprofile.Profiler()(function()
+  a()
+  b()
+  c()
+end)
+
+local dist = result:selfTimeDistribution()
+
+-- the special entries aren't counted
+local functions = 0
+local times = {}
+
+for _, v in ipairs(result) do
+  if v ~= result.host and v ~= result.ignored and v ~= result.filtered and v ~= result.hook then
+    functions = functions + 1
+    times[#times + 1] = v.totalSelfTime
+  end
+end
+
+assert(dist.count == functions, dist.count)
+table.sort(times)
+
+assert(dist.min == times[1])
+assert(dist.max == times[#times])
+assert(dist.p50 == times[math.ceil(#times * 0.5)])
+assert(dist.p99 == times[#times])
+assert(dist.min <= dist.p50 and dist.p50 <= dist.p90 and dist.p90 <= dist.p99)
+
+-- without any entries, only the count is set
+local none = result.selfTimeDistribution({})
+assert(none.count == 0 and none.min == nil and none.p50 == nil and none.max == nil)
+
+print("OK")
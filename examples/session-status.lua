@@ -0,0 +1,55 @@
+local lprofile = require("liblprofile")
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local profiler = lprofile.Profiler({protected = true})
+
+local ok, result = profiler(work, 1000)
+assert(ok and result.summary.status == "completed", result.summary.status)
+
+-- the errors caught inside of the function don't count
+ok, result = profiler(function()
+  pcall(error, "caught")
+end)
+
+assert(ok and result.summary.status == "completed")
+
+local err, partial
+ok, err, partial = profiler(function()
+  work(1000)
+  error("thrown")
+end)
+
+assert(not ok and tostring(err):find("thrown"))
+assert(partial.summary.status == "errored", partial.summary.status)
+
+ok, err, partial = lprofile.Profiler({deadline = 0.01, protected = true})(function()
+  while true do
+    work(100)
+  end
+end)
+
+assert(not ok and err.code == "DEADLINE_EXCEEDED")
+assert(partial.summary.status == "timedOut", partial.summary.status)
+
+ok, err, partial = lprofile.Profiler({instructionLimit = 100000, protected = true})(function()
+  while true do end
+end)
+
+assert(not ok and err.code == "INSTRUCTION_LIMIT_EXCEEDED")
+assert(partial.summary.status == "timedOut")
+
+-- a deserialized result has no session to report on
+profiler(error, "thrown")
+result = lprofile.deserialize(profiler:serialize())
+assert(result.summary.status == "completed")
+
+print("OK")
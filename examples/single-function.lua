@@ -0,0 +1,36 @@
+local lprofile = require("liblprofile")
+
+local function work()
+  local x = 0
+
+  for i = 1, 1000000, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+-- the first frame of the session has no parent and sits right above the profiler's frames
+local result = lprofile.Profiler()(work)
+
+local entries = {}
+
+for _, v in ipairs(result) do
+  if v ~= result.host then
+    table.insert(entries, v)
+  end
+end
+
+assert(#entries == 1, "expected exactly one entry, got " .. #entries)
+
+local entry = entries[1]
+assert(entry == result.root)
+assert(entry.calls == 1)
+assert(entry.totalTime > 0)
+
+-- the function calls nothing, so all of its time is its own
+assert(entry.totalSelfTime <= entry.totalTime)
+assert(entry.totalTime - entry.totalSelfTime < 1e-3, "time is missing from the self-time")
+assert(entry.totalTime <= result.totalTime, "the function outlived the session")
+
+print("OK")
@@ -0,0 +1,56 @@
+local lprofile = require("liblprofile")
+
+local function work(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local result = lprofile.Profiler({skipNames = true})(function()
+  for i = 1, 10, 1 do
+    work(i * 1000)
+  end
+end)
+
+local ids = {}
+
+for _, v in ipairs(result) do
+  if v ~= result.host then
+    -- the ids are still there to tell the functions apart
+    assert(v.name == "", v.name)
+    assert(v.source == nil)
+    assert(v.id:find("^0x"), v.id)
+    assert(not ids[v.id])
+    ids[v.id] = true
+  end
+end
+
+assert(result.root.calls == 1)
+assert(result.summary.totalCalls >= 11)
+assert(result.summary.luaTime == 0)
+
+-- the custom keys keep their names
+local keyed = lprofile.Profiler({
+  skipNames = true,
+  key = function(f)
+    if f == work then
+      return "work"
+    end
+  end,
+})(function()
+  work(10)
+end)
+
+local found = false
+
+for _, v in ipairs(keyed) do
+  found = found or v.name == "work"
+end
+
+assert(found, "the custom key lost its name")
+
+print("OK")
@@ -0,0 +1,57 @@
+local lprofile = require("liblprofile")
+
+local function cheap(n)
+  return n + 1
+end
+
+local function expensive()
+  local x = 0
+
+  for i = 1, 1000000, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local result = lprofile.Profiler()(function()
+  for i = 1, 100000, 1 do
+    cheap(i)
+  end
+
+  expensive()
+end)
+
+local slowest = result:slowestPerCall()
+
+-- every entry with calls is there, slowest first
+local withCalls = 0
+
+for _, v in ipairs(result) do
+  if v.calls > 0 then
+    withCalls = withCalls + 1
+  end
+end
+
+assert(#slowest == withCalls, #slowest)
+
+for i = 2, #slowest, 1 do
+  assert(slowest[i - 1].selfTimePerCall >= slowest[i].selfTimePerCall)
+end
+
+for _, v in ipairs(slowest) do
+  assert(v.entry ~= result.host)
+  assert(math.abs(v.selfTimePerCall - v.entry.totalSelfTime / v.entry.calls) < 1e-12)
+end
+
+-- called once, but far slower per call than cheap
+local top = result:slowestPerCall(2)
+assert(#top == 2)
+
+local names = top[1].entry.name .. top[2].entry.name
+assert(names:find("function expensive "), names)
+assert(not names:find("function cheap "), names)
+
+assert(not pcall(result.slowestPerCall, result, 0))
+
+print("OK")
@@ -0,0 +1,50 @@
+local lprofile = require("liblprofile")
+
+local function spin(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function walk(depth)
+  spin(10000)
+
+  if depth > 0 then
+    walk(depth - 1)
+  end
+end
+
+local profiler = lprofile.Profiler()
+local result = profiler(function()
+  walk(20)
+  spin(10000)
+end)
+
+local line = debug.getinfo(walk, "S").linedefined
+local entry
+
+for _, v in ipairs(result) do
+  if v.name:find(":" .. line .. ")", 1, true) then
+    entry = v
+  end
+end
+
+assert(entry, "walk is not in the profile")
+
+-- the recursive calls are counted once, so it's the time of the outer call
+local byId = profiler:subtreeTime(entry.id)
+assert(byId == profiler:subtreeTime(entry.name))
+assert(math.abs(byId - entry.cumulativeTime) < 1e-6, byId .. " " .. entry.cumulativeTime)
+assert(byId <= result.root.totalTime)
+
+-- the root's subtree is the whole session below it
+assert(math.abs(profiler:subtreeTime(result.root.id) - result.root.totalTime) < 1e-6)
+
+local time, err = profiler:subtreeTime("no such function")
+assert(time == nil and err.code == "NOT_FOUND")
+
+print("OK")
@@ -0,0 +1,81 @@
+local lprofile = require("liblprofile")
+
+local b, c
+
+local function a(n)
+  return b(n)
+end
+
+function b(n)
+  return c(n)
+end
+
+function c(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function run()
+  for _ = 1, 100, 1 do
+    a(1000)
+  end
+end
+
+-- Returns the name of the function that called the given one, if it only has one caller.
+local function callerOf(result, name)
+  local callers = {}
+
+  for _, edge in ipairs(result.edges) do
+    if edge.callee.name:find("function " .. name .. " ", 1, true) then
+      table.insert(callers, edge.caller)
+    end
+  end
+
+  assert(#callers == 1, name .. " has " .. #callers .. " callers")
+
+  return callers[1]
+end
+
+local kept = lprofile.Profiler()(run)
+
+-- the functions that made the tail calls stay on the stack
+assert(callerOf(kept, "c").name:find("function b ", 1, true), "c is not called by b")
+assert(callerOf(kept, "b").name:find("function a ", 1, true), "b is not called by a")
+
+-- Lua 5.1 reports a plain call for each tail call, and an event when the frame it replaced ends
+local summary = kept.summary
+assert(summary.tailCallEvents == 200, summary.tailCallEvents)
+
+if _VERSION == "Lua 5.1" then
+  assert(summary.returnEvents + summary.tailCallEvents == summary.callEvents)
+
+  local ok, err = pcall(lprofile.Profiler, {tailCalls = "replace"})
+  assert(not ok and err:find("cannot be 'replace' with Lua 5.1", 1, true), err)
+
+  print("OK")
+
+  return
+end
+
+assert(summary.returnEvents == summary.callEvents)
+
+local replaced = lprofile.Profiler({tailCalls = "replace"})(run)
+
+-- each function takes the place of the one that called it
+assert(callerOf(replaced, "c") == replaced.root, "c is not called by the root")
+assert(callerOf(replaced, "b") == replaced.root, "b is not called by the root")
+
+for _, v in ipairs(replaced) do
+  if v.name:find("function a ", 1, true) then
+    -- a's call ends with the tail call, so c's loop isn't part of it
+    assert(v.totalTime < replaced.root.totalTime / 2)
+    assert(v.calls == 100)
+  end
+end
+
+print("OK")
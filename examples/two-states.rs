@@ -0,0 +1,52 @@
+// Loads the module into two states, each on its own thread, and profiles a function in both. The
+// module keeps no per-state data in statics, so the sessions must not interfere.
+//
+// Build the module first: `cargo build`.
+use std::thread;
+
+use lua::{State, ThreadStatus};
+
+const SCRIPT: &str = r#"
+package.cpath = "./target/debug/lib?.so;./target/debug/?.dll;" .. package.cpath
+local lprofile = require("liblprofile")
+
+local function f(n)
+  local x = 0
+
+  for i = 1, n do
+    x = x + i
+  end
+
+  return x
+end
+
+local profiler = lprofile.Profiler()
+local result = profiler(f, 100000)
+
+assert(result.root, "no root entry")
+assert(result.root.calls == 1, "unexpected number of calls")
+assert(getmetatable(result) == getmetatable(profiler:result()), "the result metatable differs")
+assert(tostring(result):find("session time"), "the report is missing")
+"#;
+
+fn run() -> Result<(), String> {
+    let mut state = State::new();
+    state.open_libs();
+
+    match state.do_string(SCRIPT) {
+        ThreadStatus::Ok => Ok(()),
+        _ => Err(state.to_str(-1).unwrap_or("unknown error").to_owned()),
+    }
+}
+
+fn main() {
+    let threads = (0..2).map(|_| thread::spawn(run)).collect::<Vec<_>>();
+
+    for (i, thread) in threads.into_iter().enumerate() {
+        if let Err(msg) = thread.join().unwrap() {
+            panic!("state {} failed: {}", i + 1, msg);
+        }
+    }
+
+    println!("OK");
+}
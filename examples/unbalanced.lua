@@ -0,0 +1,14 @@
+local profiler = require("liblprofile").Profiler()
+
+local function f()
+  -- removing the hook means the profiler never sees f and the chunk return
+  debug.sethook()
+end
+
+local result = profiler(function()
+  f()
+end)
+
+for _, v in ipairs(result.warnings) do
+  print("warning:", v)
+end
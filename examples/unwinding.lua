@@ -0,0 +1,102 @@
+local lprofile = require("liblprofile")
+
+-- Lua 5.3 has no to-be-closed variables, but it runs functions on top of the frames an error
+-- unwinds in two other ways: the message handler of xpcall, and the finalizers the collector calls
+-- from it. The stack must stay balanced through both.
+
+local finalized = 0
+
+local function busy(n)
+  local x = 0
+
+  for i = 1, n, 1 do
+    x = x + i
+  end
+
+  return x
+end
+
+local function finalize()
+  busy(1e4)
+  finalized = finalized + 1
+end
+
+local function handler(err)
+  busy(1e4)
+  setmetatable({}, {__gc = finalize})
+  collectgarbage()
+
+  return err
+end
+
+local function inner()
+  error("failed")
+end
+
+local function outer()
+  -- not a tail call, so the frame is unwound by the error
+  local v = inner()
+
+  return v
+end
+
+local function after()
+  return busy(1e6)
+end
+
+local function run()
+  for _ = 1, 10, 1 do
+    assert(not xpcall(outer, handler))
+  end
+
+  return after()
+end
+
+local result = lprofile.Profiler()(run)
+
+-- the handler and the finalizer may have no names, so the entries are found by their lines
+local function entryOf(f)
+  local line = debug.getinfo(f, "S").linedefined
+
+  for _, v in ipairs(result) do
+    if v.line == line and v.rawSource == result.root.rawSource then
+      return v
+    end
+  end
+end
+
+assert(finalized == 10, finalized)
+
+local counts = {
+  {outer, 10},
+  {inner, 10},
+  {handler, 10},
+  {finalize, 10},
+  {after, 1},
+  {busy, 21},
+}
+
+for _, pair in ipairs(counts) do
+  local v = entryOf(pair[1])
+  assert(v.calls == pair[2], v.name .. ": " .. v.calls)
+end
+
+assert(entryOf(inner).errors == 10 and entryOf(outer).errors == 10)
+
+-- the unwound frames end when the error is caught, not when the session does
+local afterEntry = entryOf(after)
+assert(entryOf(outer).totalTime < afterEntry.totalTime / 2, entryOf(outer).totalTime)
+
+for _, edge in ipairs(result.edges) do
+  if edge.callee == afterEntry then
+    assert(edge.caller == result.root, edge.caller.name)
+  end
+end
+
+for _, v in ipairs(result) do
+  assert(v.totalSelfTime <= v.totalTime, v.name)
+end
+
+assert(#result.warnings == 0, table.concat(result.warnings, "\n"))
+
+print("OK")
@@ -0,0 +1,46 @@
+local lprofile = require("liblprofile")
+
+local function counter()
+  local count = 0
+
+  return function()
+    count = count + 1
+  end
+end
+
+local function accumulator()
+  local sum, step = 0, 2
+
+  return function()
+    sum = sum + step
+  end
+end
+
+-- two closures defined on the same line, capturing different variables
+local function make(kind) if kind then return counter() else return accumulator() end end
+
+local result = lprofile.Profiler({upvalueNames = true})(function()
+  make(true)()
+  make(false)()
+  print("")
+end)
+
+local seen = {}
+
+for _, v in ipairs(result) do
+  if v.domain == "C" then
+    assert(v.upvalues == nil)
+  elseif v.upvalues then
+    seen[table.concat(v.upvalues, ",")] = true
+  end
+end
+
+assert(seen["count"], "the counter's closure")
+assert(seen["sum,step"], "the accumulator's closure")
+
+-- off by default
+for _, v in ipairs(lprofile.Profiler()(counter())) do
+  assert(v.upvalues == nil)
+end
+
+print("OK")
@@ -0,0 +1,166 @@
+use std::cmp::Reverse;
+use std::fmt::{self, Display};
+use std::mem::ManuallyDrop;
+use std::time::Duration;
+
+use lua::State;
+
+use crate::{
+    FunctionKey, FunctionName, MetaValue, ProfileEntry, Profiler, ProfilingResult, SourceType,
+};
+
+// The accessors for the Rust programs embedding the profiler, so that they can read the results
+// without going through the result table. The times are the exact ones the profiler keeps, not
+// rounded by the `precision` option.
+
+impl FunctionKey {
+    // Whether this is the key of `<other>`, the entry of the functions beyond `maxFunctions`.
+    pub fn is_other(self) -> bool {
+        self == Self::OTHER
+    }
+}
+
+impl FunctionName {
+    // The name Lua gave the function when it was first called, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    // How the function was called: "global", "local", "method", "field", and so on.
+    pub fn function_type(&self) -> Option<&str> {
+        self.function_type.as_deref()
+    }
+
+    pub fn source_type(&self) -> SourceType {
+        self.source_type
+    }
+
+    // The source, relative to `sourceRoot` if it's set.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // The line the function is defined at; none for C functions.
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    // "Lua", "C", or "main", or "key" for the entries of the custom keys.
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    // The names of the upvalues of a Lua function, if they were read.
+    pub fn upvalues(&self) -> Option<&[String]> {
+        self.upvalues.as_deref()
+    }
+}
+
+impl ProfileEntry {
+    pub fn name(&self) -> Option<&FunctionName> {
+        self.name.as_ref()
+    }
+
+    pub fn calls(&self) -> usize {
+        self.calls
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.total_time
+    }
+
+    pub fn total_self_time(&self) -> Duration {
+        self.total_self_time
+    }
+
+    pub fn cumulative_time(&self) -> Duration {
+        self.cumulative_time
+    }
+
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+}
+
+impl ProfilingResult {
+    // Returns a copy of the result of the last finished session of the profiler at `index`, or
+    // `None` if the value isn't a profiler or hasn't finished a session.
+    pub fn from_profiler(state: &mut State, index: i32) -> Option<Self> {
+        if state.test_userdata(index, Profiler::TYPE_NAME).is_null() {
+            return None;
+        }
+
+        // Safety: checked above
+        let this: &mut ManuallyDrop<Profiler> = unsafe { state.to_userdata_typed(index)? };
+
+        this.finished.clone()
+    }
+
+    // Parses the output of `profiler:serialize()`.
+    pub fn from_serialized(s: &str) -> Result<Self, String> {
+        Self::deserialize(s)
+    }
+
+    // The entries of the functions, in no particular order.
+    pub fn entries(&self) -> impl Iterator<Item = (FunctionKey, &ProfileEntry)> + '_ {
+        self.data.iter().map(|(k, v)| (*k, v))
+    }
+
+    pub fn get(&self, key: FunctionKey) -> Option<&ProfileEntry> {
+        self.data.get(&key)
+    }
+
+    // The key of the profiled function.
+    pub fn root(&self) -> Option<FunctionKey> {
+        self.root
+    }
+
+    // The session time, or the sum of the session times of a merged result.
+    pub fn total_time(&self) -> Option<Duration> {
+        self.total_time
+    }
+
+    // The entries sorted by the self-time in descending order, as in the report.
+    pub fn by_self_time(&self) -> Vec<(FunctionKey, &ProfileEntry)> {
+        let mut entries = self.entries().collect::<Vec<_>>();
+        entries.sort_by_key(|(_, v)| Reverse(v.total_self_time));
+
+        entries
+    }
+
+    // How the session ended: "stopped" if the profiler failed before the code finished,
+    // "timedOut" if the deadline or the instruction limit aborted it, "errored" if the code threw an
+    // error, and "completed" otherwise. The first of these that applies wins.
+    pub fn status(&self) -> &'static str {
+        if self.abandoned {
+            "stopped"
+        } else if self.deadline_exceeded || self.instruction_limit_exceeded {
+            "timedOut"
+        } else if self.errored {
+            "errored"
+        } else {
+            "completed"
+        }
+    }
+
+    // The times the clock went backwards, which were counted as no time.
+    pub fn clock_anomalies(&self) -> usize {
+        self.clock_anomalies
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // The metadata the session was started with, sorted by the key.
+    pub fn metadata(&self) -> impl Iterator<Item = (&str, &MetaValue)> + '_ {
+        self.metadata.iter().map(|(k, v)| (k.as_str(), v))
+    }
+}
+
+// Renders the text report, like `tostring(result)` does in Lua.
+impl Display for ProfilingResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.report())
+    }
+}
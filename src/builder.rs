@@ -0,0 +1,325 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+use lua::State;
+
+use crate::config::{ClockSource, Config, HookTime, MetaValue, Recursion, TailCalls, Threshold};
+use crate::trace::{Event, EventSink};
+use crate::Profiler;
+
+// Configures a profiler from Rust, like the options table of `lprofile.Profiler` does from Lua.
+// The options that take Lua functions (`stream`, `key`, `onNewFunction`, `resolveName`, and
+// `targets`) are only available from Lua.
+//
+//     let mut state = lua::State::new();
+//     ProfilerBuilder::new().sample(10_000).memory(true).build(&mut state);
+//     state.set_global("profiler");
+//
+// Like the Lua constructor, the methods panic if given values it would reject.
+#[derive(Clone, Default)]
+pub struct ProfilerBuilder {
+    config: Config,
+    sink: Option<Rc<RefCell<dyn EventSink>>>,
+}
+
+// the sink has no `Debug` to require
+impl fmt::Debug for ProfilerBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProfilerBuilder")
+            .field("config", &self.config)
+            .field("sink", &self.sink.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl ProfilerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The `sample` option: the number of instructions between samples, which must be positive.
+    pub fn sample(mut self, interval: i32) -> Self {
+        assert!(interval > 0, "the sample interval must be positive");
+        self.config.sample_interval = Some(interval);
+
+        self
+    }
+
+    pub fn instructions(mut self, enabled: bool) -> Self {
+        self.config.count_instructions = enabled;
+
+        self
+    }
+
+    pub fn recursion(mut self, recursion: Recursion) -> Self {
+        self.config.recursion = recursion;
+
+        self
+    }
+
+    pub fn tail_calls(mut self, tail_calls: TailCalls) -> Self {
+        self.config.tail_calls = tail_calls;
+
+        self
+    }
+
+    pub fn clock(mut self, clock: ClockSource) -> Self {
+        self.config.clock = clock;
+
+        self
+    }
+
+    pub fn hook_time(mut self, hook_time: HookTime) -> Self {
+        self.config.hook_time = hook_time;
+
+        self
+    }
+
+    pub fn max_stack_depth(mut self, depth: usize) -> Self {
+        assert!(depth > 0, "the stack depth must be positive");
+        self.config.max_stack_depth = Some(depth);
+
+        self
+    }
+
+    pub fn max_functions(mut self, max: usize) -> Self {
+        assert!(max > 0, "the maximum number of functions must be positive");
+        self.config.max_functions = Some(max);
+
+        self
+    }
+
+    pub fn min_calls(mut self, min: usize) -> Self {
+        assert!(min > 0, "the minimum number of calls must be positive");
+        self.config.min_calls = Some(min);
+
+        self
+    }
+
+    pub fn noise_floor(mut self, floor: Duration) -> Self {
+        self.config.noise_floor = Some(floor);
+
+        self
+    }
+
+    pub fn drop_insignificant(mut self, enabled: bool) -> Self {
+        self.config.drop_insignificant = enabled;
+
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.config.deadline = Some(deadline);
+
+        self
+    }
+
+    pub fn instruction_limit(mut self, limit: i32) -> Self {
+        self.config.instruction_limit = Some(limit);
+
+        self
+    }
+
+    pub fn source_root(mut self, root: impl Into<String>) -> Self {
+        self.config.source_root = Some(root.into());
+
+        self
+    }
+
+    // The `flushFile` option. Without `flush_interval` and `flush_events`, the snapshots are
+    // taken every second.
+    pub fn flush_file(mut self, path: impl Into<String>) -> Self {
+        self.config.flush_file = Some(path.into());
+
+        self
+    }
+
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        assert!(
+            interval > Duration::new(0, 0),
+            "the flush interval must be positive"
+        );
+        self.config.flush_interval = Some(interval);
+
+        self
+    }
+
+    pub fn flush_events(mut self, events: usize) -> Self {
+        assert!(
+            events > 0,
+            "the number of events between flushes must be positive"
+        );
+        self.config.flush_events = Some(events);
+
+        self
+    }
+
+    pub fn call_lines(mut self, enabled: bool) -> Self {
+        self.config.call_lines = enabled;
+
+        self
+    }
+
+    pub fn merge_c_functions(mut self, enabled: bool) -> Self {
+        self.config.merge_c_functions = enabled;
+
+        self
+    }
+
+    pub fn protected(mut self, enabled: bool) -> Self {
+        self.config.protected = enabled;
+
+        self
+    }
+
+    // The `threshold` option. A share must be above 0 and at most 1.
+    pub fn threshold(mut self, threshold: Threshold) -> Self {
+        if let Threshold::Share(share) = threshold {
+            assert!(share > 0.0 && share <= 1.0, "the share must be in (0, 1]");
+        }
+
+        self.config.threshold = Some(threshold);
+
+        self
+    }
+
+    pub fn memory(mut self, enabled: bool) -> Self {
+        self.config.memory = enabled;
+
+        self
+    }
+
+    // The `liveBytes` option, which enables `memory` as well.
+    pub fn live_bytes(mut self, enabled: bool) -> Self {
+        self.config.live_bytes = enabled;
+        self.config.memory |= enabled;
+
+        self
+    }
+
+    pub fn code_size(mut self, enabled: bool) -> Self {
+        self.config.code_size = enabled;
+
+        self
+    }
+
+    pub fn self_only(mut self, enabled: bool) -> Self {
+        self.config.self_only = enabled;
+
+        self
+    }
+
+    pub fn skip_names(mut self, enabled: bool) -> Self {
+        self.config.skip_names = enabled;
+
+        self
+    }
+
+    pub fn segments(mut self, enabled: bool) -> Self {
+        self.config.segments = enabled;
+
+        self
+    }
+
+    pub fn arg_counts(mut self, enabled: bool) -> Self {
+        self.config.arg_counts = enabled;
+
+        self
+    }
+
+    // The `callsOnly` option. `build` panics if it's combined with an option it can't be.
+    pub fn calls_only(mut self, enabled: bool) -> Self {
+        self.config.calls_only = enabled;
+
+        self
+    }
+
+    // The `precision` option: the number of decimal places, from 0 to 9.
+    pub fn precision(mut self, decimals: u32) -> Self {
+        assert!(decimals <= 9, "the precision must be from 0 to 9");
+        self.config.precision = Some(decimals);
+
+        self
+    }
+
+    pub fn window(mut self, length: Duration) -> Self {
+        assert!(length > Duration::new(0, 0), "the window must be positive");
+        self.config.window = Some(length);
+
+        self
+    }
+
+    // The `record` option: whether to keep the hook events for `profiler:events`.
+    pub fn record(mut self, enabled: bool) -> Self {
+        self.config.record = enabled;
+
+        self
+    }
+
+    pub fn chain_hooks(mut self, enabled: bool) -> Self {
+        self.config.chain_hooks = enabled;
+
+        self
+    }
+
+    pub fn metamethods(mut self, enabled: bool) -> Self {
+        self.config.metamethods = enabled;
+
+        self
+    }
+
+    pub fn upvalue_names(mut self, enabled: bool) -> Self {
+        self.config.upvalue_names = enabled;
+
+        self
+    }
+
+    // Adds a key-value pair to the metadata of the sessions. The numbers must not be NaN.
+    pub fn metadata(mut self, key: impl Into<String>, value: MetaValue) -> Self {
+        assert!(
+            !matches!(value, MetaValue::Number(v) if v.is_nan()),
+            "the metadata must not be NaN"
+        );
+        self.config.metadata.insert(key.into(), value);
+
+        self
+    }
+
+    // Sends the calls of the sessions to `sink` as they begin and end. The caller keeps a handle
+    // to read what it collected; the copies made with `profiler:clone()` share it. The sink must
+    // not be borrowed while a session runs.
+    pub fn event_sink(mut self, sink: Rc<RefCell<dyn EventSink>>) -> Self {
+        self.sink = Some(sink);
+
+        self
+    }
+
+    // Pushes the profiler to the stack of `state`, where Lua code can use it like the one
+    // returned by `lprofile.Profiler`.
+    pub fn build(self, state: &mut State) {
+        if let Some(other) = self.config.calls_only_conflict() {
+            panic!("callsOnly cannot be combined with {}", other);
+        }
+
+        if let Some(msg) = self.config.version_conflict() {
+            panic!("{}", msg);
+        }
+
+        // the calls are counted without tracking when they end
+        if self.config.calls_only && self.sink.is_some() {
+            panic!("callsOnly cannot be combined with an event sink");
+        }
+
+        let mut profiler = Profiler::with_config(self.config);
+        profiler.sink = self.sink;
+        Profiler::push(state, profiler);
+    }
+
+    // Pushes the result table of the recorded events, as if a session with this configuration
+    // had received them. Sampling, instruction counting, and the window are ignored.
+    pub fn replay(self, events: &[Event], state: &mut State) {
+        Profiler::replay(self.config, events).move_to_lua(state, None);
+    }
+}
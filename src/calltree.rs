@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::{FunctionKey, ProfilingResult};
+
+// The indentation of each level of the tree.
+const INDENT: &str = "  ";
+
+// The functions that call each other, directly or not, which gprof reports as a cycle.
+pub(crate) struct Cycle {
+    pub(crate) members: Vec<FunctionKey>,
+    // the calls of the members made from outside of the cycle, and the ones between them
+    pub(crate) calls: usize,
+    pub(crate) recursive_calls: usize,
+    // the time the cycle took, counting the time of the inner calls once, and the sum of the
+    // self-times of the members
+    pub(crate) total_time: Duration,
+    pub(crate) total_self_time: Duration,
+}
+
+impl ProfilingResult {
+    // The children of a call tree node, the ones that took longer first.
+    fn sorted_children(&self, node: usize) -> Vec<usize> {
+        let mut children = self.tree[node]
+            .children
+            .values()
+            .copied()
+            .collect::<Vec<_>>();
+        children.sort_by_key(|&child| std::cmp::Reverse(self.tree[child].total_time));
+
+        children
+    }
+
+    // Finds the functions whose id or name is `s`. Several functions may share a name.
+    pub(crate) fn find_keys(&self, s: &str) -> HashSet<FunctionKey> {
+        self.data
+            .keys()
+            .copied()
+            .filter(|&k| match k {
+                FunctionKey::OTHER => s == k.id() || s == "<other>",
+                k => k.id() == s || self.name_of(k) == s,
+            })
+            .collect()
+    }
+
+    // Sums the total times of the outermost call tree nodes of the functions: the time they and
+    // everything they called took, counting the recursive calls once.
+    pub(crate) fn subtree_time(&self, keys: &HashSet<FunctionKey>) -> Duration {
+        // whether a node is called by one of the functions; parents precede their children
+        let mut inside = vec![false; self.tree.len()];
+        let mut time = Duration::new(0, 0);
+
+        for (i, node) in self.tree.iter().enumerate() {
+            for &child in node.children.values() {
+                let matches = matches!(self.tree[child].key, Some(key) if keys.contains(&key));
+
+                if matches && !inside[i] {
+                    time = time.saturating_add(self.tree[child].total_time);
+                }
+
+                inside[child] = inside[i] || matches;
+            }
+        }
+
+        time
+    }
+
+    // Finds the cycles of the call graph: its strongly connected components, found with Tarjan's
+    // algorithm, that have several functions or a function calling itself. The longest ones come
+    // first.
+    pub(crate) fn cycles(&self) -> Vec<Cycle> {
+        let mut keys = self.data.keys().copied().collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|key| key.0);
+
+        let indices = keys
+            .iter()
+            .enumerate()
+            .map(|(i, &key)| (key, i))
+            .collect::<HashMap<_, _>>();
+
+        // the callees of each function
+        let mut callees = vec![Vec::new(); keys.len()];
+
+        for node in &self.tree {
+            if let Some(&caller) = node.key.as_ref().and_then(|key| indices.get(key)) {
+                callees[caller].extend(node.children.keys().filter_map(|key| indices.get(key)));
+            }
+        }
+
+        for v in &mut callees {
+            v.sort_unstable();
+            v.dedup();
+        }
+
+        let components = strongly_connected(&callees);
+
+        let mut cycles = components
+            .into_iter()
+            .filter(|component| {
+                component.len() > 1 || callees[component[0]].contains(&component[0])
+            })
+            .map(|component| {
+                let mut members = component.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+                members.sort_unstable_by_key(|key| key.0);
+
+                let set = members.iter().copied().collect::<HashSet<_>>();
+                let mut calls = 0usize;
+                let mut recursive_calls = 0usize;
+
+                for node in &self.tree {
+                    let inside = matches!(node.key, Some(key) if set.contains(&key));
+
+                    for (key, &child) in &node.children {
+                        match (set.contains(key), inside) {
+                            (true, true) => {
+                                recursive_calls =
+                                    recursive_calls.saturating_add(self.tree[child].calls)
+                            }
+                            (true, false) => calls = calls.saturating_add(self.tree[child].calls),
+                            _ => {}
+                        }
+                    }
+                }
+
+                let total_self_time = members
+                    .iter()
+                    .filter_map(|key| self.data.get(key))
+                    .fold(Duration::new(0, 0), |sum, v| {
+                        sum.saturating_add(v.total_self_time)
+                    });
+
+                Cycle {
+                    calls,
+                    recursive_calls,
+                    total_time: self.subtree_time(&set),
+                    total_self_time,
+                    members,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        cycles.sort_by(|lhs, rhs| {
+            rhs.total_time
+                .cmp(&lhs.total_time)
+                .then(rhs.total_self_time.cmp(&lhs.total_self_time))
+                .then(lhs.members[0].0.cmp(&rhs.members[0].0))
+        });
+
+        cycles
+    }
+
+    // Renders the call tree as indented text, a line per node: the callees are listed under their
+    // callers with the calls, the total time, and the share of the caller's total time they took.
+    // A call to a function already on the path is marked as recursive, and the calls it made
+    // aren't listed, since their time is part of the outer call.
+    pub(crate) fn to_tree_text(&self) -> String {
+        let mut out = String::new();
+
+        // the functions on the path to the node being rendered
+        let mut path = Vec::new();
+        // the nodes left to render, with their depth and the total time of the caller
+        let total_time = self.total_time.unwrap_or_default();
+        let mut pending = self
+            .sorted_children(0)
+            .into_iter()
+            .rev()
+            .map(|child| (child, 0, total_time))
+            .collect::<Vec<_>>();
+
+        while let Some((node, depth, parent_time)) = pending.pop() {
+            let v = &self.tree[node];
+            let key = v.key.unwrap();
+            path.truncate(depth);
+
+            write!(
+                out,
+                "{}{}: {} calls, {:.6} s",
+                INDENT.repeat(depth),
+                self.name_of(key),
+                v.calls,
+                v.total_time.as_secs_f64(),
+            )
+            .unwrap();
+
+            if parent_time > Duration::new(0, 0) {
+                let share = v.total_time.as_secs_f64() / parent_time.as_secs_f64();
+                write!(out, " ({:.1}%)", share * 100.0).unwrap();
+            }
+
+            if path.contains(&key) {
+                writeln!(out, " [recursive]").unwrap();
+
+                continue;
+            }
+
+            writeln!(out).unwrap();
+            path.push(key);
+
+            for child in self.sorted_children(node).into_iter().rev() {
+                pending.push((child, depth + 1, v.total_time));
+            }
+        }
+
+        out
+    }
+}
+
+// Tarjan's algorithm over the adjacency lists of a graph, without recursion, since the call
+// graphs of deep programs could overflow the stack. Returns the strongly connected components.
+fn strongly_connected(graph: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    const UNVISITED: usize = usize::MAX;
+
+    let mut index = vec![UNVISITED; graph.len()];
+    let mut low = vec![0; graph.len()];
+    let mut on_stack = vec![false; graph.len()];
+    let mut stack = Vec::new();
+    let mut next = 0;
+    let mut components = Vec::new();
+
+    for start in 0..graph.len() {
+        if index[start] != UNVISITED {
+            continue;
+        }
+
+        // the vertices being visited, with the next of their edges to follow
+        let mut visiting = vec![(start, 0)];
+
+        while let Some(&(v, edge)) = visiting.last() {
+            if index[v] == UNVISITED {
+                index[v] = next;
+                low[v] = next;
+                next += 1;
+                stack.push(v);
+                on_stack[v] = true;
+            }
+
+            if let Some(&w) = graph[v].get(edge) {
+                visiting.last_mut().unwrap().1 += 1;
+
+                if index[w] == UNVISITED {
+                    visiting.push((w, 0));
+                } else if on_stack[w] {
+                    low[v] = low[v].min(index[w]);
+                }
+
+                continue;
+            }
+
+            visiting.pop();
+
+            if let Some(&(parent, _)) = visiting.last() {
+                low[parent] = low[parent].min(low[v]);
+            }
+
+            if low[v] == index[v] {
+                let mut component = Vec::new();
+
+                loop {
+                    let w = stack.pop().unwrap();
+                    on_stack[w] = false;
+                    component.push(w);
+
+                    if w == v {
+                        break;
+                    }
+                }
+
+                components.push(component);
+            }
+        }
+    }
+
+    components
+}
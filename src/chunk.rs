@@ -0,0 +1,309 @@
+use std::convert::TryInto;
+
+use lua::libc::{c_int, c_void, size_t};
+use lua::{ffi, State, Type};
+
+use crate::SourceType;
+
+// The functions defined in a chunk are read from its bytecode: the debug API of Lua 5.3 only
+// describes the functions that are called, while the dump has the prototypes of all of the nested
+// functions. The format is the one written by `ldump.c` of Lua 5.3:
+//
+//   header    signature, version, format, data, the sizes of int, size_t, Instruction,
+//             lua_Integer, and lua_Number, then an integer and a number to check them against
+//   upvalues  u8, the number of the upvalues of the main function
+//   function  source (absent if it's the parent's), int first line, int last line,
+//             u8 parameters, u8 vararg, u8 stack size, code, constants, upvalues, the nested
+//             functions, and the debug information
+//
+// The integers are in the byte order of the machine that wrote the dump, and the sizes vary too,
+// so they're read by the sizes in the header.
+const SIGNATURE: &[u8] = b"\x1bLua";
+const VERSION: u8 = 0x53;
+const FORMAT: u8 = 0;
+const DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+const TEST_INT: u64 = 0x5678;
+
+// the tags of the constants
+const NIL: u8 = 0;
+const BOOLEAN: u8 = 1;
+const FLOAT: u8 = 3;
+const INTEGER: u8 = 3 | 1 << 4;
+const SHORT_STRING: u8 = 4;
+const LONG_STRING: u8 = 4 | 1 << 4;
+
+// A function defined in the chunk.
+struct Prototype {
+    source: Vec<u8>,
+    line: usize,
+    last_line: usize,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+    int_size: usize,
+    size_t_size: usize,
+    instruction_size: usize,
+    integer_size: usize,
+    number_size: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|v| v[0])
+    }
+
+    fn uint(&mut self, size: usize) -> Option<u64> {
+        if size > 8 {
+            return None;
+        }
+
+        let bytes = self.bytes(size)?;
+
+        Some(self.decode(bytes))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> u64 {
+        let fold = |v: u64, &b: &u8| v << 8 | b as u64;
+
+        if self.little_endian {
+            bytes.iter().rev().fold(0, fold)
+        } else {
+            bytes.iter().fold(0, fold)
+        }
+    }
+
+    fn int(&mut self) -> Option<usize> {
+        let size = self.int_size;
+
+        self.uint(size)?.try_into().ok()
+    }
+
+    // Skips `count` items of `size` bytes.
+    fn skip(&mut self, count: usize, size: usize) -> Option<()> {
+        self.bytes(count.checked_mul(size)?).map(|_| ())
+    }
+
+    // Reads a string, which is absent for the sources the nested functions share with the
+    // parent.
+    fn string(&mut self) -> Option<Option<&'a [u8]>> {
+        let size = match self.u8()? {
+            0 => return Some(None),
+            0xff => {
+                let size = self.size_t_size;
+                self.uint(size)?.try_into().ok()?
+            }
+            size => size as usize,
+        };
+
+        // the size counts the terminating zero, which isn't written
+        self.bytes(size.checked_sub(1)?).map(Some)
+    }
+
+    // Reads the prototypes of the function and the ones nested in it, adding them to `out` in the
+    // order they're defined in.
+    fn function(&mut self, parent_source: &[u8], out: &mut Vec<Prototype>) -> Option<()> {
+        let source = self.string()?.unwrap_or(parent_source);
+        let line = self.int()?;
+        let last_line = self.int()?;
+
+        // the parameters, whether it's vararg, and the stack size
+        self.bytes(3)?;
+
+        let code = self.int()?;
+        let instruction_size = self.instruction_size;
+        self.skip(code, instruction_size)?;
+
+        for _ in 0..self.int()? {
+            let size = match self.u8()? {
+                NIL => 0,
+                BOOLEAN => 1,
+                FLOAT => self.number_size,
+                INTEGER => self.integer_size,
+                SHORT_STRING | LONG_STRING => {
+                    self.string()?;
+
+                    0
+                }
+                _ => return None,
+            };
+
+            self.bytes(size)?;
+        }
+
+        // whether each upvalue is on the stack, and its index
+        let upvalues = self.int()?;
+        self.skip(upvalues, 2)?;
+
+        out.push(Prototype {
+            source: source.to_vec(),
+            line,
+            last_line,
+        });
+
+        for _ in 0..self.int()? {
+            self.function(source, out)?;
+        }
+
+        // the line of each instruction
+        let lines = self.int()?;
+        let int_size = self.int_size;
+        self.skip(lines, int_size)?;
+
+        // the names and the scopes of the locals
+        for _ in 0..self.int()? {
+            self.string()?;
+            self.int()?;
+            self.int()?;
+        }
+
+        // the names of the upvalues
+        for _ in 0..self.int()? {
+            self.string()?;
+        }
+
+        Some(())
+    }
+}
+
+// Parses the dump of a function, returning the prototypes it defines, its own first. Returns
+// `None` if the format isn't Lua 5.3's.
+fn parse(data: &[u8]) -> Option<Vec<Prototype>> {
+    let mut reader = Reader {
+        data,
+        little_endian: true,
+        int_size: 0,
+        size_t_size: 0,
+        instruction_size: 0,
+        integer_size: 0,
+        number_size: 0,
+    };
+
+    if reader.bytes(SIGNATURE.len())? != SIGNATURE
+        || reader.u8()? != VERSION
+        || reader.u8()? != FORMAT
+        || reader.bytes(DATA.len())? != DATA
+    {
+        return None;
+    }
+
+    reader.int_size = reader.u8()? as usize;
+    reader.size_t_size = reader.u8()? as usize;
+    reader.instruction_size = reader.u8()? as usize;
+    reader.integer_size = reader.u8()? as usize;
+    reader.number_size = reader.u8()? as usize;
+
+    // the integer tells the byte order: its lowest byte comes first on little-endian machines
+    let test = reader.bytes(reader.integer_size)?;
+    reader.little_endian = test.first() == Some(&(TEST_INT as u8));
+
+    if test.len() > 8 || reader.decode(test) != TEST_INT {
+        return None;
+    }
+
+    let number_size = reader.number_size;
+    reader.bytes(number_size)?;
+
+    // the upvalues of the main function
+    reader.u8()?;
+
+    let mut prototypes = Vec::new();
+    reader.function(b"=?", &mut prototypes)?;
+
+    if !reader.data.is_empty() {
+        return None;
+    }
+
+    Some(prototypes)
+}
+
+// lprofile.functionsOf(f): lists the Lua functions defined in `f`, `f` included, in the order
+// they're defined in. Each is a table with its `source`, `sourceType`, `line`, `lastLine`, and
+// `domain`, like the entries have.
+pub fn functions_of(state: &mut State) -> i32 {
+    unsafe extern "C" fn write(
+        _: *mut ffi::lua_State,
+        p: *const c_void,
+        size: size_t,
+        ud: *mut c_void,
+    ) -> c_int {
+        let out = &mut *(ud as *mut Vec<u8>);
+        out.extend_from_slice(std::slice::from_raw_parts(p as *const u8, size));
+
+        0
+    }
+
+    state.check_type(1, Type::Function);
+    let native = state.is_native_fn(1);
+    state.arg_check(!native, 1, "Lua function expected");
+
+    let mut dump = Vec::new();
+    state.push_value(1);
+
+    // Safety: the function to dump is at the top of the stack, and the writer only appends to
+    // the vector
+    unsafe {
+        ffi::lua_dump(
+            state.as_ptr(),
+            Some(write),
+            &mut dump as *mut Vec<u8> as *mut c_void,
+            0,
+        );
+    }
+
+    state.pop(1);
+
+    let prototypes = match parse(&dump) {
+        Some(prototypes) => prototypes,
+        None => {
+            state.arg_error(1, "unsupported bytecode format");
+            unreachable!()
+        }
+    };
+
+    state.create_table(prototypes.len() as i32, 0);
+
+    for (i, prototype) in prototypes.iter().enumerate() {
+        let (source_type, source) =
+            SourceType::classify(&String::from_utf8_lossy(&prototype.source));
+
+        state.create_table(0, 5);
+
+        state.push("source");
+        state.push(source);
+        state.set_table(-3);
+
+        state.push("sourceType");
+        state.push(source_type.as_str());
+        state.set_table(-3);
+
+        state.push("line");
+        state.push(prototype.line as i64);
+        state.set_table(-3);
+
+        state.push("lastLine");
+        state.push(prototype.last_line as i64);
+        state.set_table(-3);
+
+        // only main chunks start at line 0
+        state.push("domain");
+        state.push(if prototype.line == 0 { "main" } else { "Lua" });
+        state.set_table(-3);
+
+        state.seti(-2, (i + 1) as i64);
+    }
+
+    1
+}
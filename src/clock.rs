@@ -0,0 +1,192 @@
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::config::ClockSource;
+
+// Reads the time for the profiler. The readings are `Instant`s whatever the source, so that the
+// frames and the results don't depend on it.
+pub(crate) trait Clock {
+    fn now(&self) -> Instant;
+
+    // The smallest step between two readings; the times below it are noise.
+    fn resolution(&self) -> Duration;
+}
+
+// The number of intervals the resolution of a clock is the shortest of.
+const RESOLUTION_SAMPLES: usize = 100;
+
+// Measures the resolution of the clock as the shortest of a few intervals between a reading and
+// the next one that differs from it. For the clocks that keep the time in nanoseconds, it's the
+// time reading the clock takes.
+fn measure_resolution(now: impl Fn() -> Instant) -> Duration {
+    (0..RESOLUTION_SAMPLES)
+        .map(|_| {
+            let start = now();
+
+            loop {
+                let elapsed = now().saturating_duration_since(start);
+
+                if elapsed > Duration::new(0, 0) {
+                    break elapsed;
+                }
+            }
+        })
+        .min()
+        .unwrap()
+}
+
+// The monotonic clock of the OS.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SystemClock;
+
+static SYSTEM_RESOLUTION: Lazy<Duration> = Lazy::new(|| measure_resolution(Instant::now));
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn resolution(&self) -> Duration {
+        *SYSTEM_RESOLUTION
+    }
+}
+
+// The timestamp counter of the CPU, which is read without calling into the OS. Its readings are
+// converted to `Instant`s by their offset from a reading of both clocks taken when it was
+// calibrated.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TscClock {
+    epoch: Instant,
+    epoch_cycles: u64,
+    nanos_per_cycle: f64,
+    resolution: Duration,
+}
+
+// Calibrated on first use, like the resolution of the system clock.
+static TSC: Lazy<Option<TscClock>> = Lazy::new(TscClock::calibrate);
+
+impl TscClock {
+    // the time the frequency of the counter is measured over
+    const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+    // Returns the clock if the counter can be trusted: it must be invariant, which means it ticks
+    // at a constant rate whatever the power state of the core, and in sync on all of the cores.
+    pub(crate) fn get() -> Option<Self> {
+        *TSC
+    }
+
+    fn calibrate() -> Option<Self> {
+        if !invariant_tsc() {
+            return None;
+        }
+
+        let epoch = Instant::now();
+        let epoch_cycles = read_tsc();
+
+        // spinning rather than sleeping keeps the core from changing its state in between
+        let end = loop {
+            let now = Instant::now();
+
+            if now - epoch >= Self::CALIBRATION_TIME {
+                break now;
+            }
+        };
+
+        let cycles = read_tsc().wrapping_sub(epoch_cycles);
+
+        if cycles == 0 {
+            return None;
+        }
+
+        let mut clock = Self {
+            epoch,
+            epoch_cycles,
+            nanos_per_cycle: (end - epoch).as_nanos() as f64 / cycles as f64,
+            resolution: Duration::new(0, 0),
+        };
+        clock.resolution = measure_resolution(|| clock.now());
+
+        Some(clock)
+    }
+}
+
+impl Clock for TscClock {
+    fn now(&self) -> Instant {
+        let cycles = read_tsc().wrapping_sub(self.epoch_cycles);
+
+        self.epoch + Duration::from_nanos((cycles as f64 * self.nanos_per_cycle) as u64)
+    }
+
+    fn resolution(&self) -> Duration {
+        self.resolution
+    }
+}
+
+// The clock a profiler was configured with. Where the counter can't be trusted, `ClockSource::Tsc`
+// falls back to the system clock.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum SessionClock {
+    System(SystemClock),
+    Tsc(TscClock),
+}
+
+impl SessionClock {
+    pub(crate) fn new(source: ClockSource) -> Self {
+        match source {
+            ClockSource::Tsc => TscClock::get().map_or(Self::System(SystemClock), Self::Tsc),
+            ClockSource::System => Self::System(SystemClock),
+        }
+    }
+
+    // The name of the clock in the result table.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::System(_) => "system",
+            Self::Tsc(_) => "tsc",
+        }
+    }
+}
+
+impl Clock for SessionClock {
+    fn now(&self) -> Instant {
+        match self {
+            Self::System(clock) => clock.now(),
+            Self::Tsc(clock) => clock.now(),
+        }
+    }
+
+    fn resolution(&self) -> Duration {
+        match self {
+            Self::System(clock) => clock.resolution(),
+            Self::Tsc(clock) => clock.resolution(),
+        }
+    }
+}
+
+// the intrinsics are only unsafe in older versions of Rust
+#[cfg(target_arch = "x86_64")]
+#[allow(unused_unsafe)]
+fn invariant_tsc() -> bool {
+    use std::arch::x86_64::__cpuid;
+
+    // Safety: every x86-64 CPU has cpuid. The flag is in an extended leaf, which may be missing.
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & 1 << 8 != 0 }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn invariant_tsc() -> bool {
+    false
+}
+
+#[cfg(target_arch = "x86_64")]
+fn read_tsc() -> u64 {
+    // Safety: every x86-64 CPU has rdtsc
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+// never called: there's no counter to calibrate
+#[cfg(not(target_arch = "x86_64"))]
+fn read_tsc() -> u64 {
+    0
+}
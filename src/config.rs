@@ -0,0 +1,705 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use lua::libc::c_int;
+use lua::{Index, Reference, State, Type};
+
+use crate::{to_string_lossy, FunctionKey};
+
+// Determines how the total time of recursive functions is computed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Recursion {
+    // only the outermost call of a function on the stack adds to its total time, which makes it
+    // the sum of the calls that don't overlap
+    #[default]
+    Outermost,
+    // every call adds to the total time, so nested calls are counted several times
+    All,
+}
+
+// Determines what happens to the function that made a tail call.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TailCalls {
+    // it stays on the stack until the called function returns, as if the call wasn't a tail call
+    #[default]
+    Keep,
+    // its call ends, and the called function takes its place
+    Replace,
+}
+
+// The clock the calls are timed with.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ClockSource {
+    // the monotonic clock of the OS, `Instant`
+    #[default]
+    System,
+    // the timestamp counter of the CPU, if it's invariant; the system clock otherwise
+    Tsc,
+}
+
+// Determines which frame the time the hook takes to handle an event is charged to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HookTime {
+    // whichever frame is running when the clock is read: the caller until the hook reads it for
+    // a call, and the callee after
+    #[default]
+    Included,
+    // none: the frames are timed as if the events took no time
+    Excluded,
+    // the `<hook>` entry, like the calls left out with `lprofile.ignore` are charged to theirs
+    Overhead,
+}
+
+// The self-time above which a function is reported as a violation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Threshold {
+    Time(Duration),
+    // a share of the session time, from 0 to 1
+    Share(f64),
+}
+
+// the share is validated when read, so it's never NaN
+impl Eq for Threshold {}
+
+// A value of the session metadata. Only the values every export can represent are allowed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetaValue {
+    String(String),
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+}
+
+// the numbers are validated when read, so they're never NaN
+impl Eq for MetaValue {}
+
+// The values as they're written in the serialized results and the pprof comments.
+impl Display for MetaValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String(v) => f.write_str(v),
+            Self::Integer(v) => write!(f, "{}", v),
+            Self::Number(v) => write!(f, "{}", v),
+            Self::Boolean(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl MetaValue {
+    // The name of the type in the serialized results.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Integer(_) => "integer",
+            Self::Number(_) => "number",
+            Self::Boolean(_) => "boolean",
+        }
+    }
+
+    pub(crate) fn from_type_name(type_name: &str, value: &str) -> Option<Self> {
+        match type_name {
+            "string" => Some(Self::String(value.to_owned())),
+            "integer" => value.parse().ok().map(Self::Integer),
+            "number" => value
+                .parse()
+                .ok()
+                .filter(|v: &f64| !v.is_nan())
+                .map(Self::Number),
+            "boolean" => value.parse().ok().map(Self::Boolean),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn push(&self, state: &mut State) {
+        match self {
+            Self::String(v) => state.push(v.as_str()),
+            Self::Integer(v) => state.push(*v),
+            Self::Number(v) => state.push(*v),
+            Self::Boolean(v) => state.push(*v),
+        }
+    }
+
+    // Reads the metadata from the table at the top of the stack; `what` names it in the errors.
+    pub(crate) fn read_table(state: &mut State, arg: Index, what: &str) -> BTreeMap<String, Self> {
+        let msg = format!(
+            "{} must be a table of strings, numbers, or booleans with string keys",
+            what
+        );
+        let is_table = state.type_of(-1) == Some(Type::Table);
+        state.arg_check(is_table, arg, &msg);
+
+        let table = state.get_top();
+        let mut metadata = BTreeMap::new();
+        state.push_nil();
+
+        while state.next(table) {
+            let value = match state.type_of(-1) {
+                Some(Type::String) => to_string_lossy(state, -1).map(Self::String),
+                Some(Type::Number) if state.is_integer(-1) => {
+                    state.to_integerx(-1).map(Self::Integer)
+                }
+                Some(Type::Number) => state
+                    .to_numberx(-1)
+                    .filter(|v| !v.is_nan())
+                    .map(Self::Number),
+                Some(Type::Boolean) => Some(Self::Boolean(state.to_bool(-1))),
+                _ => None,
+            };
+
+            let is_string = state.type_of(-2) == Some(Type::String);
+
+            match (value, is_string) {
+                (Some(value), true) => {
+                    metadata.insert(to_string_lossy(state, -2).unwrap(), value);
+                }
+                _ => {
+                    state.arg_error(arg, &msg);
+                }
+            }
+
+            state.pop(1);
+        }
+
+        metadata
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Config {
+    // the number of instructions between samples; if not set, every call is timed
+    pub sample_interval: Option<c_int>,
+    // the number of frames, from the top of the stack, each sample is attributed to
+    pub max_stack_depth: Option<usize>,
+    pub count_instructions: bool,
+    pub recursion: Recursion,
+    pub tail_calls: TailCalls,
+    pub clock: ClockSource,
+    pub hook_time: HookTime,
+    // a function called on every return instead of collecting the data
+    pub stream: Option<Reference>,
+    // a function returning the key the calls are attributed to instead of the function
+    pub key_fn: Option<Reference>,
+    // a function called with the name and the id of each function the first time it's called
+    pub on_new_function: Option<Reference>,
+    // a function returning the names of the anonymous Lua functions from their source and line
+    pub resolve_name: Option<Reference>,
+    // the number of functions tracked separately; the rest share a single entry
+    pub max_functions: Option<usize>,
+    // the number of calls below which the functions are left out of the result table
+    pub min_calls: Option<usize>,
+    // the self-time below which the calls are charged to their callers
+    pub noise_floor: Option<Duration>,
+    // whether the functions whose calls were all below the noise floor are left out of the result
+    // table
+    pub drop_insignificant: bool,
+    // the time after which the session is aborted
+    pub deadline: Option<Duration>,
+    // the number of instructions after which the session is aborted
+    pub instruction_limit: Option<c_int>,
+    // the interval between the progress lines printed to stderr, if enabled
+    pub progress: Option<Duration>,
+    // the directory file sources are made relative to
+    pub source_root: Option<String>,
+    // whether to record the lines the calls are made from
+    pub call_lines: bool,
+    // whether to merge the C functions with the same name, which may have several addresses
+    pub merge_c_functions: bool,
+    // whether to return the errors of the profiled function instead of propagating them
+    pub protected: bool,
+    pub threshold: Option<Threshold>,
+    // whether to track the allocations made by each function
+    pub memory: bool,
+    // whether to measure the memory each function's allocations still take at the end; implies
+    // `memory`
+    pub live_bytes: bool,
+    // whether to measure the bytecode size of the functions
+    pub code_size: bool,
+    // whether to skip tracking the total times
+    pub self_only: bool,
+    // whether to skip looking up the names of the functions, leaving only their ids
+    pub skip_names: bool,
+    // whether to count the calls only, without timing them or tracking the stack
+    pub calls_only: bool,
+    // whether to track the self-time before the first call and after the last one of each call
+    pub segments: bool,
+    // whether to count the calls made with each number of arguments
+    pub arg_counts: bool,
+    // the number of decimal places to round the times to
+    pub precision: Option<u32>,
+    // the length of the time the recent data is kept for
+    pub window: Option<Duration>,
+    // if set, only the calls made while one of these functions is running are profiled
+    pub targets: Option<HashSet<FunctionKey>>,
+    // if set, only the functions whose sources start with one of these are profiled
+    pub sources: Option<Vec<String>>,
+    // whether to keep the hook events for `profiler:events`
+    pub record: bool,
+    // whether to forward the events to the hook installed before the session
+    pub chain_hooks: bool,
+    // whether to add up the calls of the functions invoked as metamethods by their kind
+    pub metamethods: bool,
+    // whether to read the names of the upvalues of the Lua functions when they're first called
+    pub upvalue_names: bool,
+    // the file the snapshots of the data are appended to during the session, and the time or the
+    // number of events between them
+    pub flush_file: Option<String>,
+    pub flush_interval: Option<Duration>,
+    pub flush_events: Option<usize>,
+    // the key-value pairs describing the sessions, carried to their results
+    pub metadata: BTreeMap<String, MetaValue>,
+}
+
+impl Config {
+    // the sample interval used if the `sample` option is `true`
+    const DEFAULT_SAMPLE_INTERVAL: c_int = 10000;
+    // the time between the snapshots if the `flushFile` option is set alone
+    const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+    // Reads the configuration from the table at `arg`, which may be absent. Raises an error if the
+    // table contains an unknown or invalid option.
+    pub fn from_lua(state: &mut State, arg: Index) -> Self {
+        let mut config = Self::default();
+
+        if state.is_none_or_nil(arg) {
+            return config;
+        }
+
+        state.check_type(arg, Type::Table);
+        state.push_nil();
+
+        while state.next(arg) {
+            // Stack: key value
+            let key = match state.type_of(-2) {
+                Some(Type::String) => to_string_lossy(state, -2).unwrap(),
+                _ => {
+                    let msg = format!(
+                        "option name must be a string, got {}",
+                        state.typename_at(-2)
+                    );
+                    state.arg_error(arg, &msg);
+                    unreachable!();
+                }
+            };
+
+            match key.as_str() {
+                "sample" => {
+                    config.sample_interval = if !state.is_bool(-1) {
+                        Some(Self::positive_int(state, arg, &key))
+                    } else if state.to_bool(-1) {
+                        Some(Self::DEFAULT_SAMPLE_INTERVAL)
+                    } else {
+                        None
+                    }
+                }
+                "instructions" => config.count_instructions = Self::boolean(state, arg, &key),
+                // the callbacks are referenced after the loop
+                "stream" | "key" | "onNewFunction" | "resolveName" => {
+                    let is_fn = state.is_fn(-1);
+                    let msg = format!("option '{}' must be a function", key);
+                    state.arg_check(is_fn, arg, &msg);
+                }
+                "maxFunctions" => {
+                    config.max_functions = Some(Self::positive_int(state, arg, &key) as usize)
+                }
+                "maxStackDepth" => {
+                    config.max_stack_depth = Some(Self::positive_int(state, arg, &key) as usize)
+                }
+                "minCalls" => {
+                    config.min_calls = Some(Self::positive_int(state, arg, &key) as usize)
+                }
+                "noiseFloor" => {
+                    config.noise_floor = Some(Self::seconds(state, arg, &key));
+                }
+                "dropInsignificant" => config.drop_insignificant = Self::boolean(state, arg, &key),
+                "deadline" => {
+                    config.deadline = Some(Self::seconds(state, arg, &key));
+                }
+                "instructionLimit" => {
+                    config.instruction_limit = Some(Self::positive_int(state, arg, &key))
+                }
+                "progress" => {
+                    config.progress = Some(Self::seconds(state, arg, &key));
+                }
+                "flushInterval" => {
+                    config.flush_interval = Some(Self::seconds(state, arg, &key));
+                }
+                "flushEvents" => {
+                    config.flush_events = Some(Self::positive_int(state, arg, &key) as usize)
+                }
+                "window" => {
+                    config.window = Some(Self::seconds(state, arg, &key));
+                }
+                "memory" => config.memory = Self::boolean(state, arg, &key),
+                "liveBytes" => config.live_bytes = Self::boolean(state, arg, &key),
+                "codeSize" => config.code_size = Self::boolean(state, arg, &key),
+                "selfOnly" => config.self_only = Self::boolean(state, arg, &key),
+                "skipNames" => config.skip_names = Self::boolean(state, arg, &key),
+                "callsOnly" => config.calls_only = Self::boolean(state, arg, &key),
+                "segments" => config.segments = Self::boolean(state, arg, &key),
+                "argCounts" => config.arg_counts = Self::boolean(state, arg, &key),
+                "record" => config.record = Self::boolean(state, arg, &key),
+                "chainHooks" => config.chain_hooks = Self::boolean(state, arg, &key),
+                "metamethods" => config.metamethods = Self::boolean(state, arg, &key),
+                "upvalueNames" => config.upvalue_names = Self::boolean(state, arg, &key),
+                "precision" => config.precision = Some(Self::precision(state, arg)),
+                "targets" => config.targets = Some(Self::targets(state, arg)),
+                "sources" => config.sources = Some(Self::sources(state, arg)),
+                "threshold" => config.threshold = Some(Self::threshold(state, arg)),
+                "metadata" => {
+                    config.metadata = MetaValue::read_table(state, arg, "option 'metadata'")
+                }
+                "protected" => config.protected = Self::boolean(state, arg, &key),
+                "callLines" => config.call_lines = Self::boolean(state, arg, &key),
+                "mergeCFunctions" => config.merge_c_functions = Self::boolean(state, arg, &key),
+                "sourceRoot" => {
+                    let is_string = state.type_of(-1) == Some(Type::String);
+                    state.arg_check(is_string, arg, "option 'sourceRoot' must be a string");
+                    config.source_root = to_string_lossy(state, -1);
+                }
+                "flushFile" => {
+                    let is_string = state.type_of(-1) == Some(Type::String);
+                    state.arg_check(is_string, arg, "option 'flushFile' must be a string");
+                    config.flush_file = to_string_lossy(state, -1);
+                }
+                "tailCalls" => {
+                    config.tail_calls = match Self::option(state, arg, &key, &["keep", "replace"]) {
+                        0 => TailCalls::Keep,
+                        _ => TailCalls::Replace,
+                    }
+                }
+                "clock" => {
+                    config.clock = match Self::option(state, arg, &key, &["system", "tsc"]) {
+                        0 => ClockSource::System,
+                        _ => ClockSource::Tsc,
+                    }
+                }
+                "hookTime" => {
+                    let options = ["included", "excluded", "overhead"];
+
+                    config.hook_time = match Self::option(state, arg, &key, &options) {
+                        0 => HookTime::Included,
+                        1 => HookTime::Excluded,
+                        _ => HookTime::Overhead,
+                    }
+                }
+                "recursion" => {
+                    config.recursion = match Self::option(state, arg, &key, &["outermost", "all"]) {
+                        0 => Recursion::Outermost,
+                        _ => Recursion::All,
+                    }
+                }
+                _ => {
+                    state.arg_error(arg, &format!("unknown option '{}'", key));
+                }
+            }
+
+            state.pop(1);
+        }
+
+        if config.live_bytes {
+            config.memory = true;
+        }
+
+        // Every option has been read without raising an error, so none of these references can
+        // leak. From here on, the errors have to release them with `unreference_all`.
+        config.stream = Self::callback(state, arg, "stream");
+        config.key_fn = Self::callback(state, arg, "key");
+        config.on_new_function = Self::callback(state, arg, "onNewFunction");
+        config.resolve_name = Self::callback(state, arg, "resolveName");
+
+        if config.stream.is_some() {
+            if config.sample_interval.is_some() || config.count_instructions {
+                config.unreference_all(state);
+                let msg = "option 'stream' cannot be combined with 'sample' or 'instructions'";
+                state.arg_error(arg, msg);
+            }
+
+            if config.key_fn.is_some() {
+                config.unreference_all(state);
+                state.arg_error(arg, "option 'stream' cannot be combined with 'key'");
+            }
+
+            if config.record {
+                config.unreference_all(state);
+                state.arg_error(arg, "option 'stream' cannot be combined with 'record'");
+            }
+
+            if config.flush_file.is_some() {
+                config.unreference_all(state);
+                state.arg_error(arg, "option 'stream' cannot be combined with 'flushFile'");
+            }
+        }
+
+        if config.max_stack_depth.is_some() && config.sample_interval.is_none() {
+            config.unreference_all(state);
+            state.arg_error(arg, "option 'maxStackDepth' needs 'sample'");
+        }
+
+        if config.noise_floor.is_some() && config.sample_interval.is_some() {
+            config.unreference_all(state);
+            state.arg_error(arg, "option 'noiseFloor' cannot be combined with 'sample'");
+        }
+
+        if config.drop_insignificant && config.noise_floor.is_none() {
+            config.unreference_all(state);
+            state.arg_error(arg, "option 'dropInsignificant' needs 'noiseFloor'");
+        }
+
+        let flushes = config.flush_interval.is_some() || config.flush_events.is_some();
+
+        if flushes && config.flush_file.is_none() {
+            config.unreference_all(state);
+            let msg = "options 'flushInterval' and 'flushEvents' need 'flushFile'";
+            state.arg_error(arg, msg);
+        }
+
+        if let Some(other) = config.calls_only_conflict() {
+            config.unreference_all(state);
+
+            let msg = format!("option 'callsOnly' cannot be combined with '{}'", other);
+            state.arg_error(arg, &msg);
+        }
+
+        if let Some(msg) = config.version_conflict() {
+            config.unreference_all(state);
+            state.arg_error(arg, msg);
+        }
+
+        config
+    }
+
+    // References the function the option `key` of the table at `arg` is set to, if it's set.
+    fn callback(state: &mut State, arg: Index, key: &str) -> Option<Reference> {
+        state.push(key);
+
+        if state.raw_get(arg) == Type::Nil {
+            state.pop(1);
+            return None;
+        }
+
+        Some(state.reference(lua::REGISTRYINDEX))
+    }
+
+    // Releases the references to the callbacks, before raising an error about the options.
+    fn unreference_all(&self, state: &mut State) {
+        let references = [
+            self.stream,
+            self.key_fn,
+            self.on_new_function,
+            self.resolve_name,
+        ];
+
+        for reference in references.iter().flatten() {
+            state.unreference(lua::REGISTRYINDEX, *reference);
+        }
+    }
+
+    // The time between the snapshots appended to the `flush_file`, if they're taken by time.
+    pub(crate) fn flush_interval(&self) -> Option<Duration> {
+        match self.flush_file {
+            Some(_) if self.flush_interval.is_none() && self.flush_events.is_none() => {
+                Some(Self::DEFAULT_FLUSH_INTERVAL)
+            }
+            Some(_) => self.flush_interval,
+            None => None,
+        }
+    }
+
+    // Returns why one of the options can't be used with the version of Lua the module is built
+    // for, if one can't.
+    pub(crate) fn version_conflict(&self) -> Option<&'static str> {
+        if !cfg!(feature = "lua51") {
+            return None;
+        }
+
+        if self.arg_counts {
+            return Some("option 'argCounts' isn't supported with Lua 5.1");
+        }
+
+        // Lua 5.1 reports the tail calls when the frames they replaced end
+        if self.tail_calls == TailCalls::Replace {
+            return Some("option 'tailCalls' cannot be 'replace' with Lua 5.1");
+        }
+
+        None
+    }
+
+    // Returns the name of an option that needs the calls to be timed or the stack to be tracked,
+    // if it's set along with `calls_only`.
+    pub(crate) fn calls_only_conflict(&self) -> Option<&'static str> {
+        if !self.calls_only {
+            return None;
+        }
+
+        let options = [
+            ("sample", self.sample_interval.is_some()),
+            ("instructions", self.count_instructions),
+            ("stream", self.stream.is_some()),
+            ("key", self.key_fn.is_some()),
+            ("onNewFunction", self.on_new_function.is_some()),
+            ("resolveName", self.resolve_name.is_some()),
+            ("deadline", self.deadline.is_some()),
+            ("progress", self.progress.is_some()),
+            ("callLines", self.call_lines),
+            ("threshold", self.threshold.is_some()),
+            ("liveBytes", self.live_bytes),
+            ("memory", self.memory),
+            ("codeSize", self.code_size),
+            ("segments", self.segments),
+            ("argCounts", self.arg_counts),
+            ("window", self.window.is_some()),
+            ("targets", self.targets.is_some()),
+            ("sources", self.sources.is_some()),
+            ("record", self.record),
+            ("hookTime", self.hook_time != HookTime::Included),
+            ("flushFile", self.flush_file.is_some()),
+            ("metamethods", self.metamethods),
+            ("noiseFloor", self.noise_floor.is_some()),
+        ];
+
+        options.iter().find(|(_, set)| *set).map(|(name, _)| *name)
+    }
+
+    // Returns the index of the value in `options`.
+    fn option(state: &mut State, arg: Index, key: &str, options: &[&str]) -> usize {
+        let value = match state.type_of(-1) {
+            Some(Type::String) => to_string_lossy(state, -1).unwrap(),
+            _ => String::new(),
+        };
+
+        match options.iter().position(|&v| v == value) {
+            Some(i) => i,
+            None => {
+                let msg = format!("option '{}' must be one of: {}", key, options.join(", "));
+                state.arg_error(arg, &msg);
+                unreachable!();
+            }
+        }
+    }
+
+    fn boolean(state: &mut State, arg: Index, key: &str) -> bool {
+        if !state.is_bool(-1) {
+            let msg = format!("option '{}' must be a boolean", key);
+            state.arg_error(arg, &msg);
+        }
+
+        state.to_bool(-1)
+    }
+
+    // Reads an array of functions.
+    fn targets(state: &mut State, arg: Index) -> HashSet<FunctionKey> {
+        let msg = "option 'targets' must be an array of functions";
+        let is_table = state.type_of(-1) == Some(Type::Table);
+        state.arg_check(is_table, arg, msg);
+
+        let table = state.get_top();
+        let mut targets = HashSet::new();
+        state.push_nil();
+
+        while state.next(table) {
+            let is_fn = state.is_fn(-1);
+            state.arg_check(is_fn, arg, msg);
+            targets.insert(FunctionKey(state.to_pointer(-1) as usize));
+            state.pop(1);
+        }
+
+        targets
+    }
+
+    // Reads either a source prefix or an array of them.
+    fn sources(state: &mut State, arg: Index) -> Vec<String> {
+        let msg = "option 'sources' must be a string or an array of strings";
+
+        if state.type_of(-1) == Some(Type::String) {
+            return vec![to_string_lossy(state, -1).unwrap()];
+        }
+
+        let is_table = state.type_of(-1) == Some(Type::Table);
+        state.arg_check(is_table, arg, msg);
+
+        let table = state.get_top();
+        let mut sources = Vec::new();
+        state.push_nil();
+
+        while state.next(table) {
+            let is_string = state.type_of(-1) == Some(Type::String);
+            state.arg_check(is_string, arg, msg);
+            sources.push(to_string_lossy(state, -1).unwrap());
+            state.pop(1);
+        }
+
+        sources
+    }
+
+    // Reads either a number of seconds or a percentage string like "5%".
+    fn threshold(state: &mut State, arg: Index) -> Threshold {
+        if state.type_of(-1) == Some(Type::String) {
+            let value = to_string_lossy(state, -1).unwrap();
+            let share = value
+                .strip_suffix('%')
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(|v| *v > 0.0 && *v <= 100.0);
+
+            if let Some(share) = share {
+                return Threshold::Share(share / 100.0);
+            }
+        } else if let Some(secs) = state.to_numberx(-1).filter(|v| *v > 0.0) {
+            if let Ok(time) = Duration::try_from_secs_f64(secs) {
+                return Threshold::Time(time);
+            }
+        }
+
+        let msg = "option 'threshold' must be a positive number or a percentage";
+        state.arg_error(arg, msg);
+        unreachable!();
+    }
+
+    fn positive_number(state: &mut State, arg: Index, key: &str) -> f64 {
+        match state.to_numberx(-1) {
+            Some(v) if v > 0.0 && v.is_finite() => v,
+            _ => {
+                let msg = format!("option '{}' must be a positive number", key);
+                state.arg_error(arg, &msg);
+                unreachable!();
+            }
+        }
+    }
+
+    // Reads a positive number of seconds. `Duration` can't hold more than about 1.8e19 seconds,
+    // and converting a longer time would panic.
+    fn seconds(state: &mut State, arg: Index, key: &str) -> Duration {
+        let secs = Self::positive_number(state, arg, key);
+
+        match Duration::try_from_secs_f64(secs) {
+            Ok(time) => time,
+            Err(_) => {
+                let msg = format!("option '{}' is too large", key);
+                state.arg_error(arg, &msg);
+                unreachable!();
+            }
+        }
+    }
+
+    // Reads a number of decimal places. More than nanoseconds would be meaningless.
+    fn precision(state: &mut State, arg: Index) -> u32 {
+        match state.to_integerx(-1) {
+            Some(v) if (0..=9).contains(&v) => v as u32,
+            _ => {
+                state.arg_error(arg, "option 'precision' must be an integer from 0 to 9");
+                unreachable!();
+            }
+        }
+    }
+
+    fn positive_int(state: &mut State, arg: Index, key: &str) -> c_int {
+        match state.to_integerx(-1) {
+            Some(v) if v > 0 && v <= c_int::MAX as i64 => v as c_int,
+            _ => {
+                let msg = format!("option '{}' must be a positive integer", key);
+                state.arg_error(arg, &msg);
+                unreachable!();
+            }
+        }
+    }
+}
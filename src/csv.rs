@@ -0,0 +1,95 @@
+use crate::ProfilingResult;
+
+impl ProfilingResult {
+    // Renders the entries as CSV, a row per function sorted by the self-time like the report,
+    // after a header row. The columns of the numbers that weren't tracked are left out, like the
+    // fields of the entry tables; a cell is empty where an entry has no value.
+    pub(crate) fn to_csv(&self) -> String {
+        let mut columns = vec!["id", "name", "source", "line", "calls"];
+
+        if !self.calls_only {
+            if !self.self_only {
+                columns.extend(&["totalTime", "cumulativeTime"]);
+            }
+
+            columns.extend(&["totalSelfTime", "selfShare", "errors", "stdDev"]);
+
+            if self.is_sampled() {
+                columns.push("selfSamples");
+            }
+
+            if self.counts_instructions {
+                columns.push("instructions");
+            }
+
+            if self.tracks_memory {
+                columns.extend(&["bytesAllocated", "bytesFreed"]);
+            }
+
+            if self.tracks_live_bytes {
+                columns.push("liveBytes");
+            }
+        }
+
+        let mut out = columns.join(",");
+        out.push('\n');
+
+        let session_time = self.total_time.filter(|v| !v.is_zero());
+
+        for (key, v) in self.by_self_time() {
+            let name = v.name.as_ref();
+
+            for (i, &column) in columns.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+
+                let cell = match column {
+                    "id" => key.id(),
+                    "name" => self.name_of(key),
+                    "source" => name.map(|v| v.source.to_string()).unwrap_or_default(),
+                    "line" => name
+                        .and_then(|v| v.line)
+                        .map(|v| v.to_string())
+                        .unwrap_or_default(),
+                    "selfShare" => session_time
+                        .map(|total| {
+                            (v.total_self_time.as_secs_f64() / total.as_secs_f64()).to_string()
+                        })
+                        .unwrap_or_default(),
+                    "stdDev" => v
+                        .std_dev()
+                        .map(|v| self.round(v).to_string())
+                        .unwrap_or_default(),
+                    "calls" => v.calls.to_string(),
+                    "totalTime" => self.seconds(v.total_time).to_string(),
+                    "cumulativeTime" => self.seconds(v.cumulative_time).to_string(),
+                    "totalSelfTime" => self.seconds(v.total_self_time).to_string(),
+                    "errors" => v.errors.to_string(),
+                    "selfSamples" => v.self_samples.to_string(),
+                    "instructions" => v.instructions.to_string(),
+                    "bytesAllocated" => v.memory.bytes_allocated.to_string(),
+                    "bytesFreed" => v.memory.bytes_freed.to_string(),
+                    "liveBytes" => v.memory.live_bytes.to_string(),
+                    _ => unreachable!(),
+                };
+
+                out.push_str(&quote(&cell));
+            }
+
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+// Quotes a cell if it has a comma, a quote, or a line break, doubling the quotes, as RFC 4180
+// specifies.
+fn quote(cell: &str) -> String {
+    if !cell.contains(&[',', '"', '\n', '\r'][..]) {
+        return cell.to_owned();
+    }
+
+    format!("\"{}\"", cell.replace('"', "\"\""))
+}
@@ -0,0 +1,84 @@
+use std::fmt::Write;
+
+use crate::{Cost, FunctionKey, ProfilingResult};
+
+// The width of the edges costing the most; the others are thinner in proportion to their cost.
+const MAX_PEN_WIDTH: f64 = 5.0;
+
+impl ProfilingResult {
+    // Renders the call graph in the DOT language. Nodes are labeled with the function names, and
+    // edges with the number of calls and their cost: the time spent in them or in the callee's
+    // body.
+    pub(crate) fn to_dot(&self, cost: Cost) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph profile {{").unwrap();
+        writeln!(out, "  node [shape=box];").unwrap();
+
+        for (k, v) in &self.data {
+            writeln!(
+                out,
+                "  {} [label=\"{}\\n{} calls\\ntotal {:.6} s, self {:.6} s\"];",
+                node_id(*k),
+                escape(&self.name_of(*k)),
+                v.calls,
+                v.total_time.as_secs_f64(),
+                v.total_self_time.as_secs_f64(),
+            )
+            .unwrap();
+        }
+
+        let edges = self
+            .edges()
+            .into_iter()
+            .map(|(k, (calls, total_time, self_time))| match cost {
+                Cost::Inclusive => (k, (calls, total_time)),
+                Cost::Exclusive => (k, (calls, self_time)),
+            })
+            .collect::<Vec<_>>();
+
+        let max_time = edges
+            .iter()
+            .map(|(_, (_, time))| time.as_secs_f64())
+            .fold(0.0, f64::max);
+
+        let unit = match cost {
+            Cost::Inclusive => "s",
+            Cost::Exclusive => "s self",
+        };
+
+        for &((caller, callee), (calls, time)) in &edges {
+            let width = if max_time > 0.0 {
+                1.0 + (MAX_PEN_WIDTH - 1.0) * time.as_secs_f64() / max_time
+            } else {
+                1.0
+            };
+
+            writeln!(
+                out,
+                "  {} -> {} [label=\"{} calls\\n{:.6} {}\", penwidth={:.2}];",
+                node_id(caller),
+                node_id(callee),
+                calls,
+                time.as_secs_f64(),
+                unit,
+                width,
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "}}").unwrap();
+
+        out
+    }
+}
+
+fn node_id(key: FunctionKey) -> String {
+    format!("\"{}\"", key)
+}
+
+// Escapes a string for a quoted DOT string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
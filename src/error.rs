@@ -0,0 +1,78 @@
+use lua::{lua_func, State};
+
+// Errors raised by the profiler are tables with the `code` and `message` fields, so that callers
+// can tell them apart without matching the message.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum Error {
+    // a session was started while another one is running
+    AlreadyRunning,
+    // the operation needs a running session
+    NotRunning,
+    // popLabel was called without a label pushed
+    NoLabel,
+    // the operation needs a finished session
+    NotFinished,
+    // the file passed to profileFile or the source passed to profileString could not be loaded
+    LoadFailed,
+    // the session ran for longer than the deadline
+    DeadlineExceeded,
+    // the session ran more instructions than the limit
+    InstructionLimitExceeded,
+    // no function has the given name or id
+    NotFound,
+    // a function took longer than the budget `assertUnder` was given
+    BudgetExceeded,
+}
+
+impl Error {
+    const TYPE_NAME: &'static str = "lprofile.Error";
+
+    fn code(self) -> &'static str {
+        match self {
+            Error::AlreadyRunning => "ALREADY_RUNNING",
+            Error::NotRunning => "NOT_RUNNING",
+            Error::NoLabel => "NO_LABEL",
+            Error::NotFinished => "NOT_FINISHED",
+            Error::LoadFailed => "LOAD_FAILED",
+            Error::DeadlineExceeded => "DEADLINE_EXCEEDED",
+            Error::InstructionLimitExceeded => "INSTRUCTION_LIMIT_EXCEEDED",
+            Error::NotFound => "NOT_FOUND",
+            Error::BudgetExceeded => "BUDGET_EXCEEDED",
+        }
+    }
+
+    // Pushes the error object to the stack.
+    pub(crate) fn push(self, state: &mut State, message: &str) {
+        state.create_table(0, 2);
+
+        state.push(self.code());
+        state.set_field(-2, "code");
+
+        state.push(message);
+        state.set_field(-2, "message");
+
+        if state.new_metatable(Self::TYPE_NAME) {
+            state.push_fn(lua_func!(Self::to_string));
+            state.set_field(-2, "__tostring");
+        }
+
+        state.set_metatable(-2);
+    }
+
+    pub(crate) fn raise(self, state: &mut State, message: &str) -> ! {
+        self.push(state, message);
+        state.error()
+    }
+
+    // __tostring: keeps uncaught errors readable.
+    fn to_string(state: &mut State) -> i32 {
+        state.get_field(1, "code");
+        state.get_field(1, "message");
+
+        let code = state.to_str(-2).unwrap_or("").to_owned();
+        let message = state.to_str(-1).unwrap_or("").to_owned();
+        state.push(format!("{}: {}", code, message));
+
+        1
+    }
+}
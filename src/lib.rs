@@ -1,43 +1,180 @@
-use std::collections::HashMap;
-use std::ffi::{CStr, CString};
+use std::any::Any;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CStr;
 use std::fmt::{self, Display};
+use std::fs;
+use std::io::{self, Write};
 use std::mem::ManuallyDrop;
-use std::sync::Once;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
 use std::time::{Duration, Instant};
 
 use lua::ffi::{self, lua_Debug};
-use lua::libc::c_int;
-use lua::{lua_func, Function, Hook, HookMask, State};
+use lua::libc::{c_int, c_void, size_t};
+use lua::{lua_func, Function, Hook, HookMask, Reference, State, ThreadStatus};
 use once_cell::sync::Lazy;
 
+use clock::{Clock, SessionClock, SystemClock};
+use config::Config;
+use error::Error;
+use memory::{MemoryStats, MemoryTracker};
+use saturate::{clamped, SaturatingAdd};
+use stats::Latencies;
+use version::HookEvent;
+use window::Window;
+
+pub use builder::ProfilerBuilder;
+pub use config::{ClockSource, HookTime, MetaValue, Recursion, TailCalls, Threshold};
+pub use trace::{Event, EventKind, EventSink};
+
+mod api;
+mod builder;
+mod calltree;
+mod chunk;
+mod clock;
+mod config;
+mod csv;
+mod dot;
+mod error;
+mod memory;
+mod pack;
+#[cfg(feature = "pprof")]
+mod pprof;
+mod report;
+mod saturate;
+mod serialize;
+mod stats;
+mod trace;
+mod version;
+mod window;
+
+// The address of a function. It reaches Lua only as the string `id` returns: the pointers of
+// 64-bit hosts don't fit in the numbers of every Lua build, and would be rounded silently.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-struct FunctionKey(usize);
+pub struct FunctionKey(usize);
 
 impl FunctionKey {
+    // the entry the functions beyond `Config::max_functions` are folded into; no function is
+    // allocated at address 0
+    const OTHER: Self = Self(0);
+
+    // Returns `None` if Lua can't tell the function, or if its address would be mistaken for
+    // `OTHER`.
+    //
     // Safety: ar must be a valid pointer to an activation record received by a hook
     unsafe fn from_ar(state: &mut State, ar: &mut lua_Debug) -> Option<Self> {
-        let what = CString::new("f").unwrap();
-
-        match ffi::lua_getinfo(state.as_ptr(), what.as_ptr(), ar) {
+        match ffi::lua_getinfo(state.as_ptr(), b"f\0".as_ptr() as *const _, ar) {
             0 => None,
             _ => {
                 let addr = state.to_pointer(-1) as usize;
                 state.pop(1);
 
-                Some(Self(addr))
+                Some(Self(addr)).filter(|&key| key != Self::OTHER)
+            }
+        }
+    }
+
+    // The `id` of the entry in Lua: the address as a hex string, or "other".
+    pub fn id(self) -> String {
+        match self {
+            Self::OTHER => "other".to_owned(),
+            key => key.to_string(),
+        }
+    }
+
+    // Parses an `id`, the inverse of `id`.
+    pub(crate) fn from_id(id: &str) -> Option<Self> {
+        match id {
+            "other" => Some(Self::OTHER),
+            id => id
+                .strip_prefix("0x")
+                .and_then(|id| usize::from_str_radix(id, 16).ok())
+                .map(Self),
+        }
+    }
+}
+
+impl Display for FunctionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SourceType {
+    File,
+    String,
+    // a function loaded from stripped bytecode
+    Bytecode,
+    C,
+    // a chunk name given by the user
+    Other,
+}
+
+impl SourceType {
+    // The source of a string chunk is the whole code; only a part of the first line is kept.
+    const MAX_STRING_LEN: usize = 40;
+
+    // Splits the source reported by Lua into its type and a readable source.
+    fn classify(source: &str) -> (Self, String) {
+        if let Some(path) = source.strip_prefix('@') {
+            (SourceType::File, path.to_owned())
+        } else if source == "=[C]" {
+            (SourceType::C, "[C]".to_owned())
+        } else if source == "=?" {
+            (SourceType::Bytecode, "?".to_owned())
+        } else if let Some(name) = source.strip_prefix('=') {
+            (SourceType::Other, name.to_owned())
+        } else {
+            let line = source.lines().next().unwrap_or("");
+            let mut result = line.chars().take(Self::MAX_STRING_LEN).collect::<String>();
+
+            if result.len() < source.len() {
+                result.push_str("...");
             }
+
+            (SourceType::String, result)
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SourceType::File => "file",
+            SourceType::String => "string",
+            SourceType::Bytecode => "bytecode",
+            SourceType::C => "C",
+            SourceType::Other => "other",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "file" => Some(SourceType::File),
+            "string" => Some(SourceType::String),
+            "bytecode" => Some(SourceType::Bytecode),
+            "C" => Some(SourceType::C),
+            "other" => Some(SourceType::Other),
+            _ => None,
         }
     }
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-struct FunctionName {
+pub struct FunctionName {
     name: Option<String>,
     function_type: Option<String>,
-    source: String,
+    source_type: SourceType,
+    // shared by the functions defined in the same chunk (see `intern`)
+    source: Rc<str>,
+    // the source before it was made relative to `Config::source_root`
+    raw_source: Rc<str>,
     line: Option<usize>,
     // Lua function / C function / main chunk
     domain: String,
+    // the names of the upvalues of a Lua function, with `Config::upvalue_names`
+    upvalues: Option<Vec<String>>,
 }
 
 impl FunctionName {
@@ -56,38 +193,110 @@ impl FunctionName {
             Some(function_type)
         };
 
-        let source = CStr::from_ptr(&ar.short_src as *const lua::libc::c_char)
-            .to_string_lossy()
-            .into_owned();
+        // Lua 5.3 names the metamethods without the underscores, except for `__gc`
+        let name = match function_type.as_deref() {
+            Some("metamethod") => name.map(|name| metamethod_name(&name)),
+            _ => name,
+        };
 
-        let line = ar.linedefined;
-        let line = if line == -1 {
-            None
-        } else {
-            Some(line as usize)
+        let (source_type, source) =
+            SourceType::classify(&CStr::from_ptr(ar.source).to_string_lossy());
+
+        let domain = CStr::from_ptr(ar.what).to_string_lossy().into_owned();
+
+        // Lua reports line -1 for all C functions, whether light or closures with upvalues
+        let line = match ar.linedefined {
+            line if line < 0 => None,
+            line => Some(line as usize),
         };
 
-        let domain = CStr::from_ptr(ar.what).to_str().unwrap().to_owned();
+        let source = Rc::<str>::from(source);
 
         Self {
             name,
             function_type,
+            source_type,
+            raw_source: source.clone(),
             source,
             line,
             domain,
+            upvalues: None,
+        }
+    }
+
+    // Replaces the sources with the equal ones from `sources`, adding them if necessary, so that
+    // the memory they take scales with the number of chunks rather than functions.
+    fn intern(&mut self, sources: &mut HashSet<Rc<str>>) {
+        for source in [&mut self.source, &mut self.raw_source] {
+            match sources.get(&**source) {
+                Some(interned) => *source = interned.clone(),
+                None => {
+                    sources.insert(source.clone());
+                }
+            }
+        }
+    }
+
+    // The name of the entry of a key returned by the key function.
+    fn custom(key: String) -> Self {
+        let source = Rc::<str>::from("key");
+
+        Self {
+            name: Some(key),
+            function_type: None,
+            source_type: SourceType::Other,
+            raw_source: source.clone(),
+            source,
+            line: None,
+            domain: "key".to_owned(),
+            upvalues: None,
         }
     }
+
+    // Makes the path of a file source relative to `root`. Sources outside of it are kept as is.
+    fn relativize(&mut self, root: &str) {
+        if self.source_type != SourceType::File {
+            return;
+        }
+
+        let root = root.trim_end_matches('/');
+
+        let relative = match self.raw_source.strip_prefix(root) {
+            Some(rest) if rest.starts_with('/') => rest.trim_start_matches('/'),
+            _ => return,
+        };
+
+        self.source = relative.into();
+    }
 }
 
-impl Display for FunctionName {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.domain == "main" {
-            write!(f, "main chunk of {} ({}", self.source, self.source)?;
+impl FunctionName {
+    // Lua reports no source for C functions, so the ones from the standard libraries cannot be told
+    // apart from the ones registered by the host.
+    pub fn is_builtin(&self) -> bool {
+        self.domain == "C" || self.source_type == SourceType::C
+    }
 
-            if let Some(line) = self.line {
-                write!(f, ":{}", line)?;
-            }
+    fn fmt_source(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source_type {
+            SourceType::String => write!(f, "string \"{}\"", self.source),
+            SourceType::Bytecode => write!(f, "stripped bytecode"),
+            _ => write!(f, "{}", self.source),
+        }
+    }
+}
 
+impl Display for FunctionName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let ("key", Some(name)) = (self.domain.as_str(), &self.name) {
+            // the key the calls were attributed to by `Config::key_fn`
+            write!(f, "{}", name)
+        } else if self.domain == "main" {
+            // main chunks are defined on line 0, which is not worth showing
+            write!(f, "main chunk of ")?;
+            self.fmt_source(f)?;
+            write!(f, " (")?;
+            self.fmt_source(f)?;
             write!(f, ")")
         } else {
             if self.name.is_none() {
@@ -106,7 +315,8 @@ impl Display for FunctionName {
                 write!(f, "function ")?;
             }
 
-            write!(f, "({}", self.source)?;
+            write!(f, "(")?;
+            self.fmt_source(f)?;
 
             if let Some(line) = self.line {
                 write!(f, ":{}", line)?;
@@ -117,13 +327,102 @@ impl Display for FunctionName {
     }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-struct ProfileEntry {
+// the events Lua 5.3 calls the metamethods for, and `__close`, which Lua 5.4 adds
+const METAMETHODS: &[&str] = &[
+    "__index",
+    "__newindex",
+    "__gc",
+    "__mode",
+    "__len",
+    "__eq",
+    "__add",
+    "__sub",
+    "__mul",
+    "__mod",
+    "__pow",
+    "__div",
+    "__idiv",
+    "__band",
+    "__bor",
+    "__bxor",
+    "__shl",
+    "__shr",
+    "__unm",
+    "__bnot",
+    "__lt",
+    "__le",
+    "__concat",
+    "__call",
+    "__close",
+];
+
+// Adds the underscores the debug API may leave out of the name of a metamethod.
+fn metamethod_name(name: &str) -> String {
+    if name.starts_with("__") {
+        name.to_owned()
+    } else {
+        format!("__{}", name)
+    }
+}
+
+// The calls of the functions invoked as a kind of metamethod, with the `metamethods` option.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct MetamethodStats {
+    calls: usize,
+    total_time: Duration,
+    total_self_time: Duration,
+    // the calls of the kind on the stack; only the outermost one adds to the total time
+    depth: usize,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProfileEntry {
     calls: usize,
     total_time: Duration,
     total_self_time: Duration,
+    // the time at least one of the function's frames was on the stack, whatever the recursion
+    // policy: since the frames nest, it's the sum of the times of the outermost ones
+    cumulative_time: Duration,
     name: Option<FunctionName>,
+    // the number of the function's frames on the stack, whoever called them. With the default
+    // recursion policy, only the frame closed when it drops to 0 adds to the total time, which is
+    // what keeps mutual recursion (f -> g -> f) from being counted twice
     recursion_depth: usize,
+    // the highest `recursion_depth` reached
+    max_recursion: usize,
+    // the number of samples taken while the function was on the stack
+    samples: usize,
+    // the number of samples taken while the function was at the top of the stack
+    self_samples: usize,
+    // the number of those samples taken on each line of the function
+    line_samples: BTreeMap<usize, usize>,
+    // the last sample the function was counted in, to avoid counting recursive calls twice
+    last_sample: usize,
+    // the number of VM instructions executed in the function's body
+    instructions: u64,
+    // the functions that called this one
+    callers: HashSet<FunctionKey>,
+    // the number of outermost calls timed, and the sums of their durations and of their squares
+    // (in nanoseconds), for the standard deviation
+    timed_calls: usize,
+    timed_time: Duration,
+    timed_squares: u128,
+    // the number of calls made at each recursion depth
+    depths: BTreeMap<usize, usize>,
+    // the allocations made in the function's body, if tracked
+    memory: MemoryStats,
+    // the size of the function's stripped bytecode, if measured
+    code_size: Option<usize>,
+    // the number of calls unwound by an error instead of returning
+    errors: usize,
+    // the number of calls the function made
+    child_calls: usize,
+    // the self-time of the calls that made calls before the first one and after the last one
+    // returned, if tracked
+    setup_time: Duration,
+    teardown_time: Duration,
+    // the number of calls made with each number of arguments, if tracked
+    arg_counts: BTreeMap<usize, usize>,
 }
 
 impl ProfileEntry {
@@ -132,361 +431,4926 @@ impl ProfileEntry {
             calls: 1,
             total_time: Duration::new(0, 0),
             total_self_time: Duration::new(0, 0),
+            cumulative_time: Duration::new(0, 0),
             name,
             recursion_depth: 1,
+            max_recursion: 1,
+            samples: 0,
+            self_samples: 0,
+            line_samples: BTreeMap::new(),
+            last_sample: 0,
+            instructions: 0,
+            callers: HashSet::new(),
+            timed_calls: 0,
+            timed_time: Duration::new(0, 0),
+            timed_squares: 0,
+            depths: BTreeMap::new(),
+            memory: MemoryStats::default(),
+            code_size: None,
+            errors: 0,
+            child_calls: 0,
+            setup_time: Duration::new(0, 0),
+            teardown_time: Duration::new(0, 0),
+            arg_counts: BTreeMap::new(),
+        }
+    }
+
+    // Returns the population standard deviation of the durations of the outermost calls.
+    fn std_dev(&self) -> Option<f64> {
+        if self.timed_calls == 0 {
+            return None;
         }
+
+        let n = self.timed_calls as f64;
+        let mean = self.timed_time.as_secs_f64() / n;
+        let squares = self.timed_squares as f64 / 1e18 / n;
+
+        // rounding errors may make the variance slightly negative
+        Some((squares - mean * mean).max(0.0).sqrt())
     }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 struct CallFrame {
     entry: Instant,
     inner_start: Instant,
     level: usize,
     key: FunctionKey,
     suspended: bool,
+    // the self-time of this call
+    self_time: Duration,
+    // the self-time before the first call this call made, once it makes one
+    setup: Option<Duration>,
+    // the index of the call's node in `ProfilingResult::tree`, and of its caller's
+    node: usize,
+    parent: usize,
+    // the kind of metamethod the function was invoked as, if it's tracked
+    metamethod: Option<&'static str>,
 }
 
 impl CallFrame {
-    fn new(level: usize, key: FunctionKey) -> Self {
+    fn new(level: usize, key: FunctionKey, node: usize, parent: usize, now: Instant) -> Self {
+        Self {
+            entry: now,
+            inner_start: now,
+            level,
+            key,
+            suspended: false,
+            self_time: Duration::new(0, 0),
+            setup: None,
+            node,
+            parent,
+            metamethod: None,
+        }
+    }
+
+    // Creates a frame without reading the clock. The frame's times are meaningless.
+    fn untimed(level: usize, key: FunctionKey, node: usize, parent: usize, epoch: Instant) -> Self {
         Self {
-            entry: Instant::now(),
-            inner_start: Instant::now(),
+            entry: epoch,
+            inner_start: epoch,
             level,
             key,
             suspended: false,
+            self_time: Duration::new(0, 0),
+            setup: None,
+            node,
+            parent,
+            metamethod: None,
         }
     }
 
-    fn close(&self, result: &mut ProfilingResult) {
-        debug_assert!(!self.suspended, "attempted to close a suspended call frame");
+    fn close(&mut self, result: &mut ProfilingResult, now: Instant) {
+        let timed = !result.is_sampled();
+
+        // a suspended frame has already accounted for its self-time
+        let last_segment = if timed && !self.suspended {
+            let elapsed = result.elapsed(self.inner_start, now);
+            result.saturated |= self.self_time.add_saturating(elapsed);
+            result.add_self_time(self.key, elapsed);
+
+            elapsed
+        } else {
+            Duration::new(0, 0)
+        };
+
+        // the calls that made no calls have a single segment, which is neither
+        if let (true, Some(setup)) = (result.tracks_segments, self.setup) {
+            if let Some(entry) = result.data.get_mut(&self.key) {
+                result.saturated |= entry.setup_time.add_saturating(setup)
+                    | entry.teardown_time.add_saturating(last_segment);
+            }
+        }
+
+        // below the noise floor, the self-time is mostly the overhead of timing the call, so it's
+        // charged to the caller instead; the root has no caller to charge it to
+        let caller = result.tree[self.parent].key;
+        let folded = match (result.noise_floor, caller) {
+            (Some(floor), Some(caller)) if timed && self.self_time < floor => {
+                result.fold_self_time(self.key, caller, self.self_time);
+
+                true
+            }
+            _ => false,
+        };
+
+        if timed {
+            let node = &mut result.tree[if folded { self.parent } else { self.node }];
+            result.saturated |= node.self_time.add_saturating(self.self_time);
+        }
+
+        let total_time = if timed && !result.self_only {
+            Some(result.elapsed(self.entry, now))
+        } else {
+            None
+        };
+
+        if let Some(elapsed) = total_time {
+            result.saturated |= result.tree[self.node].total_time.add_saturating(elapsed);
+        }
+
+        let all = result.recursion == Recursion::All;
+
+        // there are no entries when streaming
+        if let Some(entry) = result.data.get_mut(&self.key) {
+            // the keys folded into `<other>` share the depth, and a desynchronized stack may close
+            // more frames than it opened
+            if entry.recursion_depth == 0 {
+                let warning = "a function returned more times than it was called; its total time \
+                               may be inaccurate";
+
+                if !result.warnings.iter().any(|v| v == warning) {
+                    result.warnings.push(warning.to_owned());
+                }
+            }
+
+            entry.recursion_depth = entry.recursion_depth.saturating_sub(1);
+
+            if let Some(elapsed) = total_time {
+                if entry.recursion_depth == 0 || all {
+                    result.saturated |= entry.total_time.add_saturating(elapsed);
+                }
+
+                if entry.recursion_depth == 0 {
+                    let nanos = elapsed.as_nanos();
 
-        let entry = result.data.get_mut(&self.key).unwrap();
-        entry.total_self_time += self.inner_start.elapsed();
+                    result.saturated |= entry.cumulative_time.add_saturating(elapsed)
+                        | entry.timed_calls.add_saturating(1)
+                        | entry.timed_time.add_saturating(elapsed)
+                        | entry
+                            .timed_squares
+                            .add_saturating(nanos.saturating_mul(nanos));
+                }
+            }
+        }
+
+        let metamethods = result.metamethods.as_mut();
+
+        if let Some(stats) = self.metamethod.and_then(|kind| metamethods?.get_mut(kind)) {
+            stats.depth = stats.depth.saturating_sub(1);
 
-        entry.recursion_depth -= 1;
+            if timed {
+                result.saturated |= stats.total_self_time.add_saturating(self.self_time);
+            }
 
-        if entry.recursion_depth == 0 {
-            entry.total_time += self.entry.elapsed();
+            if let (Some(elapsed), 0) = (total_time, stats.depth) {
+                result.saturated |= stats.total_time.add_saturating(elapsed);
+            }
         }
     }
 
-    fn suspend(&mut self, result: &mut ProfilingResult) {
-        debug_assert!(!self.suspended, "the call frame is already suspended");
+    fn suspend(&mut self, result: &mut ProfilingResult, now: Instant) {
+        if self.suspended {
+            return;
+        }
 
-        let entry = result.data.get_mut(&self.key).unwrap();
-        entry.total_self_time += self.inner_start.elapsed();
+        let elapsed = result.elapsed(self.inner_start, now);
+        result.saturated |= self.self_time.add_saturating(elapsed);
+        result.add_self_time(self.key, elapsed);
         self.suspended = true;
+        self.setup.get_or_insert(self.self_time);
     }
 
-    fn resume(&mut self) {
+    fn resume(&mut self, now: Instant) {
         if !self.suspended {
             return;
         }
 
-        self.inner_start = Instant::now();
+        self.inner_start = now;
         self.suspended = false;
     }
 }
 
+// A node of the call tree: a function called through a particular chain of calls.
 #[derive(Clone, Debug, Eq, PartialEq)]
-struct ProfilingResult {
-    data: HashMap<FunctionKey, ProfileEntry>,
-    total_time: Option<Duration>,
+struct CallNode {
+    // none for the root node, which stands for the caller of the profiled function
+    key: Option<FunctionKey>,
+    calls: usize,
+    total_time: Duration,
+    self_time: Duration,
+    children: HashMap<FunctionKey, usize>,
 }
 
-impl ProfilingResult {
-    fn new() -> Self {
+impl CallNode {
+    fn new(key: Option<FunctionKey>) -> Self {
         Self {
-            data: HashMap::new(),
-            total_time: None,
+            key,
+            calls: 0,
+            total_time: Duration::new(0, 0),
+            self_time: Duration::new(0, 0),
+            children: HashMap::new(),
         }
     }
+}
 
-    fn move_to_lua(self, state: &mut State) -> i32 {
-        let len = self.data.len() as i32;
-        state.create_table(len, 1);
+// The time an export weighs the functions and the calls by.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Cost {
+    // the self-time, conventional for flame graphs
+    Exclusive,
+    // the total time, conventional for call graphs
+    Inclusive,
+}
 
-        for (i, v) in self.data.values().enumerate() {
-            state.create_table(0, 4);
+impl Cost {
+    // Reads the optional cost argument at `arg`: "self" or "total".
+    fn check(state: &mut State, arg: i32, default: Self) -> Self {
+        if state.is_none_or_nil(arg) {
+            return default;
+        }
 
-            state.push("name");
-            state.push(v.name.as_ref().map_or_else(String::new, |v| v.to_string()));
+        match check_string_lossy(state, arg).as_str() {
+            "self" => Cost::Exclusive,
+            "total" => Cost::Inclusive,
+            _ => {
+                state.arg_error(arg, "cost must be one of: self, total");
+                unreachable!()
+            }
+        }
+    }
+}
+
+// The settings of a result that the entry tables depend on, copied out of it so that an entry can
+// be pushed without keeping the result borrowed.
+#[derive(Clone, Copy, Debug)]
+struct EntryFormat {
+    calls_only: bool,
+    self_only: bool,
+    sampled: bool,
+    counts_instructions: bool,
+    tracks_memory: bool,
+    tracks_live_bytes: bool,
+    tracks_segments: bool,
+    tracks_arg_counts: bool,
+    precision: Option<u32>,
+}
+
+impl EntryFormat {
+    // Rounds a number to the precision of the result table.
+    fn round(self, v: f64) -> f64 {
+        match self.precision {
+            Some(precision) => {
+                let factor = 10f64.powi(precision as i32);
+                (v * factor).round() / factor
+            }
+            None => v,
+        }
+    }
+
+    fn seconds(self, time: Duration) -> f64 {
+        self.round(time.as_secs_f64())
+    }
+
+    // Pushes the entry table of the function with the key `k` and the name `name`.
+    fn push(self, state: &mut State, k: FunctionKey, name: String, v: &ProfileEntry) {
+        state.create_table(0, 8);
+
+        state.push("id");
+        state.push(k.id());
+        state.set_table(-3);
+
+        state.push("name");
+        state.push(name);
+        state.set_table(-3);
+
+        if let Some(ref name) = v.name {
+            state.push("source");
+            state.push(&*name.source);
             state.set_table(-3);
 
-            state.push("calls");
-            state.push(v.calls as i64);
+            state.push("rawSource");
+            state.push(&*name.raw_source);
             state.set_table(-3);
 
-            state.push("totalTime");
-            state.push(v.total_time.as_secs_f64());
+            state.push("sourceType");
+            state.push(name.source_type.as_str());
             state.set_table(-3);
 
-            state.push("totalSelfTime");
-            state.push(v.total_self_time.as_secs_f64());
+            state.push("isBuiltin");
+            state.push(name.is_builtin());
             state.set_table(-3);
 
-            state.seti(-2, (i + 1) as i64);
+            state.push("domain");
+            state.push(name.domain.as_str());
+            state.set_table(-3);
+
+            if let Some(line) = name.line {
+                state.push("line");
+                state.push(line as i64);
+                state.set_table(-3);
+            }
+
+            if let Some(ref upvalues) = name.upvalues {
+                state.push("upvalues");
+                state.create_table(upvalues.len() as i32, 0);
+
+                for (i, upvalue) in upvalues.iter().enumerate() {
+                    state.push(upvalue.as_str());
+                    state.seti(-2, (i + 1) as i64);
+                }
+
+                state.set_table(-3);
+            }
         }
 
-        state.push("totalTime");
-        state.push(self.total_time.map(|v| v.as_secs_f64()));
+        state.push("calls");
+        state.push(clamped(v.calls));
         state.set_table(-3);
 
-        1
-    }
-}
+        // nothing else is tracked
+        if self.calls_only {
+            return;
+        }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-struct Profiler {
-    result: Option<ProfilingResult>,
-    stack: Vec<CallFrame>,
-}
+        state.push("callSites");
+        state.push(clamped(v.callers.len()));
+        state.set_table(-3);
 
-impl Profiler {
-    const TYPE_NAME: &'static str = "Profiler";
-    const OPAQUE_REGISTRY_KEY: *const i32 = &0 as *const i32;
+        state.push("childCalls");
+        state.push(clamped(v.child_calls));
+        state.set_table(-3);
 
-    fn new(state: &mut State) -> i32 {
-        static METATABLE: Once = Once::new();
+        state.push("errors");
+        state.push(clamped(v.errors));
+        state.set_table(-3);
 
-        METATABLE.call_once(|| {
-            state.new_metatable(Self::TYPE_NAME);
-            state.set_fns(
-                &[
-                    ("__call", lua_func!(Self::call)),
-                    ("__gc", lua_func!(Self::gc)),
-                ],
-                0,
-            );
-        });
+        if !self.self_only {
+            state.push("totalTime");
+            state.push(self.seconds(v.total_time));
+            state.set_table(-3);
 
-        // Safety: guaranteed by Lua.
-        unsafe {
-            *state.new_userdata_typed() = ManuallyDrop::new(Profiler {
-                result: None,
-                stack: Vec::new(),
-            });
+            state.push("cumulativeTime");
+            state.push(self.seconds(v.cumulative_time));
+            state.set_table(-3);
         }
 
-        state.set_metatable_from_registry(Self::TYPE_NAME);
+        state.push("totalSelfTime");
+        state.push(self.seconds(v.total_self_time));
+        state.set_table(-3);
 
-        1
-    }
+        state.push("maxRecursion");
+        state.push(clamped(v.max_recursion));
+        state.set_table(-3);
 
-    fn call(state: &mut State) -> i32 {
-        // check but don't use, since we need state later
-        state.set_top(2);
-        state.check_userdata(1, Self::TYPE_NAME);
-        state.check_type(2, lua::Type::Function);
+        state.push("depthHistogram");
+        state.create_table(v.depths.len() as i32, 0);
 
-        if Self::get_from_registry(state) {
-            state.push("attempt to run multiple profiling sessions simulatenously");
-            state.error();
+        for (&depth, &count) in &v.depths {
+            state.push(clamped(count));
+            state.seti(-2, depth as i64);
         }
 
-        // Safety: checked above; set_hook does not modify the stack.
-        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
-        this.result.replace(ProfilingResult::new());
+        state.set_table(-3);
 
-        // Stack:
-        // BEFORE      AFTER
-        // 1    2      1 2
-        // Self f      f Self
-        state.rotate(1, 1);
-        state.raw_setp(lua::REGISTRYINDEX, Self::OPAQUE_REGISTRY_KEY);
+        if let Some(std_dev) = v.std_dev() {
+            state.push("stdDev");
+            state.push(self.round(std_dev));
+            state.set_table(-3);
+        }
 
-        let prev_hook = Self::set_hook(state);
+        if self.sampled {
+            if !self.self_only {
+                state.push("samples");
+                state.push(clamped(v.samples));
+                state.set_table(-3);
+            }
 
-        let start = Instant::now();
-        let status = state.pcall(0, 0, 0);
-        let total_time = start.elapsed();
+            state.push("selfSamples");
+            state.push(clamped(v.self_samples));
+            state.set_table(-3);
 
-        Self::unset_hook(state, prev_hook);
+            state.push("lineSamples");
+            state.create_table(0, v.line_samples.len() as i32);
 
-        if status.is_err() {
-            // propagate the error
-            state.error();
+            for (&line, &count) in &v.line_samples {
+                state.push(clamped(count));
+                state.seti(-2, line as i64);
+            }
+
+            state.set_table(-3);
         }
 
-        Self::get_from_registry(state);
+        if self.counts_instructions {
+            state.push("instructions");
+            state.push(clamped(v.instructions));
+            state.set_table(-3);
+        }
 
-        // Safety: the registry is not modified during profiling
-        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
-        let mut result = this.result.take().unwrap();
-        result.total_time = Some(total_time);
-        result.move_to_lua(state)
-    }
+        if self.tracks_memory {
+            let fields = [
+                ("allocations", v.memory.allocations),
+                ("reallocations", v.memory.reallocations),
+                ("frees", v.memory.frees),
+                ("bytesAllocated", v.memory.bytes_allocated),
+                ("bytesFreed", v.memory.bytes_freed),
+            ];
 
-    fn get_from_registry(state: &mut State) -> bool {
-        let result = match state.raw_getp(lua::REGISTRYINDEX, Self::OPAQUE_REGISTRY_KEY) {
-            lua::Type::Userdata => !state.test_userdata(-1, Self::TYPE_NAME).is_null(),
-            _ => false,
-        };
+            for &(field, value) in &fields {
+                state.push(clamped(value));
+                state.set_field(-2, field);
+            }
 
-        if !result {
-            state.pop(1);
+            if self.tracks_live_bytes {
+                state.push(clamped(v.memory.live_bytes));
+                state.set_field(-2, "liveBytes");
+            }
         }
 
-        result
-    }
+        if self.tracks_segments {
+            state.push("setupTime");
+            state.push(self.seconds(v.setup_time));
+            state.set_table(-3);
 
-    fn gc(state: &mut State) -> i32 {
-        // Safety: guaranteed by Lua unless violated with debug.getmetatable, which is irrelevant.
-        unsafe {
-            let this: &mut ManuallyDrop<Self> = state.check_userdata_typed(1, Self::TYPE_NAME);
-            ManuallyDrop::drop(this);
+            state.push("teardownTime");
+            state.push(self.seconds(v.teardown_time));
+            state.set_table(-3);
         }
 
-        state.pop(1);
+        if self.tracks_arg_counts {
+            state.push("argCounts");
+            state.create_table(0, v.arg_counts.len() as i32);
 
-        0
-    }
+            for (&count, &calls) in &v.arg_counts {
+                state.push(clamped(calls));
+                state.seti(-2, count as i64);
+            }
 
-    fn set_hook(state: &mut State) -> (Hook, HookMask, c_int) {
-        let prev = (
+            state.set_table(-3);
+        }
+
+        if let Some(code_size) = v.code_size {
+            state.push("codeSize");
+            state.push(clamped(code_size));
+            state.set_table(-3);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProfilingResult {
+    data: HashMap<FunctionKey, ProfileEntry>,
+    // the profiled function
+    root: Option<FunctionKey>,
+    total_time: Option<Duration>,
+    // the total number of samples taken, if the times are sampled
+    samples: Option<usize>,
+    counts_instructions: bool,
+    tracks_memory: bool,
+    // whether the memory still allocated by each function at the end is measured
+    tracks_live_bytes: bool,
+    // the allocations made during the session, if tracked
+    memory: MemoryStats,
+    // whether only the self-time is tracked, leaving the total times at zero
+    self_only: bool,
+    // whether only the calls are counted, leaving all of the times at zero
+    calls_only: bool,
+    // whether the self-time before the first call and after the last one is tracked
+    tracks_segments: bool,
+    // whether the number of arguments of each call is counted
+    tracks_arg_counts: bool,
+    // the name of the clock the calls were timed with, and its resolution
+    clock: &'static str,
+    clock_resolution: Duration,
+    // the times a reading of the clock was earlier than the one it was measured from
+    clock_anomalies: usize,
+    // the number of decimal places the times in the result table are rounded to
+    precision: Option<u32>,
+    // the recent data, if the window is set
+    window: Option<Window>,
+    // the distribution of the session times, which differ if the result was merged
+    latencies: Latencies,
+    recursion: Recursion,
+    max_functions: Option<usize>,
+    // the entries with fewer calls are folded into the `<filtered>` entry of the result table
+    min_calls: Option<usize>,
+    // the self-time below which the calls are charged to their callers, and whether the entries
+    // left without self-time are folded into the `<insignificant>` entry of the result table
+    noise_floor: Option<Duration>,
+    drop_insignificant: bool,
+    threshold: Option<Threshold>,
+    warnings: Vec<String>,
+    // the self-time of the code run under each label
+    labels: HashMap<String, Duration>,
+    // the labels pushed by the user; the last one is active
+    label_stack: Vec<String>,
+    // the distinct sources of the functions, shared by their names
+    sources: HashSet<Rc<str>>,
+    // the entries of the keys returned by `Config::key_fn`
+    custom_keys: HashMap<String, FunctionKey>,
+    // whether the source of each function seen matches `Config::sources`, if set
+    source_matches: HashMap<FunctionKey, bool>,
+    // the keys the C functions are merged into by name, if `Config::merge_c_functions` is set
+    c_keys: HashMap<FunctionKey, FunctionKey>,
+    // the markers set by the user, as offsets from the session start, in the order they were set
+    markers: Vec<(String, Duration)>,
+    // the call tree; the first node is the root, and children always follow their parents
+    tree: Vec<CallNode>,
+    // the number of calls made from each line of the caller, if enabled
+    call_lines: HashMap<(FunctionKey, FunctionKey), BTreeMap<usize, usize>>,
+    // the number of hook events received, to judge how much the profiler perturbed the run
+    call_events: usize,
+    return_events: usize,
+    tail_call_events: usize,
+    // whether a counter or a time reached its maximum and stopped growing
+    saturated: bool,
+    // the hook events in the order they were received, if `Config::record` is set
+    events: Option<Vec<Event>>,
+    // the highest number of profiled frames on the stack at once
+    max_stack_depth: usize,
+    // whether the session was aborted by the deadline
+    deadline_exceeded: bool,
+    // whether the session was aborted by the instruction limit
+    instruction_limit_exceeded: bool,
+    // whether the hook failed and profiling stopped early
+    abandoned: bool,
+    // whether the profiled code threw an error
+    errored: bool,
+    // the time the hook took on one in `Profiler::OVERHEAD_SAMPLE_INTERVAL` events
+    hook_time: Duration,
+    // the calls made with `lprofile.ignore` and the time they took
+    ignored_calls: usize,
+    ignored_time: Duration,
+    // where the time the hook took was charged to, and, unless it's the frames, that time and the
+    // number of events it took it on
+    hook_attribution: HookTime,
+    hook_body_time: Duration,
+    hook_body_events: usize,
+    // the metadata the session was started with
+    metadata: BTreeMap<String, MetaValue>,
+    // the calls of the metamethods by their kind, if they're tracked
+    metamethods: Option<BTreeMap<&'static str, MetamethodStats>>,
+    // the samples whose stacks were deeper than `Config::max_stack_depth`, and their time
+    truncated_samples: usize,
+    truncated_time: Duration,
+}
+
+impl ProfilingResult {
+    fn new(config: &Config) -> Self {
+        let clock = SessionClock::new(config.clock);
+
+        Self {
+            data: HashMap::new(),
+            root: None,
+            total_time: None,
+            samples: config.sample_interval.map(|_| 0),
+            counts_instructions: config.count_instructions,
+            tracks_memory: config.memory,
+            tracks_live_bytes: config.live_bytes,
+            memory: MemoryStats::default(),
+            self_only: config.self_only,
+            calls_only: config.calls_only,
+            tracks_segments: config.segments,
+            tracks_arg_counts: config.arg_counts,
+            clock: clock.name(),
+            clock_resolution: clock.resolution(),
+            clock_anomalies: 0,
+            precision: config.precision,
+            window: config.window.map(Window::new),
+            latencies: Latencies::default(),
+            recursion: config.recursion,
+            max_functions: config.max_functions,
+            min_calls: config.min_calls,
+            noise_floor: config.noise_floor,
+            drop_insignificant: config.drop_insignificant,
+            threshold: config.threshold,
+            warnings: Vec::new(),
+            labels: HashMap::new(),
+            label_stack: Vec::new(),
+            sources: HashSet::new(),
+            custom_keys: HashMap::new(),
+            source_matches: HashMap::new(),
+            c_keys: HashMap::new(),
+            markers: Vec::new(),
+            tree: vec![CallNode::new(None)],
+            call_lines: HashMap::new(),
+            call_events: 0,
+            return_events: 0,
+            tail_call_events: 0,
+            saturated: false,
+            events: if config.record {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            max_stack_depth: 0,
+            deadline_exceeded: false,
+            instruction_limit_exceeded: false,
+            abandoned: false,
+            errored: false,
+            hook_time: Duration::new(0, 0),
+            ignored_calls: 0,
+            ignored_time: Duration::new(0, 0),
+            hook_attribution: config.hook_time,
+            hook_body_time: Duration::new(0, 0),
+            hook_body_events: 0,
+            metadata: config.metadata.clone(),
+            metamethods: if config.metamethods {
+                Some(BTreeMap::new())
+            } else {
+                None
+            },
+            truncated_samples: 0,
+            truncated_time: Duration::new(0, 0),
+        }
+    }
+
+    // Resets the result for a new session, like `new` does, but keeps the memory the collections
+    // have grown, so that the sessions run back to back don't allocate it again.
+    fn recycle(&mut self, config: &Config) {
+        let mut old = std::mem::replace(self, Self::new(config));
+
+        old.data.clear();
+        old.warnings.clear();
+        old.labels.clear();
+        old.label_stack.clear();
+        old.sources.clear();
+        old.custom_keys.clear();
+        old.source_matches.clear();
+        old.c_keys.clear();
+        old.markers.clear();
+        old.call_lines.clear();
+        old.tree.truncate(1);
+        old.tree[0] = CallNode::new(None);
+
+        self.data = old.data;
+        self.warnings = old.warnings;
+        self.labels = old.labels;
+        self.label_stack = old.label_stack;
+        self.sources = old.sources;
+        self.custom_keys = old.custom_keys;
+        self.source_matches = old.source_matches;
+        self.c_keys = old.c_keys;
+        self.markers = old.markers;
+        self.call_lines = old.call_lines;
+        self.tree = old.tree;
+
+        if let (Some(events), Some(mut old)) = (self.events.as_mut(), old.events) {
+            old.clear();
+            *events = old;
+        }
+    }
+
+    // Returns the node of the function called from the `parent` node, creating it if necessary.
+    fn tree_child(&mut self, parent: usize, key: FunctionKey) -> usize {
+        if let Some(&node) = self.tree[parent].children.get(&key) {
+            return node;
+        }
+
+        let node = self.tree.len();
+        self.tree.push(CallNode::new(Some(key)));
+        self.tree[parent].children.insert(key, node);
+
+        node
+    }
+
+    // Sums up the calls, the total times, and the self-times of the call tree nodes by caller and
+    // callee.
+    fn edges(&self) -> HashMap<(FunctionKey, FunctionKey), (usize, Duration, Duration)> {
+        let mut edges = HashMap::<_, (usize, Duration, Duration)>::new();
+
+        for node in &self.tree {
+            let caller = match node.key {
+                Some(key) => key,
+                None => continue,
+            };
+
+            for (&callee, &child) in &node.children {
+                let edge = edges.entry((caller, callee)).or_default();
+                edge.0 = edge.0.saturating_add(self.tree[child].calls);
+                edge.1 = edge.1.saturating_add(self.tree[child].total_time);
+                edge.2 = edge.2.saturating_add(self.tree[child].self_time);
+            }
+        }
+
+        edges
+    }
+
+    // Finds the path from the root down to a leaf with the largest sum of the total times of its
+    // nodes. Returns the nodes of the path, excluding the root.
+    fn critical_path(&self) -> Vec<usize> {
+        // children follow their parents, so iterating backwards visits the children first
+        let mut best = vec![(Duration::new(0, 0), None); self.tree.len()];
+
+        for (i, node) in self.tree.iter().enumerate().rev() {
+            let next = node
+                .children
+                .values()
+                .copied()
+                .max_by_key(|&child| best[child].0);
+
+            best[i] = (
+                node.total_time
+                    .saturating_add(next.map_or(Duration::new(0, 0), |v| best[v].0)),
+                next,
+            );
+        }
+
+        let mut path = Vec::new();
+        let mut node = best[0].1;
+
+        while let Some(next) = node {
+            path.push(next);
+            node = best[next].1;
+        }
+
+        path
+    }
+
+    // The time between two readings of the clock. The counter isn't necessarily in sync on all of
+    // the cores, so a reading can be earlier than the one before it: the time is zero then, and the
+    // anomaly is counted.
+    fn elapsed(&mut self, start: Instant, now: Instant) -> Duration {
+        if now < start {
+            self.saturated |= self.clock_anomalies.add_saturating(1);
+        }
+
+        now.saturating_duration_since(start)
+    }
+
+    // Moves the self-time of a call below the noise floor from its function to its caller. The
+    // labels and the recent data keep it where it was measured.
+    fn fold_self_time(&mut self, from: FunctionKey, to: FunctionKey, time: Duration) {
+        if let Some(entry) = self.data.get_mut(&from) {
+            entry.total_self_time = entry.total_self_time.saturating_sub(time);
+        }
+
+        if let Some(entry) = self.data.get_mut(&to) {
+            self.saturated |= entry.total_self_time.add_saturating(time);
+        }
+    }
+
+    fn add_self_time(&mut self, key: FunctionKey, time: Duration) {
+        if let Some(entry) = self.data.get_mut(&key) {
+            self.saturated |= entry.total_self_time.add_saturating(time);
+        }
+
+        if let Some(window) = self.window.as_mut() {
+            self.saturated |= window.activity(key).self_time.add_saturating(time);
+        }
+
+        if let Some(label) = self.label_stack.last() {
+            self.saturated |= self.labels.get_mut(label).unwrap().add_saturating(time);
+        }
+    }
+
+    // Returns the key to record the function under: once the limit is reached, new functions are
+    // folded into the `FunctionKey::OTHER` entry.
+    fn tracked_key(&self, key: FunctionKey) -> FunctionKey {
+        let max = match self.max_functions {
+            Some(max) => max,
+            None => return key,
+        };
+
+        let tracked = self.data.len() - self.data.contains_key(&FunctionKey::OTHER) as usize;
+
+        if tracked >= max && !self.data.contains_key(&key) {
+            FunctionKey::OTHER
+        } else {
+            key
+        }
+    }
+
+    // Returns the key of the entry the calls attributed to `key` by the key function are
+    // accumulated in. No function is allocated at the top of the address space, so the keys
+    // count down from there.
+    fn custom_key(&mut self, key: &str) -> FunctionKey {
+        if let Some(&key) = self.custom_keys.get(key) {
+            return key;
+        }
+
+        let result = FunctionKey(usize::MAX - self.custom_keys.len());
+        self.custom_keys.insert(key.to_owned(), result);
+
+        result
+    }
+
+    // Returns the key the calls of the C function at `key` are merged into: the one of the first
+    // C function seen with the same name. The keys are allocated like the custom keys, under
+    // names the key function can't return by accident. Lua functions and anonymous C functions
+    // keep their key.
+    //
+    // Safety: `l` must be a valid Lua state running a hook, and `ar` its activation record.
+    unsafe fn merged_c_key(
+        &mut self,
+        l: *mut ffi::lua_State,
+        ar: &mut lua_Debug,
+        key: FunctionKey,
+    ) -> FunctionKey {
+        if let Some(&merged) = self.c_keys.get(&key) {
+            return merged;
+        }
+
+        let merged = match Profiler::determine_name_for(l, ar) {
+            Some(FunctionName {
+                name: Some(name),
+                ref domain,
+                ..
+            }) if domain == "C" => self.custom_key(&format!("\0C {}", name)),
+            _ => key,
+        };
+
+        self.c_keys.insert(key, merged);
+
+        merged
+    }
+
+    fn push_label(&mut self, label: String) {
+        self.labels.entry(label.clone()).or_default();
+        self.label_stack.push(label);
+    }
+
+    fn is_sampled(&self) -> bool {
+        self.samples.is_some()
+    }
+
+    // Describes the entries whose numbers contradict each other, which means the profiler
+    // miscounted them. A function's self-time is part of its total time, so it can't be longer.
+    // The total times aren't tracked with `self_only`, and saturated counters are reported as such.
+    fn consistency_warnings(&self) -> Vec<String> {
+        if self.self_only || self.saturated {
+            return Vec::new();
+        }
+
+        let mut keys = self
+            .data
+            .iter()
+            .filter(|(_, v)| v.total_self_time > v.total_time)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|key| key.0);
+
+        keys.into_iter()
+            .map(|key| {
+                let v = &self.data[&key];
+
+                format!(
+                    "the self-time of {} ({:.6} s) exceeds its total time ({:.6} s); the profiler \
+                     miscounted it",
+                    self.name_of(key),
+                    v.total_self_time.as_secs_f64(),
+                    v.total_time.as_secs_f64(),
+                )
+            })
+            .collect()
+    }
+
+    // Estimates the share of the session time the hook took, from the events it was measured on.
+    // The dispatch of the events by Lua isn't measured, so the actual share is a bit higher.
+    fn overhead(&self) -> Option<f64> {
+        let total_time = self.total_time.filter(|v| *v > Duration::new(0, 0))?;
+        let hook_time = self.hook_time.as_secs_f64() * Profiler::OVERHEAD_SAMPLE_INTERVAL as f64;
+
+        Some((hook_time / total_time.as_secs_f64()).min(1.0))
+    }
+
+    // Pushes the table describing how far the numbers of the result can be trusted.
+    // `saturated` also covers the sums made while building the table.
+    fn push_reliability(&self, state: &mut State, saturated: bool) {
+        let capped = self.data.contains_key(&FunctionKey::OTHER);
+        let approximate = self.is_sampled()
+            || capped
+            || self.deadline_exceeded
+            || self.instruction_limit_exceeded
+            || saturated
+            || self.abandoned;
+
+        state.create_table(0, 10);
+
+        state.push("mode");
+        state.push(if self.is_sampled() {
+            "sampled"
+        } else if self.calls_only {
+            "calls"
+        } else {
+            "exact"
+        });
+        state.set_table(-3);
+
+        let flags = [
+            ("approximate", approximate),
+            ("capped", capped),
+            ("deadlineExceeded", self.deadline_exceeded),
+            ("instructionLimitExceeded", self.instruction_limit_exceeded),
+            ("stackTruncated", self.truncated_samples > 0),
+            ("saturated", saturated),
+            ("abandoned", self.abandoned),
+        ];
+
+        for &(field, value) in &flags {
+            state.push(value);
+            state.set_field(-2, field);
+        }
+
+        state.push("overhead");
+        state.push(self.overhead());
+        state.set_table(-3);
+
+        // the program would have run in the rest of the time without the hook
+        let perturbation = self
+            .overhead()
+            .filter(|&overhead| overhead < 1.0)
+            .map(|overhead| 1.0 / (1.0 - overhead));
+
+        state.push("perturbation");
+        state.push(perturbation);
+        state.set_table(-3);
+    }
+
+    // Adds up the self-times of the C functions, the Lua functions, and the main chunks, in this
+    // order. The custom keys and `<other>` belong to none of them.
+    fn domain_times(&self) -> [Duration; 3] {
+        let mut times = [Duration::new(0, 0); 3];
+
+        for v in self.data.values() {
+            let i = match v.name.as_ref().map(|name| name.domain.as_str()) {
+                Some("C") => 0,
+                Some("Lua") => 1,
+                Some("main") => 2,
+                _ => continue,
+            };
+
+            times[i] = times[i].saturating_add(v.total_self_time);
+        }
+
+        times
+    }
+
+    // Returns the part of the session time not spent in the profiled functions, which includes the
+    // time taken by the profiler itself. Without the self-times, it's unknown.
+    fn host_time(&self) -> Option<Duration> {
+        if self.calls_only {
+            return None;
+        }
+
+        let self_time = self
+            .data
+            .values()
+            .map(|v| v.total_self_time)
+            .sum::<Duration>();
+        let self_time = self_time
+            .saturating_add(self.ignored_time)
+            .saturating_add(self.hook_body_time);
+
+        self.total_time.map(|total| total.saturating_sub(self_time))
+    }
+
+    fn round(&self, v: f64) -> f64 {
+        self.entry_format().round(v)
+    }
+
+    // Converts a duration to seconds for the result table.
+    fn seconds(&self, time: Duration) -> f64 {
+        self.round(time.as_secs_f64())
+    }
+
+    fn entry_format(&self) -> EntryFormat {
+        EntryFormat {
+            calls_only: self.calls_only,
+            self_only: self.self_only,
+            sampled: self.is_sampled(),
+            counts_instructions: self.counts_instructions,
+            tracks_memory: self.tracks_memory,
+            tracks_live_bytes: self.tracks_live_bytes,
+            tracks_segments: self.tracks_segments,
+            tracks_arg_counts: self.tracks_arg_counts,
+            precision: self.precision,
+        }
+    }
+
+    fn name_of(&self, key: FunctionKey) -> String {
+        if key == FunctionKey::OTHER {
+            return "<other>".to_owned();
+        }
+
+        self.data
+            .get(&key)
+            .and_then(|v| v.name.as_ref())
+            .map_or_else(String::new, |v| v.to_string())
+    }
+
+    // Pushes the entry table of a function.
+    fn push_entry(&self, state: &mut State, k: FunctionKey, v: &ProfileEntry) {
+        self.entry_format().push(state, k, self.name_of(k), v);
+    }
+
+    // The entries in an order that doesn't change from run to run: by the source, the line, and
+    // the name of the function, with the unnamed ones last, and then by the key.
+    fn ordered_entries(&self) -> Vec<(FunctionKey, &ProfileEntry)> {
+        fn order(v: &ProfileEntry) -> (bool, Option<&str>, Option<usize>, Option<&str>) {
+            let name = v.name.as_ref();
+
+            (
+                name.is_none(),
+                name.map(|v| &*v.source),
+                name.and_then(|v| v.line),
+                name.and_then(|v| v.name.as_deref()),
+            )
+        }
+
+        let mut entries = self.data.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+        entries.sort_by(|(lhs_key, lhs), (rhs_key, rhs)| {
+            order(lhs).cmp(&order(rhs)).then(lhs_key.0.cmp(&rhs_key.0))
+        });
+
+        entries
+    }
+
+    // Pushes the result table. If `target` is given, the table at its index is filled instead,
+    // and keeps its metatable if it has one.
+    fn move_to_lua(&self, state: &mut State, target: Option<(i32, Fill)>) -> i32 {
+        let len = self.data.len() as i32;
+
+        // the entries are stored after the first `offset` integer keys
+        let offset = match target {
+            Some((idx, fill)) => {
+                state.push_value(idx);
+
+                match fill {
+                    Fill::Clear => {
+                        clear_table(state);
+
+                        0
+                    }
+                    Fill::Append => state.raw_len(-1) as i64,
+                }
+            }
+            None => {
+                state.create_table(len, 5);
+
+                0
+            }
+        };
+
+        // the indices of the entry tables, for the edges
+        let mut indices = HashMap::with_capacity(self.data.len());
+        let mut filtered = (0, 0, Duration::new(0, 0));
+        let mut insignificant = (0, 0);
+        // the sums of the entries left out may overflow too, which the result doesn't know about
+        let mut saturated = self.saturated;
+
+        for (k, v) in self.ordered_entries() {
+            // the root is always kept
+            if v.calls < self.min_calls.unwrap_or(0) && self.root != Some(k) {
+                filtered.0 += 1;
+                saturated |= filtered.1.add_saturating(v.calls)
+                    | filtered.2.add_saturating(v.total_self_time);
+
+                continue;
+            }
+
+            // their self-time is already their callers'
+            let folded = v.calls > 0 && v.total_self_time.is_zero();
+
+            if self.drop_insignificant && folded && self.root != Some(k) {
+                insignificant.0 += 1;
+                saturated |= insignificant.1.add_saturating(v.calls);
+
+                continue;
+            }
+
+            let index = offset + indices.len() as i64 + 1;
+            indices.insert(k, index);
+            self.push_entry(state, k, v);
+
+            if self.root == Some(k) {
+                state.push_value(-1);
+                state.set_field(-3, "root");
+            }
+
+            state.seti(-2, index);
+        }
+
+        // the index of the next entry after the functions
+        let mut next = offset + indices.len() as i64 + 1;
+
+        if let (Some(host_time), false) = (self.host_time(), self.data.is_empty()) {
+            state.create_table(0, 5);
+
+            state.push("id");
+            state.push("host");
+            state.set_table(-3);
+
+            state.push("name");
+            state.push("<host>");
+            state.set_table(-3);
+
+            state.push("calls");
+            state.push(0_i64);
+            state.set_table(-3);
+
+            state.push("totalTime");
+            state.push(self.seconds(host_time));
+            state.set_table(-3);
+
+            state.push("totalSelfTime");
+            state.push(self.seconds(host_time));
+            state.set_table(-3);
+
+            state.push_value(-1);
+            state.set_field(-3, "host");
+
+            state.seti(-2, next);
+            next += 1;
+        }
+
+        if self.ignored_calls > 0 {
+            state.create_table(0, 5);
+
+            state.push("id");
+            state.push("ignored");
+            state.set_table(-3);
+
+            state.push("name");
+            state.push("<ignored>");
+            state.set_table(-3);
+
+            state.push("calls");
+            state.push(clamped(self.ignored_calls));
+            state.set_table(-3);
+
+            state.push("totalTime");
+            state.push(self.seconds(self.ignored_time));
+            state.set_table(-3);
+
+            state.push("totalSelfTime");
+            state.push(self.seconds(self.ignored_time));
+            state.set_table(-3);
+
+            state.push_value(-1);
+            state.set_field(-3, "ignored");
+
+            state.seti(-2, next);
+            next += 1;
+        }
+
+        if self.truncated_samples > 0 {
+            state.create_table(0, 6);
+
+            state.push("id");
+            state.push("truncated");
+            state.set_table(-3);
+
+            state.push("name");
+            state.push("...");
+            state.set_table(-3);
+
+            state.push("calls");
+            state.push(0_i64);
+            state.set_table(-3);
+
+            state.push("samples");
+            state.push(clamped(self.truncated_samples));
+            state.set_table(-3);
+
+            state.push("totalTime");
+            state.push(self.seconds(self.truncated_time));
+            state.set_table(-3);
+
+            // the top frames have the self-time
+            state.push("totalSelfTime");
+            state.push(0.0);
+            state.set_table(-3);
+
+            state.push_value(-1);
+            state.set_field(-3, "truncated");
+
+            state.seti(-2, next);
+            next += 1;
+        }
+
+        if self.hook_attribution == HookTime::Overhead {
+            state.create_table(0, 5);
+
+            state.push("id");
+            state.push("hook");
+            state.set_table(-3);
+
+            state.push("name");
+            state.push("<hook>");
+            state.set_table(-3);
+
+            state.push("calls");
+            state.push(clamped(self.hook_body_events));
+            state.set_table(-3);
+
+            state.push("totalTime");
+            state.push(self.seconds(self.hook_body_time));
+            state.set_table(-3);
+
+            state.push("totalSelfTime");
+            state.push(self.seconds(self.hook_body_time));
+            state.set_table(-3);
+
+            state.push_value(-1);
+            state.set_field(-3, "hook");
+
+            state.seti(-2, next);
+            next += 1;
+        }
+
+        let (count, calls, self_time) = filtered;
+
+        if count > 0 {
+            state.create_table(0, 5);
+
+            state.push("id");
+            state.push("filtered");
+            state.set_table(-3);
+
+            state.push("name");
+            state.push("<filtered>");
+            state.set_table(-3);
+
+            state.push("calls");
+            state.push(clamped(calls));
+            state.set_table(-3);
+
+            state.push("totalSelfTime");
+            state.push(self.seconds(self_time));
+            state.set_table(-3);
+
+            state.push("functionCount");
+            state.push(clamped(count));
+            state.set_table(-3);
+
+            state.push_value(-1);
+            state.set_field(-3, "filtered");
+
+            state.seti(-2, next);
+            next += 1;
+        }
+
+        let (count, calls) = insignificant;
+
+        if count > 0 {
+            state.create_table(0, 4);
+
+            state.push("id");
+            state.push("insignificant");
+            state.set_table(-3);
+
+            state.push("name");
+            state.push("<insignificant>");
+            state.set_table(-3);
+
+            state.push("calls");
+            state.push(clamped(calls));
+            state.set_table(-3);
+
+            state.push("functionCount");
+            state.push(clamped(count));
+            state.set_table(-3);
+
+            state.push_value(-1);
+            state.set_field(-3, "insignificant");
+
+            state.seti(-2, next);
+        }
+
+        state.push("totalTime");
+        state.push(self.total_time.map(|v| self.seconds(v)));
+        state.set_table(-3);
+
+        state.push("summary");
+        state.create_table(0, 15);
+
+        state.push("functionCount");
+        state.push(clamped(self.data.len()));
+        state.set_table(-3);
+
+        state.push("totalCalls");
+        state.push(
+            self.data
+                .values()
+                .fold(0i64, |sum, v| sum.saturating_add(clamped(v.calls))),
+        );
+        state.set_table(-3);
+
+        state.push("totalTime");
+        state.push(self.total_time.map(|v| self.seconds(v)));
+        state.set_table(-3);
+
+        state.push("callEvents");
+        state.push(clamped(self.call_events));
+        state.set_table(-3);
+
+        state.push("returnEvents");
+        state.push(clamped(self.return_events));
+        state.set_table(-3);
+
+        state.push("tailCallEvents");
+        state.push(clamped(self.tail_call_events));
+        state.set_table(-3);
+
+        state.push("maxStackDepth");
+        state.push(clamped(self.max_stack_depth));
+        state.set_table(-3);
+
+        state.push("status");
+        state.push(self.status());
+        state.set_table(-3);
+
+        state.push("clock");
+        state.push(self.clock);
+        state.set_table(-3);
+
+        // not rounded: it's usually below the precision
+        state.push("clockResolution");
+        state.push(self.clock_resolution.as_secs_f64());
+        state.set_table(-3);
+
+        state.push("clockAnomalies");
+        state.push(clamped(self.clock_anomalies));
+        state.set_table(-3);
+
+        // the shares are of the self-time of all of the entries
+        let self_time = self.data.values().fold(Duration::new(0, 0), |sum, v| {
+            sum.saturating_add(v.total_self_time)
+        });
+        let domains = ["c", "lua", "main"];
+
+        for (domain, time) in domains.iter().zip(self.domain_times()) {
+            state.push(self.seconds(time));
+            state.set_field(-2, &format!("{}Time", domain));
+
+            if !self_time.is_zero() {
+                state.push(time.as_secs_f64() / self_time.as_secs_f64());
+                state.set_field(-2, &format!("{}Share", domain));
+            }
+        }
+
+        state.push("reliability");
+        self.push_reliability(state, saturated);
+        state.set_table(-3);
+
+        if let Some(ref metamethods) = self.metamethods {
+            state.push("metamethods");
+            state.create_table(0, metamethods.len() as i32);
+
+            for (kind, v) in metamethods {
+                state.create_table(0, 3);
+
+                state.push("calls");
+                state.push(clamped(v.calls));
+                state.set_table(-3);
+
+                if !self.self_only {
+                    state.push("totalTime");
+                    state.push(self.seconds(v.total_time));
+                    state.set_table(-3);
+                }
+
+                state.push("totalSelfTime");
+                state.push(self.seconds(v.total_self_time));
+                state.set_table(-3);
+
+                state.set_field(-2, kind);
+            }
+
+            state.set_table(-3);
+        }
+
+        if !self.metadata.is_empty() {
+            state.push("metadata");
+            state.create_table(0, self.metadata.len() as i32);
+
+            for (key, value) in &self.metadata {
+                value.push(state);
+                state.set_field(-2, key);
+            }
+
+            state.set_table(-3);
+        }
+
+        if self.tracks_memory {
+            let growth = clamped(self.memory.bytes_allocated)
+                .saturating_sub(clamped(self.memory.bytes_freed));
+            let fields = [
+                ("totalAllocated", clamped(self.memory.bytes_allocated)),
+                ("totalFreed", clamped(self.memory.bytes_freed)),
+                ("netGrowth", growth),
+            ];
+
+            for &(field, value) in &fields {
+                state.push(value);
+                state.set_field(-2, field);
+            }
+
+            if self.tracks_live_bytes {
+                state.push(clamped(self.memory.live_bytes));
+                state.set_field(-2, "liveBytes");
+            }
+        }
+
+        state.set_table(-3);
+
+        state.push("labels");
+        state.create_table(0, self.labels.len() as i32);
+
+        for (label, v) in &self.labels {
+            state.push(self.seconds(*v));
+            state.set_field(-2, label);
+        }
+
+        state.set_table(-3);
+
+        state.push("markers");
+        state.create_table(self.markers.len() as i32, 0);
+
+        for (i, (label, time)) in self.markers.iter().enumerate() {
+            state.create_table(0, 2);
+
+            state.push("label");
+            state.push(label.as_str());
+            state.set_table(-3);
+
+            state.push("time");
+            state.push(self.seconds(*time));
+            state.set_table(-3);
+
+            state.seti(-2, (i + 1) as i64);
+        }
+
+        state.set_table(-3);
+
+        if let Some(threshold) = self.threshold {
+            let limit = match (threshold, self.total_time) {
+                (Threshold::Time(time), _) => Some(time),
+                (Threshold::Share(share), Some(total)) => Some(total.mul_f64(share)),
+                (Threshold::Share(_), None) => None,
+            };
+
+            let violations = match limit {
+                Some(limit) => self
+                    .data
+                    .iter()
+                    .filter(|(_, v)| v.total_self_time > limit)
+                    .filter_map(|(k, _)| indices.get(k).copied())
+                    .collect(),
+                None => Vec::new(),
+            };
+
+            state.push("violations");
+            state.create_table(violations.len() as i32, 0);
+
+            for (i, index) in violations.into_iter().enumerate() {
+                // Stack: result "violations" violations
+                state.raw_geti(-3, index);
+                state.seti(-2, (i + 1) as i64);
+            }
+
+            state.set_table(-3);
+        }
+
+        // the edges of the filtered entries have nothing to point to
+        let mut edges = self
+            .edges()
+            .into_iter()
+            .filter(|((caller, callee), _)| {
+                indices.contains_key(caller) && indices.contains_key(callee)
+            })
+            .collect::<Vec<_>>();
+
+        // in the order of the entries, like them
+        edges.sort_by_key(|((caller, callee), _)| (indices[caller], indices[callee]));
+
+        state.push("edges");
+        state.create_table(edges.len() as i32, 0);
+
+        for (i, &((caller, callee), (calls, total_time, self_time))) in edges.iter().enumerate() {
+            state.create_table(0, 7);
+
+            // Stack: result "edges" edges edge
+            state.push("caller");
+            state.raw_geti(-5, indices[&caller]);
+            state.set_table(-3);
+
+            state.push("callee");
+            state.raw_geti(-5, indices[&callee]);
+            state.set_table(-3);
+
+            state.push("calls");
+            state.push(clamped(calls));
+            state.set_table(-3);
+
+            if !self.self_only {
+                state.push("totalTime");
+                state.push(self.seconds(total_time));
+                state.set_table(-3);
+
+                let caller_time = self.data[&caller].total_time;
+
+                if !caller_time.is_zero() {
+                    state.push("callerShare");
+                    state.push(total_time.as_secs_f64() / caller_time.as_secs_f64());
+                    state.set_table(-3);
+                }
+            }
+
+            state.push("selfTime");
+            state.push(self.seconds(self_time));
+            state.set_table(-3);
+
+            if let Some(lines) = self.call_lines.get(&(caller, callee)) {
+                state.push("lines");
+                state.create_table(0, lines.len() as i32);
+
+                for (&line, &count) in lines {
+                    state.push(clamped(count));
+                    state.seti(-2, line as i64);
+                }
+
+                state.set_table(-3);
+            }
+
+            state.seti(-2, (i + 1) as i64);
+        }
+
+        state.set_table(-3);
+
+        if let Some(ref window) = self.window {
+            let (covered, recent) = window.recent();
+            let mut recent = recent
+                .into_iter()
+                .filter(|(k, _)| indices.contains_key(k))
+                .collect::<Vec<_>>();
+            recent.sort_by_key(|(_, activity)| Reverse(activity.self_time));
+
+            state.push("recent");
+            state.create_table(0, 2);
+
+            state.push("time");
+            state.push(self.seconds(covered));
+            state.set_table(-3);
+
+            state.push("entries");
+            state.create_table(recent.len() as i32, 0);
+
+            for (i, (k, activity)) in recent.iter().enumerate() {
+                state.create_table(0, 3);
+
+                // Stack: result "recent" recent "entries" entries activity
+                state.push("entry");
+                state.raw_geti(-7, indices[k]);
+                state.set_table(-3);
+
+                state.push("calls");
+                state.push(clamped(activity.calls));
+                state.set_table(-3);
+
+                state.push("totalSelfTime");
+                state.push(self.seconds(activity.self_time));
+                state.set_table(-3);
+
+                state.seti(-2, (i + 1) as i64);
+            }
+
+            state.set_table(-3);
+            state.set_table(-3);
+        }
+
+        // the path is chosen by the total times
+        let path = if self.self_only {
+            Vec::new()
+        } else {
+            self.critical_path()
+        };
+
+        if !path.is_empty() {
+            state.push("criticalPath");
+            state.create_table(path.len() as i32, 0);
+
+            for (i, &node) in path.iter().enumerate() {
+                let node = &self.tree[node];
+
+                state.create_table(0, 2);
+
+                state.push("name");
+                state.push(node.key.map_or_else(String::new, |key| self.name_of(key)));
+                state.set_table(-3);
+
+                state.push("totalTime");
+                state.push(self.seconds(node.total_time));
+                state.set_table(-3);
+
+                state.seti(-2, (i + 1) as i64);
+            }
+
+            state.set_table(-3);
+        }
+
+        state.push("saturated");
+        state.push(saturated);
+        state.set_table(-3);
+
+        let warnings = self
+            .warnings
+            .iter()
+            .cloned()
+            .chain(self.consistency_warnings())
+            .collect::<Vec<_>>();
+
+        state.push("warnings");
+        state.create_table(warnings.len() as i32, 0);
+
+        for (i, warning) in warnings.iter().enumerate() {
+            state.push(warning.as_str());
+            state.seti(-2, (i + 1) as i64);
+        }
+
+        state.set_table(-3);
+
+        match target {
+            Some(_) if state.get_metatable(-1) => state.pop(1),
+            _ => report::set_metatable(state),
+        }
+
+        1
+    }
+}
+
+// How `ProfilingResult::move_to_lua` fills a table given to it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Fill {
+    // the fields of the table are removed first
+    Clear,
+    // the entries are added after the ones in the table, and the other fields are replaced
+    Append,
+}
+
+// Removes all of the fields of the table at the top of the stack.
+fn clear_table(state: &mut State) {
+    state.push_nil();
+
+    while state.next(-2) {
+        // Stack: table key value -> table key
+        state.pop(1);
+        // assigning to an existing field doesn't break the traversal
+        state.push_value(-1);
+        state.push_nil();
+        state.raw_set(-4);
+    }
+}
+
+// Pushes a table describing the distribution of the session times.
+fn push_latencies(state: &mut State, latencies: &Latencies) {
+    state.create_table(0, 8);
+
+    state.push("count");
+    state.push(clamped(latencies.count));
+    state.set_table(-3);
+
+    let fields = [
+        ("mean", latencies.mean()),
+        ("min", latencies.min),
+        ("max", latencies.min.map(|_| latencies.max)),
+        ("stddev", latencies.stddev()),
+        ("p50", latencies.quantile(0.5)),
+        ("p95", latencies.quantile(0.95)),
+        ("p99", latencies.quantile(0.99)),
+    ];
+
+    for &(field, time) in &fields {
+        if let Some(time) = time {
+            state.push(time.as_secs_f64());
+            state.set_field(-2, field);
+        }
+    }
+}
+
+// Appends the result to the file in the serialized format, which starts with its header, so that
+// the snapshots can be told apart.
+fn append_snapshot(path: &str, result: &ProfilingResult) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    file.write_all(result.serialize().as_bytes())
+}
+
+// Lua strings are arbitrary bytes, which `State::to_str` and `State::check_string` fail or panic
+// on if they aren't valid UTF-8. These replace the invalid sequences instead, like the names of
+// the functions do.
+fn to_string_lossy(state: &mut State, index: i32) -> Option<String> {
+    state
+        .to_bytes(index)
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn check_string_lossy(state: &mut State, arg: i32) -> String {
+    // numbers are converted, as with luaL_checkstring
+    if !state.is_string(arg) {
+        state.check_type(arg, lua::Type::String);
+    }
+
+    to_string_lossy(state, arg).unwrap()
+}
+
+// A hook installed before the session, which the profiler's hook forwards the events it asked for
+// to, with the `chainHooks` option.
+#[derive(Clone, Copy)]
+struct ChainedHook {
+    hook: extern "C" fn(*mut ffi::lua_State, *mut ffi::lua_Debug),
+    mask: HookMask,
+    count: c_int,
+    // the instructions since its last count event
+    ticks: c_int,
+}
+
+impl ChainedHook {
+    fn new(
+        hook: extern "C" fn(*mut ffi::lua_State, *mut ffi::lua_Debug),
+        mask: HookMask,
+        count: c_int,
+    ) -> Self {
+        // a count event without a count is never delivered
+        let count = if mask.contains(lua::MASKCOUNT) {
+            count
+        } else {
+            0
+        };
+
+        Self {
+            hook,
+            mask,
+            count,
+            ticks: 0,
+        }
+    }
+
+    // Counts the `step` instructions of a count event of the installed hook, and returns whether
+    // this hook's count is reached.
+    fn count_event(&mut self, step: c_int) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        self.ticks += step;
+
+        if self.ticks < self.count {
+            return false;
+        }
+
+        self.ticks = 0;
+
+        true
+    }
+}
+
+fn gcd(mut a: c_int, mut b: c_int) -> c_int {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+
+    a
+}
+
+struct Profiler {
+    result: Option<ProfilingResult>,
+    // the calls in progress. It's cleared rather than replaced between the sessions and never
+    // shrinks, so the calls only allocate while the program goes deeper than it ever has.
+    stack: Vec<CallFrame>,
+    config: Config,
+    // the clock chosen by `Config::clock`
+    clock: SessionClock,
+    session_start: Option<Instant>,
+    // the time the last sample was taken at
+    last_sample: Option<Instant>,
+    // the result of the last finished session
+    finished: Option<ProfilingResult>,
+    // the number of sessions started and of resets, by which the iterators tell that `finished`
+    // is no longer the result they were created for
+    generation: i64,
+    // the names of the functions seen by the sessions before `resetTimings`, to skip looking
+    // them up again
+    names: HashMap<FunctionKey, FunctionName>,
+    // set while Lua code is called from the hook
+    in_callback: bool,
+    // set if the hook panicked, after which the events are ignored
+    failed: bool,
+    // the number of instructions since the last sample, if they are counted
+    ticks: c_int,
+    // the allocator wrapper, if the memory is tracked
+    memory: Option<Box<MemoryTracker>>,
+    // the number of hook events since the clock was last checked for the deadline and the progress
+    clock_ticks: usize,
+    // the number of events since the time the hook takes was last measured, and the start of the
+    // event being measured
+    overhead_ticks: usize,
+    overhead_start: Option<Instant>,
+    // the hook installed before the session, if the events are forwarded to it
+    chained: Option<ChainedHook>,
+    // the instruction counts of the profiler's count events and of the installed hook, which
+    // differ when chaining; the profiler handles every `own_count / hook_count`-th event
+    own_count: c_int,
+    hook_count: c_int,
+    // the instructions since the profiler's last count event, in steps of `hook_count`
+    count_ticks: c_int,
+    // the instructions run during the session, if they are counted for the `instructionLimit`
+    instructions: u64,
+    // the session time after which the next progress line is printed
+    next_progress: Duration,
+    // the stack level of the code that started the session; the frames at or below it belong to
+    // the profiler and its caller
+    base_level: usize,
+    // the last result of `frame`, whose memory the next session reuses
+    spare: Option<ProfilingResult>,
+    // unless the hook's time is charged to the frames, the time the event being handled started
+    // at, and the time the hook took so far, which the frames are timed without
+    event_start: Option<Instant>,
+    hidden_time: Duration,
+    // the session time after which the next snapshot is appended to the `flushFile`, the events
+    // since the last one, and whether writing one failed
+    next_flush: Duration,
+    flush_ticks: usize,
+    flush_failed: bool,
+    // the receiver of the calls set with `ProfilerBuilder::event_sink`, shared with its copies
+    sink: Option<Rc<RefCell<dyn EventSink>>>,
+}
+
+impl Profiler {
+    const TYPE_NAME: &'static str = "Profiler";
+    const CLOCK_CHECK_INTERVAL: usize = 1000;
+    // the number of events per measurement of the time the hook takes
+    const OVERHEAD_SAMPLE_INTERVAL: usize = 64;
+    // the call depth most programs stay within, to avoid growing the stack during the session
+    const STACK_CAPACITY: usize = 64;
+    // the numbers of calls `benchmark` makes by default
+    const DEFAULT_WARMUP: usize = 10;
+    const DEFAULT_ITERATIONS: usize = 100;
+
+    fn new(state: &mut State) -> i32 {
+        let config = Config::from_lua(state, 1);
+        Self::push(state, Self::with_config(config));
+
+        1
+    }
+
+    fn with_config(config: Config) -> Self {
+        Profiler {
+            result: None,
+            stack: Vec::with_capacity(Self::STACK_CAPACITY),
+            clock: SessionClock::new(config.clock),
+            config,
+            session_start: None,
+            last_sample: None,
+            finished: None,
+            generation: 0,
+            names: HashMap::new(),
+            in_callback: false,
+            failed: false,
+            ticks: 0,
+            clock_ticks: 0,
+            overhead_ticks: 0,
+            overhead_start: None,
+            chained: None,
+            own_count: 0,
+            hook_count: 0,
+            count_ticks: 0,
+            instructions: 0,
+            next_progress: Duration::new(0, 0),
+            memory: None,
+            base_level: 0,
+            spare: None,
+            event_start: None,
+            hidden_time: Duration::new(0, 0),
+            next_flush: Duration::new(0, 0),
+            flush_ticks: 0,
+            flush_failed: false,
+            sink: None,
+        }
+    }
+
+    // Pushes the profiler to the stack as a userdata.
+    fn push(state: &mut State, profiler: Self) {
+        // the metatable lives in the registry, so it has to be created in every state
+        if state.new_metatable(Self::TYPE_NAME) {
+            state.set_fns(
+                &[
+                    ("__call", lua_func!(Self::call)),
+                    ("__gc", lua_func!(Self::gc)),
+                    ("pushLabel", lua_func!(Self::push_label)),
+                    ("popLabel", lua_func!(Self::pop_label)),
+                    ("mark", lua_func!(Self::mark)),
+                    ("serialize", lua_func!(Self::serialize)),
+                    ("dot", lua_func!(Self::dot)),
+                    ("csv", lua_func!(Self::csv)),
+                    ("tree", lua_func!(Self::tree)),
+                    ("subtreeTime", lua_func!(Self::subtree_time)),
+                    ("collapseRecursion", lua_func!(Self::collapse_recursion)),
+                    ("pack", lua_func!(Self::pack)),
+                    #[cfg(feature = "pprof")]
+                    ("pprof", lua_func!(Self::pprof)),
+                    ("save", lua_func!(Self::save)),
+                    ("result", lua_func!(Self::result)),
+                    ("iter", lua_func!(Self::iter)),
+                    ("stats", lua_func!(Self::stats)),
+                    ("reset", lua_func!(Self::reset)),
+                    ("resetTimings", lua_func!(Self::reset_timings)),
+                    ("setMetadata", lua_func!(Self::set_metadata)),
+                    ("events", lua_func!(Self::events)),
+                    ("clone", lua_func!(Self::clone_lua)),
+                    ("profileCoroutine", lua_func!(Self::profile_coroutine)),
+                    ("frame", lua_func!(Self::frame)),
+                ],
+                0,
+            );
+            state.push_value(-1);
+            state.set_field(-2, "__index");
+        }
+
+        state.pop(1);
+
+        // Safety: guaranteed by Lua.
+        unsafe {
+            *state.new_userdata_typed() = ManuallyDrop::new(profiler);
+        }
+
+        state.set_metatable_from_registry(Self::TYPE_NAME);
+    }
+
+    // The time the frames are timed with: the clock's, less the time the hook took if it's not
+    // charged to the frames. During an event, it stands still at the moment the event started.
+    fn now(&self) -> Instant {
+        let now = self.event_start.unwrap_or_else(|| self.clock.now());
+
+        now.checked_sub(self.hidden_time).unwrap_or(now)
+    }
+
+    // Returns the data collected so far as if the session ended now.
+    fn snapshot(&self) -> Option<ProfilingResult> {
+        let mut result = self.result.clone()?;
+        let now = self.now();
+
+        for frame in self.stack.iter().rev() {
+            let mut frame = *frame;
+            frame.resume(now);
+            frame.close(&mut result, now);
+        }
+
+        result.total_time = self.session_start.map(|v| result.elapsed(v, now));
+        result.label_stack.clear();
+
+        Some(result)
+    }
+
+    // profiler:clone(): creates a profiler with the same configuration. If the profiler is
+    // running, the copy's finished result is a snapshot of the session; otherwise, it's a copy of
+    // the last finished result.
+    fn clone_lua(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let mut config = this.config.clone();
+        let finished = this.snapshot().or_else(|| this.finished.clone());
+        let names = this.names.clone();
+        let sink = this.sink.clone();
+        let depth = this.stack.capacity();
+
+        // the copy must hold its own reference, since it's released when the profiler is collected
+        if let Some(stream) = config.stream {
+            state.raw_geti(lua::REGISTRYINDEX, stream.value() as i64);
+            config.stream = Some(state.reference(lua::REGISTRYINDEX));
+        }
+
+        if let Some(key_fn) = config.key_fn {
+            state.raw_geti(lua::REGISTRYINDEX, key_fn.value() as i64);
+            config.key_fn = Some(state.reference(lua::REGISTRYINDEX));
+        }
+
+        if let Some(callback) = config.on_new_function {
+            state.raw_geti(lua::REGISTRYINDEX, callback.value() as i64);
+            config.on_new_function = Some(state.reference(lua::REGISTRYINDEX));
+        }
+
+        if let Some(resolver) = config.resolve_name {
+            state.raw_geti(lua::REGISTRYINDEX, resolver.value() as i64);
+            config.resolve_name = Some(state.reference(lua::REGISTRYINDEX));
+        }
+
+        // the copy likely profiles the same code, so it starts with the stack the original grew
+        let mut copy = Self::with_config(config);
+        copy.stack.reserve(depth);
+        copy.finished = finished;
+        copy.names = names;
+        copy.sink = sink;
+        Self::push(state, copy);
+
+        1
+    }
+
+    fn call(state: &mut State) -> i32 {
+        // check but don't use, since we need state later
+        state.check_userdata(1, Self::TYPE_NAME);
+        state.check_type(2, lua::Type::Function);
+
+        Self::run(state)
+    }
+
+    // Runs a profiling session. The stack must contain the profiler, the function to profile, and
+    // the arguments to pass to it.
+    fn run(state: &mut State) -> i32 {
+        // Safety: the userdata is at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        let protected = this.config.protected;
+
+        if !Self::is_enabled(state) {
+            return Self::run_disabled(state, protected);
+        }
+
+        let nargs = state.get_top() - 2;
+        let (errored, result) = Self::session(state, nargs, 0);
+
+        if errored && !protected {
+            // Safety: the userdata is still at index 1
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+            // kept for `profiler:result` before propagating the error, which would leak it
+            this.finished = Some(result);
+
+            state.error();
+        }
+
+        // in the protected mode, the results are preceded by the status like pcall's
+        let nresults = match (protected, errored) {
+            (false, _) => 1,
+            (true, false) => {
+                state.push(true);
+
+                2
+            }
+            (true, true) => {
+                // Stack: ... error -> ... false error
+                state.push(false);
+                state.insert(-2);
+
+                3
+            }
+        };
+
+        result.move_to_lua(state, None);
+
+        // Safety: the userdata is still at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        this.finished = Some(result);
+
+        nresults
+    }
+
+    // Calls the function without profiling it, returning its results instead of the result table.
+    // Errors are still caught in the protected mode.
+    fn run_disabled(state: &mut State, protected: bool) -> i32 {
+        // Stack: profiler f args... -> f args...
+        state.remove(1);
+        let nargs = state.get_top() - 1;
+
+        if !protected {
+            state.call(nargs, lua::MULTRET);
+
+            return state.get_top();
+        }
+
+        let ok = !state.pcall(nargs, lua::MULTRET, 0).is_err();
+        state.push(ok);
+        state.insert(1);
+
+        state.get_top()
+    }
+
+    // Checks whether profiling is enabled in the state, which it is unless disabled by
+    // `lprofile.setEnabled(false)` or the environment.
+    fn is_enabled(state: &mut State) -> bool {
+        state.raw_getp(lua::REGISTRYINDEX, &DISABLED_REGISTRY_KEY);
+        let disabled = state.to_bool(-1);
+        state.pop(1);
+
+        !disabled
+    }
+
+    fn set_enabled(state: &mut State, enabled: bool) {
+        state.push(!enabled);
+        state.raw_setp(lua::REGISTRYINDEX, &DISABLED_REGISTRY_KEY);
+    }
+
+    // lprofile.setEnabled(enabled): turns profiling on or off in the state. While it's off, the
+    // sessions just call the functions. A running session is not affected.
+    fn set_enabled_lua(state: &mut State) -> i32 {
+        state.check_type(1, lua::Type::Boolean);
+        let enabled = state.to_bool(1);
+        Self::set_enabled(state, enabled);
+
+        0
+    }
+
+    // lprofile.isEnabled(): checks whether profiling is enabled.
+    fn is_enabled_lua(state: &mut State) -> i32 {
+        let enabled = Self::is_enabled(state);
+        state.push(enabled);
+
+        1
+    }
+
+    // lprofile.current(): returns the profiler running a session, or nil if there's none.
+    fn current(state: &mut State) -> i32 {
+        if !Self::get_from_registry(state) {
+            state.push_nil();
+        }
+
+        1
+    }
+
+    // Calls the function below the `nargs` values at the top of the stack with them while
+    // profiling. The profiler must be at index 1. Leaves the function's results (or the error) on
+    // the stack and returns whether an error was thrown.
+    fn session(state: &mut State, nargs: i32, nresults: i32) -> (bool, ProfilingResult) {
+        // the profiled function is called with lua_pcall, which doesn't add a frame, so it runs at
+        // base_level + 1 whatever called the profiler
+        let base_level = Self::get_stack_level(state);
+        let l = state.as_ptr();
+
+        Self::session_on(state, l, base_level, |state| {
+            state.pcall(nargs, nresults, 0).is_err()
+        })
+    }
+
+    // Runs `body` while profiling the thread `thread`, whose frames above `base_level` are
+    // profiled. The profiler must be at index 1. `body` returns whether an error was thrown, which
+    // is returned along with the result.
+    fn session_on(
+        state: &mut State,
+        thread: *mut ffi::lua_State,
+        base_level: usize,
+        body: impl FnOnce(&mut State) -> bool,
+    ) -> (bool, ProfilingResult) {
+        if Self::get_from_registry(state) {
+            Error::AlreadyRunning.raise(
+                state,
+                "attempt to run multiple profiling sessions simultaneously",
+            );
+        }
+
+        let l = state.as_ptr();
+        // Safety: the thread is kept alive by the caller during the session
+        let hooked = unsafe { &mut State::from_ptr(thread) };
+
+        // Safety: checked above; set_hook does not modify the stack.
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        let config = this.config.clone();
+        let result = match this.spare.take() {
+            Some(mut result) => {
+                result.recycle(&config);
+
+                result
+            }
+            None => ProfilingResult::new(&config),
+        };
+        this.result.replace(result);
+        this.generation = this.generation.wrapping_add(1);
+        let clock = this.clock;
+        this.session_start = Some(clock.now());
+        this.last_sample = this.session_start;
+        this.in_callback = false;
+        this.failed = false;
+        this.ticks = 0;
+        this.clock_ticks = 0;
+        this.event_start = None;
+        this.hidden_time = Duration::new(0, 0);
+        this.next_progress = config.progress.unwrap_or_default();
+        this.next_flush = config.flush_interval().unwrap_or_default();
+        this.flush_ticks = 0;
+        this.flush_failed = false;
+        this.base_level = base_level;
+        this.memory = if config.memory {
+            // Safety: `l` is valid; the tracker is removed before the session ends
+            Some(unsafe { MemoryTracker::install(l, config.live_bytes) })
+        } else {
+            None
+        };
+
+        state.push_value(1);
+        state.raw_setp(lua::REGISTRYINDEX, &OPAQUE_REGISTRY_KEY);
+
+        let (prev_hook, chained) = Self::set_hook(hooked, &config);
+        let hook_count = hooked.get_hook_count();
+
+        // Safety: the userdata is still at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        this.chained = chained;
+        this.own_count = Self::own_count(&config);
+        this.hook_count = hook_count;
+        this.count_ticks = 0;
+        this.instructions = 0;
+        this.overhead_ticks = 0;
+        this.overhead_start = None;
+
+        let start = clock.now();
+        let errored = body(state);
+        let total_time = clock.now().saturating_duration_since(start);
+
+        Self::unset_hook(hooked, prev_hook);
+
+        // only the blocks that are still reachable count as live
+        if config.live_bytes {
+            state.gc(lua::GcOption::Collect, 0);
+        }
+
+        // Safety: the userdata is still at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        let memory = this.memory.take();
+
+        let mut result = Self::end_session(state, errored).unwrap();
+        result.errored = errored;
+        result.total_time = Some(total_time);
+        result.latencies.record(total_time);
+
+        if let Some(memory) = memory {
+            // Safety: installed on the same state above
+            unsafe { memory.uninstall(l) };
+
+            for (key, stats) in memory.stats.borrow().iter() {
+                if let Some(entry) = result.data.get_mut(key) {
+                    entry.memory.add(stats);
+                }
+            }
+
+            let mut total = memory.total.get();
+
+            for (key, live) in memory.live_bytes() {
+                total.live_bytes += live;
+
+                if let Some(entry) = result.data.get_mut(&key) {
+                    entry.memory.live_bytes += live;
+                }
+            }
+
+            result.memory.add(&total);
+        }
+
+        // the last snapshot is the whole session
+        if let Some(ref path) = config.flush_file {
+            if let Err(e) = append_snapshot(path, &result) {
+                result
+                    .warnings
+                    .push(format!("could not write the snapshot to {}: {}", path, e));
+            }
+        }
+
+        (errored, result)
+    }
+
+    // profiler:profileCoroutine(co, ...): resumes the coroutine with the arguments, and then
+    // without arguments each time it yields, until it returns, profiling only the code it runs.
+    // Returns the result table, an array of the values of each yield packed like `table.pack`
+    // does, and the values the coroutine returned.
+    fn profile_coroutine(state: &mut State) -> i32 {
+        state.check_userdata(1, Self::TYPE_NAME);
+        state.check_type(2, lua::Type::Thread);
+
+        let mut co = state.to_thread(2).unwrap();
+
+        let resumable = match co.status() {
+            ThreadStatus::Yield => true,
+            // a coroutine that hasn't started only has its function on the stack; a running or
+            // normal one has frames
+            ThreadStatus::Ok => co.get_stack(0).is_none() && co.get_top() > 0,
+            _ => false,
+        };
+        state.arg_check(resumable, 2, "cannot resume a dead or running coroutine");
+
+        let nargs = state.get_top() - 2;
+        state.arg_check(co.check_stack(nargs), 2, "too many arguments to resume");
+
+        // Stack: profiler co args... -> profiler co yields args...
+        state.create_table(0, 0);
+        state.insert(3);
+
+        // Safety: the userdata is at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        let protected = this.config.protected;
+
+        let (errored, result) = if Self::is_enabled(state) {
+            // the coroutine's function is at level 1 of its stack, and nothing runs below it
+            let l = co.as_ptr();
+            let (errored, result) =
+                Self::session_on(state, l, 0, |state| Self::drive(state, &mut co, nargs));
+
+            (errored, Some(result))
+        } else {
+            (Self::drive(state, &mut co, nargs), None)
+        };
+
+        if errored && !protected {
+            if let Some(result) = result {
+                // Safety: the userdata is still at index 1
+                let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+                // kept for `profiler:result` before propagating the error, which would leak it
+                this.finished = Some(result);
+            }
+
+            state.error();
+        }
+
+        if errored {
+            // Stack: profiler co yields error -> profiler co false error [result]
+            state.remove(3);
+            state.push(false);
+            state.insert(3);
+        }
+
+        if let Some(result) = result {
+            result.move_to_lua(state, None);
+            state.insert(if errored { 5 } else { 3 });
+
+            // Safety: the userdata is still at index 1
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+            this.finished = Some(result);
+        }
+
+        if protected && !errored {
+            state.push(true);
+            state.insert(3);
+        }
+
+        state.get_top() - 2
+    }
+
+    // Resumes the coroutine with the `nargs` values at the top of the stack, and then again each
+    // time it yields, appending the yielded values to the table at index 3. Leaves the values it
+    // returned (or the error) on the stack and returns whether an error was thrown.
+    fn drive(state: &mut State, co: &mut State, nargs: i32) -> bool {
+        state.xmove(co, nargs);
+        let mut nargs = nargs;
+        let mut yields = 0;
+
+        loop {
+            let status = co.resume(Some(state), nargs);
+            let n = co.get_top();
+
+            if status.is_err() {
+                co.xmove(state, 1);
+
+                return true;
+            }
+
+            if !state.check_stack(n + 1) {
+                co.pop(n);
+                state.push("too many results to resume");
+
+                return true;
+            }
+
+            co.xmove(state, n);
+
+            if status == ThreadStatus::Ok {
+                return false;
+            }
+
+            // Stack: values... -> {values..., n = n}
+            state.create_table(n, 1);
+            state.insert(-(n + 1));
+
+            for i in (1..=n).rev() {
+                state.seti(-(i + 1), i as i64);
+            }
+
+            state.push("n");
+            state.push(n as i64);
+            state.set_table(-3);
+
+            yields += 1;
+            state.seti(3, yields);
+            nargs = 0;
+        }
+    }
+
+    // profiler:frame(f, ...): profiles a call of `f` with the arguments and returns its results,
+    // keeping the result as the profiler's last one. Meant to be called once per frame of a loop:
+    // the previous result is reused for the session, so the allocations stay the same from frame
+    // to frame. Calls made while a session is running aren't profiled.
+    fn frame(state: &mut State) -> i32 {
+        state.check_userdata(1, Self::TYPE_NAME);
+        state.check_type(2, lua::Type::Function);
+
+        let running = Self::get_from_registry(state);
+
+        if running {
+            state.pop(1);
+        }
+
+        if running || !Self::is_enabled(state) {
+            // Stack: profiler f args... -> f args...
+            state.remove(1);
+            let nargs = state.get_top() - 1;
+            state.call(nargs, lua::MULTRET);
+
+            return state.get_top();
+        }
+
+        // Safety: the userdata is at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        this.spare = this.finished.take();
+
+        let nargs = state.get_top() - 2;
+        let (errored, result) = Self::session(state, nargs, lua::MULTRET);
+
+        // Safety: the userdata is still at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        this.finished = Some(result);
+
+        if errored {
+            state.error();
+        }
+
+        state.get_top() - 1
+    }
+
+    // lprofile.wrap(f[, config]): returns a function that calls `f` while profiling it, and the
+    // profiler that accumulates the results of all of the calls.
+    fn wrap(state: &mut State) -> i32 {
+        state.check_type(1, lua::Type::Function);
+        let config = Config::from_lua(state, 2);
+
+        Self::push(state, Self::with_config(config));
+        state.push_value(-1);
+        state.push_value(1);
+        state.push_closure(lua_func!(Self::wrapped), 2);
+        state.insert(-2);
+
+        2
+    }
+
+    fn wrapped(state: &mut State) -> i32 {
+        let nargs = state.get_top();
+
+        // nested calls are part of the running session
+        let running = Self::get_from_registry(state);
+
+        if running {
+            state.pop(1);
+        }
+
+        if running || !Self::is_enabled(state) {
+            state.push_value(ffi::lua_upvalueindex(2));
+            state.insert(1);
+            state.call(nargs, lua::MULTRET);
+
+            return state.get_top();
+        }
+
+        // Stack: args... -> profiler f args...
+        state.push_value(ffi::lua_upvalueindex(1));
+        state.insert(1);
+        state.push_value(ffi::lua_upvalueindex(2));
+        state.insert(2);
+
+        let nargs = state.get_top() - 2;
+        let (errored, result) = Self::session(state, nargs, lua::MULTRET);
+
+        // Safety: the userdata is at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+
+        match this.finished {
+            Some(ref mut finished) => finished.merge(&result),
+            None => this.finished = Some(result),
+        }
+
+        if errored {
+            state.error();
+        }
+
+        state.get_top() - 1
+    }
+
+    // lprofile.benchmark(f[, options], ...): calls `f` with the rest of the arguments the `warmup`
+    // number of times without profiling, then profiles the `iterations` number of calls. Returns
+    // the statistics of the call times, like `profiler:stats`, and the result table of all of the
+    // profiled calls.
+    fn benchmark(state: &mut State) -> i32 {
+        state.check_type(1, lua::Type::Function);
+        let (warmup, iterations) = Self::benchmark_options(state, 2);
+
+        // the options may be absent, but the slot is removed below
+        let top = state.get_top().max(2);
+        state.set_top(top);
+        let nargs = top - 2;
+
+        // each call pushes the function and the arguments again
+        let fits = state.check_stack(nargs + 1);
+        state.arg_check(fits, 3, "too many arguments to benchmark");
+
+        for _ in 0..warmup {
+            for i in 1..=nargs + 1 {
+                state.push_value(if i == 1 { 1 } else { i + 1 });
+            }
+
+            state.call(nargs, 0);
+        }
+
+        // Stack: f options args... -> Self f args...
+        state.remove(2);
+        Self::push(state, Self::with_config(Config::default()));
+        state.insert(1);
+
+        let fits = state.check_stack(nargs + 1);
+        state.arg_check(fits, 3, "too many arguments to benchmark");
+
+        for _ in 0..iterations {
+            for i in 2..=nargs + 2 {
+                state.push_value(i);
+            }
+
+            let (errored, result) = Self::session(state, nargs, 0);
+
+            // Safety: the userdata is at index 1
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+
+            // merged before propagating the error, which would leak the result
+            match this.finished {
+                Some(ref mut finished) => finished.merge(&result),
+                None => this.finished = Some(result),
+            }
+
+            if errored {
+                state.error();
+            }
+        }
+
+        // Safety: the userdata is still at index 1
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+        let result = this.finished.take().unwrap();
+
+        push_latencies(state, &result.latencies);
+        result.move_to_lua(state, None);
+
+        2
+    }
+
+    // Reads the options of `benchmark` from the table at `arg`, which may be absent.
+    fn benchmark_options(state: &mut State, arg: i32) -> (usize, usize) {
+        let mut counts = [Self::DEFAULT_WARMUP, Self::DEFAULT_ITERATIONS];
+
+        if state.is_none_or_nil(arg) {
+            return (counts[0], counts[1]);
+        }
+
+        state.check_type(arg, lua::Type::Table);
+
+        for (count, key) in counts.iter_mut().zip(["warmup", "iterations"]) {
+            state.get_field(arg, key);
+
+            if !state.is_nil(-1) {
+                match state.to_integerx(-1) {
+                    Some(v) if v > 0 => *count = v as usize,
+                    _ => {
+                        let msg = format!("option '{}' must be a positive integer", key);
+                        state.arg_error(arg, &msg);
+                    }
+                }
+            }
+
+            state.pop(1);
+        }
+
+        (counts[0], counts[1])
+    }
+
+    // lprofile.assertUnder(f, budgets, ...): profiles a call of `f` with the rest of the arguments
+    // and raises BUDGET_EXCEEDED if the total time of one of the functions named in `budgets`
+    // exceeds the number of seconds it maps to. Otherwise, returns the result table.
+    fn assert_under(state: &mut State) -> i32 {
+        state.check_type(1, lua::Type::Function);
+        let budgets = Self::budgets(state, 2);
+        let nargs = state.get_top() - 2;
+
+        // Stack: f budgets args... -> Self f args...
+        state.remove(2);
+        Self::push(state, Self::with_config(Config::default()));
+        state.insert(1);
+
+        let (errored, result) = Self::session(state, nargs, 0);
+
+        if errored || Self::push_budget_error(state, &result, &budgets) {
+            // nothing that owns memory may be left when the error is raised
+            drop(budgets);
+
+            // Safety: the userdata is at index 1
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+            this.finished = Some(result);
+
+            state.error();
+        }
+
+        result.move_to_lua(state, None)
+    }
+
+    // If one of the functions named in `budgets` is missing from the result or took longer than
+    // its budget, pushes the error to raise and returns true.
+    fn push_budget_error(
+        state: &mut State,
+        result: &ProfilingResult,
+        budgets: &[(String, f64)],
+    ) -> bool {
+        let mut exceeded = Vec::new();
+
+        for (name, budget) in budgets {
+            let keys = result.find_keys(name);
+
+            if keys.is_empty() {
+                let msg = format!("no function named '{}'", name);
+                Error::NotFound.push(state, &msg);
+
+                return true;
+            }
+
+            // the functions sharing the name are added up, like `result:get` does
+            let time = keys
+                .iter()
+                .fold(Duration::new(0, 0), |sum, k| {
+                    sum.saturating_add(result.data[k].total_time)
+                })
+                .as_secs_f64();
+
+            if time > *budget {
+                exceeded.push(format!(
+                    "{} took {:.6} s, {:.6} s ({:.1}%) over its budget of {:.6} s",
+                    name,
+                    time,
+                    time - budget,
+                    (time / budget - 1.0) * 100.0,
+                    budget,
+                ));
+            }
+        }
+
+        if exceeded.is_empty() {
+            return false;
+        }
+
+        Error::BudgetExceeded.push(state, &exceeded.join("; "));
+
+        true
+    }
+
+    // Reads the budgets of `assertUnder` from the table at `arg`: names or ids mapped to positive
+    // numbers of seconds. They are sorted by name to report the failures in a stable order.
+    fn budgets(state: &mut State, arg: i32) -> Vec<(String, f64)> {
+        state.check_type(arg, lua::Type::Table);
+
+        let mut budgets = Vec::new();
+        state.push_nil();
+
+        while state.next(arg) {
+            let name = match state.type_of(-2) {
+                Some(lua::Type::String) => to_string_lossy(state, -2).unwrap(),
+                _ => {
+                    state.arg_error(arg, "budget names must be strings");
+                    unreachable!();
+                }
+            };
+
+            match state.to_numberx(-1) {
+                Some(v) if v > 0.0 && v.is_finite() => budgets.push((name, v)),
+                _ => {
+                    let msg = format!("budget of '{}' must be a positive number", name);
+                    state.arg_error(arg, &msg);
+                }
+            }
+
+            state.pop(1);
+        }
+
+        budgets.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+
+        budgets
+    }
+
+    // Closes the frames left on the stack, takes the result and removes the profiler from the
+    // registry. The stack is left unchanged.
+    fn end_session(state: &mut State, errored: bool) -> Option<ProfilingResult> {
+        if !Self::get_from_registry(state) {
+            return None;
+        }
+
+        // Safety: the registry is not modified during profiling
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+
+        let this: &mut Self = &mut **this;
+        let orphaned = this.stack.len();
+
+        // if an error was thrown, the frames it unwound have never received a return event
+        this.event_start = None;
+        let now = this.now();
+        this.close_frames_above(0, errored, now);
+
+        if !errored && orphaned > 0 {
+            this.result.as_mut().unwrap().warnings.push(format!(
+                "{} call frame(s) did not receive a return event; the results may be inaccurate",
+                orphaned
+            ));
+        }
+
+        let result = this.result.take();
+        state.pop(1);
+
+        state.push_nil();
+        state.raw_setp(lua::REGISTRYINDEX, &OPAQUE_REGISTRY_KEY);
+
+        result
+    }
+
+    fn push_label(state: &mut State) -> i32 {
+        let label = check_string_lossy(state, 2);
+
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        match this.result.as_mut() {
+            Some(result) => {
+                result.push_label(label);
+
+                0
+            }
+            None => Error::NotRunning.raise(state, "the profiler is not running"),
+        }
+    }
+
+    fn pop_label(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let popped = this
+            .result
+            .as_mut()
+            .map(|result| result.label_stack.pop().is_some());
+
+        match popped {
+            Some(true) => 0,
+            Some(false) => Error::NoLabel.raise(state, "no label to pop"),
+            None => Error::NotRunning.raise(state, "the profiler is not running"),
+        }
+    }
+
+    // Markers don't touch the stack of the profiler, so they can also be set from the callbacks
+    // called by the hook.
+    fn mark(state: &mut State) -> i32 {
+        let label = check_string_lossy(state, 2);
+
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let start = this.session_start;
+        let now = this.clock.now();
+
+        match (this.result.as_mut(), start) {
+            (Some(result), Some(start)) => {
+                result
+                    .markers
+                    .push((label, now.saturating_duration_since(start)));
+
+                0
+            }
+            _ => Error::NotRunning.raise(state, "the profiler is not running"),
+        }
+    }
+
+    fn serialize(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        match this.finished.as_ref().map(ProfilingResult::serialize) {
+            Some(serialized) => {
+                state.push(serialized);
+
+                1
+            }
+            None => Error::NotFinished.raise(state, "no finished session to serialize"),
+        }
+    }
+
+    // profiler:pack(): converts the result of the last finished session to a compact binary
+    // string.
+    fn pack(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        match this.finished.as_ref().map(ProfilingResult::pack) {
+            Some(packed) => {
+                pack::push_bytes(state, &packed);
+
+                1
+            }
+            None => Error::NotFinished.raise(state, "no finished session to pack"),
+        }
+    }
+
+    // profiler:pprof(): encodes the last finished session in the pprof format.
+    #[cfg(feature = "pprof")]
+    fn pprof(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        match this.finished.as_ref().map(ProfilingResult::to_pprof) {
+            Some(profile) => {
+                pack::push_bytes(state, &profile);
+
+                1
+            }
+            None => Error::NotFinished.raise(state, "no finished session to encode"),
+        }
+    }
+
+    // profiler:save(path, format[, cost]): writes the last finished session to the file in the
+    // given format. The cost is passed to the exporter, if it takes one. Returns true, or nil and
+    // the error message if the file can't be written.
+    fn save(state: &mut State) -> i32 {
+        const FORMATS: &[&str] = &["text", "serialized", "packed", "dot", "csv"];
+
+        let path = check_string_lossy(state, 2);
+        let format = check_string_lossy(state, 3);
+
+        let format = match FORMATS.iter().position(|&v| v == format) {
+            Some(i) => i,
+            None => {
+                let msg = format!("format must be one of: {}", FORMATS.join(", "));
+                state.arg_error(3, &msg);
+                unreachable!()
+            }
+        };
+
+        let cost = Cost::check(state, 4, Cost::Inclusive);
+
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let data = match this.finished.as_ref() {
+            Some(result) => match format {
+                0 => result.report().into_bytes(),
+                1 => result.serialize().into_bytes(),
+                2 => result.pack(),
+                3 => result.to_dot(cost).into_bytes(),
+                _ => result.to_csv().into_bytes(),
+            },
+            None => Error::NotFinished.raise(state, "no finished session to save"),
+        };
+
+        match fs::write(&path, data) {
+            Ok(()) => {
+                state.push(true);
+
+                1
+            }
+            Err(e) => {
+                state.push_nil();
+                state.push(format!("{}: {}", path, e));
+
+                2
+            }
+        }
+    }
+
+    // profiler:result([t[, fill]]): builds the result table of the last finished session again,
+    // or fills `t` with it. `fill` is "clear" (the default) or "append".
+    fn result(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let result = match this.finished.clone() {
+            Some(result) => result,
+            None => Error::NotFinished.raise(state, "no finished session"),
+        };
+
+        let target = if state.is_none_or_nil(2) {
+            None
+        } else {
+            state.check_type(2, lua::Type::Table);
+            let fill = match state.check_option(3, Some("clear"), &["clear", "append"]) {
+                0 => Fill::Clear,
+                _ => Fill::Append,
+            };
+
+            Some((2, fill))
+        };
+
+        result.move_to_lua(state, target)
+    }
+
+    // profiler:iter(): returns an iterator over the entry tables of the last finished session,
+    // which creates them one at a time.
+    fn iter(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let keys = match this.finished {
+            Some(ref result) => result
+                .ordered_entries()
+                .into_iter()
+                .map(|(k, _)| k.id())
+                .collect::<Vec<_>>(),
+            None => Error::NotFinished.raise(state, "no finished session to iterate over"),
+        };
+        let generation = this.generation;
+
+        state.push_value(1);
+        state.push(0);
+
+        // Stack: profiler 0 -> profiler 0 keys
+        state.create_table(keys.len() as i32, 0);
+
+        for (i, k) in keys.iter().enumerate() {
+            state.push(k.as_str());
+            state.seti(-2, i as i64 + 1);
+        }
+
+        drop(keys);
+        state.push(generation);
+        state.push_closure(lua_func!(Self::iter_next), 4);
+
+        1
+    }
+
+    // The upvalues are the profiler, the number of entries returned so far, the array of the ids
+    // of the entries, in the order of the result table, taken when the iterator was created, and
+    // the generation of the profiler at that time.
+    fn iter_next(state: &mut State) -> i32 {
+        let mut i = state.to_integerx(ffi::lua_upvalueindex(2)).unwrap_or(0);
+        let generation = state.to_integerx(ffi::lua_upvalueindex(4));
+
+        // the entry is copied, since pushing it allocates, and a finalizer run by the collector
+        // could reset the profiler or finish another session meanwhile
+        let (key, name, format, entry) = loop {
+            state.geti(ffi::lua_upvalueindex(3), i + 1);
+            let key = state.to_str(-1).and_then(FunctionKey::from_id);
+            state.pop(1);
+            i += 1;
+
+            let key = match key {
+                Some(key) => key,
+                None => return 0,
+            };
+
+            // Safety: the first upvalue is the profiler
+            let this: &mut ManuallyDrop<Self> =
+                unsafe { state.to_userdata_typed(ffi::lua_upvalueindex(1)).unwrap() };
+
+            // the iteration ends once another session starts or the profiler is reset
+            if generation != Some(this.generation) {
+                return 0;
+            }
+
+            if let Some(ref result) = this.finished {
+                if let Some(entry) = result.data.get(&key) {
+                    break (
+                        key,
+                        result.name_of(key),
+                        result.entry_format(),
+                        entry.clone(),
+                    );
+                }
+            }
+        };
+
+        format.push(state, key, name, &entry);
+
+        state.push(i);
+        state.replace(ffi::lua_upvalueindex(2));
+
+        1
+    }
+
+    // profiler:stats(): describes the distribution of the session times, which are the durations
+    // of the calls of the function if the profiler was returned by `wrap`.
+    fn stats(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let latencies = match this.finished {
+            Some(ref result) => result.latencies.clone(),
+            None => Error::NotFinished.raise(state, "no finished session"),
+        };
+
+        push_latencies(state, &latencies);
+
+        1
+    }
+
+    // profiler:reset(): discards the result of the last finished session.
+    fn reset(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        if this.result.is_some() {
+            Error::AlreadyRunning.raise(state, "cannot reset a running profiler");
+        }
+
+        this.finished = None;
+        this.generation = this.generation.wrapping_add(1);
+
+        0
+    }
+
+    // profiler:setMetadata(metadata): replaces the metadata of the next sessions, and of the running
+    // one. `nil` removes it.
+    fn set_metadata(state: &mut State) -> i32 {
+        state.check_userdata(1, Self::TYPE_NAME);
+
+        let metadata = if state.is_none_or_nil(2) {
+            BTreeMap::new()
+        } else {
+            state.push_value(2);
+            MetaValue::read_table(state, 2, "metadata")
+        };
+
+        // Safety: checked above
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(1).unwrap() };
+
+        if let Some(result) = this.result.as_mut() {
+            result.metadata = metadata.clone();
+        }
+
+        this.config.metadata = metadata;
+
+        0
+    }
+
+    // profiler:resetTimings(): discards the result of the last finished session, but keeps the
+    // names of its functions for the next sessions.
+    fn reset_timings(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        if this.result.is_some() {
+            Error::AlreadyRunning.raise(state, "cannot reset a running profiler");
+        }
+
+        this.generation = this.generation.wrapping_add(1);
+
+        if let Some(finished) = this.finished.take() {
+            let custom_keys = finished.custom_keys;
+            let names = finished
+                .data
+                .into_iter()
+                // the keys of the custom entries are only meaningful within a session
+                .filter(|(k, _)| !custom_keys.values().any(|v| v == k))
+                .filter_map(|(k, v)| v.name.map(|name| (k, name)));
+
+            this.names.extend(names);
+        }
+
+        this.stack.clear();
+
+        0
+    }
+
+    // profiler:dot([cost]): renders the call graph of the last finished session for Graphviz,
+    // weighing the calls by the total time unless `cost` is "self".
+    fn dot(state: &mut State) -> i32 {
+        let cost = Cost::check(state, 2, Cost::Inclusive);
+
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        match this.finished.as_ref().map(|result| result.to_dot(cost)) {
+            Some(dot) => {
+                state.push(dot);
+
+                1
+            }
+            None => Error::NotFinished.raise(state, "no finished session to render"),
+        }
+    }
+
+    // profiler:csv(): renders the entries of the last finished session as CSV.
+    fn csv(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        match this.finished.as_ref().map(|result| result.to_csv()) {
+            Some(csv) => {
+                state.push(csv);
+
+                1
+            }
+            None => Error::NotFinished.raise(state, "no finished session to render"),
+        }
+    }
+
+    // profiler:tree(): renders the call tree of the last finished session as indented text.
+    fn tree(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        match this.finished.as_ref().map(|result| result.to_tree_text()) {
+            Some(tree) => {
+                state.push(tree);
+
+                1
+            }
+            None => Error::NotFinished.raise(state, "no finished session to render"),
+        }
+    }
+
+    // profiler:collapseRecursion(): describes the cycles of the call graph of the last finished
+    // session, each as a single entry, the slowest first.
+    fn collapse_recursion(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        // read before pushing anything, since the result borrows the state
+        let cycles = match this.finished.as_ref() {
+            Some(result) => result
+                .cycles()
+                .into_iter()
+                .map(|cycle| {
+                    let members = cycle
+                        .members
+                        .iter()
+                        .map(|&key| (key.id(), result.name_of(key)))
+                        .collect::<Vec<_>>();
+                    let total_time = if result.self_only {
+                        None
+                    } else {
+                        Some(result.seconds(cycle.total_time))
+                    };
+                    let total_self_time = result.seconds(cycle.total_self_time);
+
+                    (cycle, members, total_time, total_self_time)
+                })
+                .collect::<Vec<_>>(),
+            None => Error::NotFinished.raise(state, "no finished session"),
+        };
+
+        state.create_table(cycles.len() as i32, 0);
+
+        for (i, (cycle, members, total_time, total_self_time)) in cycles.into_iter().enumerate() {
+            state.create_table(0, 6);
+
+            state.push("name");
+            state.push(format!("<cycle {}>", i + 1));
+            state.set_table(-3);
+
+            state.push("members");
+            state.create_table(members.len() as i32, 0);
+
+            for (j, (id, name)) in members.into_iter().enumerate() {
+                state.create_table(0, 2);
+
+                state.push("id");
+                state.push(id);
+                state.set_table(-3);
+
+                state.push("name");
+                state.push(name);
+                state.set_table(-3);
+
+                state.seti(-2, (j + 1) as i64);
+            }
+
+            state.set_table(-3);
+
+            state.push("calls");
+            state.push(clamped(cycle.calls));
+            state.set_table(-3);
+
+            state.push("recursiveCalls");
+            state.push(clamped(cycle.recursive_calls));
+            state.set_table(-3);
+
+            if let Some(total_time) = total_time {
+                state.push("totalTime");
+                state.push(total_time);
+                state.set_table(-3);
+            }
+
+            state.push("totalSelfTime");
+            state.push(total_self_time);
+            state.set_table(-3);
+
+            state.seti(-2, (i + 1) as i64);
+        }
+
+        1
+    }
+
+    // profiler:subtreeTime(nameOrId): returns the time the functions with the name or id spent
+    // running, including the calls they made, in the last finished session. If there's no such
+    // function, returns nil and the error.
+    fn subtree_time(state: &mut State) -> i32 {
+        let s = check_string_lossy(state, 2);
+
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let time = this.finished.as_ref().map(|result| {
+            let keys = result.find_keys(&s);
+
+            if keys.is_empty() {
+                None
+            } else {
+                Some(result.seconds(result.subtree_time(&keys)))
+            }
+        });
+
+        match time {
+            Some(Some(time)) => {
+                state.push(time);
+
+                1
+            }
+            Some(None) => {
+                state.push_nil();
+                Error::NotFound.push(state, &format!("no function named '{}'", s));
+
+                2
+            }
+            None => Error::NotFinished.raise(state, "no finished session"),
+        }
+    }
+
+    // lprofile.ignore(f, ...): calls `f` with the arguments and returns its results. If a session
+    // is running, the call is left out of it: the hook skips the events it triggers, like the ones
+    // of the callbacks, and its time is charged to the `<ignored>` entry instead of the caller.
+    fn ignore(state: &mut State) -> i32 {
+        state.check_type(1, lua::Type::Function);
+        let nargs = state.get_top() - 1;
+
+        // nested calls and the calls from the callbacks are already ignored
+        let start = if Self::get_from_registry(state) {
+            // Safety: the check above
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+            let this: &mut Self = &mut **this;
+            let now = this.now();
+
+            let start = match (this.in_callback, this.result.as_mut()) {
+                (false, Some(result)) => {
+                    if let (Some(last), false) = (this.stack.last_mut(), result.is_sampled()) {
+                        last.suspend(result, now);
+                    }
+
+                    this.in_callback = true;
+
+                    Some(now)
+                }
+                _ => None,
+            };
+
+            state.pop(1);
+
+            start
+        } else {
+            None
+        };
+
+        let status = state.pcall(nargs, lua::MULTRET, 0);
+
+        if let (Some(start), true) = (start, Self::get_from_registry(state)) {
+            // Safety: the check above
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+            let this: &mut Self = &mut **this;
+            let now = this.now();
+            this.in_callback = false;
+
+            if let Some(result) = this.result.as_mut() {
+                let elapsed = result.elapsed(start, now);
+                result.saturated |= result.ignored_calls.add_saturating(1)
+                    | result.ignored_time.add_saturating(elapsed);
+            }
+
+            if let Some(last) = this.stack.last_mut() {
+                last.resume(now);
+            }
+
+            state.pop(1);
+        }
+
+        if status.is_err() {
+            state.error();
+        }
+
+        state.get_top()
+    }
+
+    // lprofile.profileFile(path, ...): loads the file and profiles its main chunk with the given
+    // arguments. If the file cannot be loaded, returns nil and the error message.
+    fn profile_file(state: &mut State) -> i32 {
+        let path = check_string_lossy(state, 1);
+
+        if state.load_file(&path).is_err() {
+            let message = to_string_lossy(state, -1).unwrap_or_default();
+            state.pop(1);
+            state.push_nil();
+            Error::LoadFailed.push(state, &message);
+
+            return 2;
+        }
+
+        // Stack: path args... chunk -> Self chunk args...
+        state.replace(1);
+        Self::push(state, Self::with_config(Config::default()));
+        state.insert(1);
+
+        Self::run(state)
+    }
+
+    // lprofile.profileString(source[, chunkname], ...): loads the source and profiles the chunk
+    // with the given arguments. The chunk name defaults to the source, as with `load`. If the
+    // source cannot be loaded, returns nil and the error message.
+    fn profile_string(state: &mut State) -> i32 {
+        if !state.is_string(1) {
+            state.check_type(1, lua::Type::String);
+        }
+
+        let source = state.to_bytes(1).unwrap().to_vec();
+        let chunk_name = if state.is_none_or_nil(2) {
+            String::from_utf8_lossy(&source).into_owned()
+        } else {
+            check_string_lossy(state, 2)
+        };
+
+        // the chunk name has a slot even if it's omitted
+        if state.get_top() < 2 {
+            state.set_top(2);
+        }
+
+        if state.load_bufferx(&source, &chunk_name, "bt").is_err() {
+            let message = to_string_lossy(state, -1).unwrap_or_default();
+            state.pop(1);
+            state.push_nil();
+            Error::LoadFailed.push(state, &message);
+
+            return 2;
+        }
+
+        // Stack: source chunkname args... chunk -> Self chunk args...
+        state.replace(1);
+        state.remove(2);
+        Self::push(state, Self::with_config(Config::default()));
+        state.insert(1);
+
+        Self::run(state)
+    }
+
+    fn get_from_registry(state: &mut State) -> bool {
+        let result = match state.raw_getp(lua::REGISTRYINDEX, &OPAQUE_REGISTRY_KEY) {
+            lua::Type::Userdata => !state.test_userdata(-1, Self::TYPE_NAME).is_null(),
+            _ => false,
+        };
+
+        if !result {
+            state.pop(1);
+        }
+
+        result
+    }
+
+    fn gc(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua unless violated with debug.getmetatable, which is irrelevant.
+        let references = unsafe {
+            let this: &mut ManuallyDrop<Self> = state.check_userdata_typed(1, Self::TYPE_NAME);
+            let references = [
+                this.config.stream,
+                this.config.key_fn,
+                this.config.on_new_function,
+                this.config.resolve_name,
+            ];
+            ManuallyDrop::drop(this);
+
+            references
+        };
+
+        for reference in references.iter().flatten() {
+            state.unreference(lua::REGISTRYINDEX, *reference);
+        }
+
+        state.pop(1);
+
+        0
+    }
+
+    // The number of instructions between the count events the profiler needs, or 0 if it doesn't.
+    fn own_count(config: &Config) -> c_int {
+        if config.count_instructions {
+            1
+        } else if let Some(interval) = config.sample_interval {
+            interval
+        } else if config.deadline.is_some()
+            || config.progress.is_some()
+            || config.flush_interval().is_some()
+        {
+            // loops without calls must be interrupted as well
+            Self::CLOCK_CHECK_INTERVAL as c_int
+        } else {
+            0
+        }
+    }
+
+    // Installs the profiler's hook, and returns the previous one, to be restored after the
+    // session, along with the hook to forward the events to, if chaining.
+    fn set_hook(
+        state: &mut State,
+        config: &Config,
+    ) -> ((Hook, HookMask, c_int), Option<ChainedHook>) {
+        let prev = (
             state.get_hook(),
             state.get_hook_mask(),
             state.get_hook_count(),
         );
 
-        let mut mask = HookMask::empty();
-        mask.insert(lua::MASKRET);
-        mask.insert(lua::MASKCALL);
+        let chained = match prev.0 {
+            Some(hook) if config.chain_hooks => Some(ChainedHook::new(hook, prev.1, prev.2)),
+            _ => None,
+        };
+
+        let mut mask = HookMask::empty();
+        mask.insert(lua::MASKCALL);
+
+        // the returns are only needed to time the calls
+        if !config.calls_only {
+            mask.insert(lua::MASKRET);
+        }
+
+        let mut count = Self::own_count(config);
+
+        // a count that all of them can be derived from
+        let combine = |ours, theirs| match (ours, theirs) {
+            (0, theirs) => theirs,
+            (ours, 0) => ours,
+            (ours, theirs) => gcd(ours, theirs),
+        };
+
+        if let Some(ref chained) = chained {
+            // the events the chained hook didn't ask for are filtered out by `forward_event`
+            mask.insert(chained.mask);
+            count = combine(count, chained.count);
+        }
+
+        // the limit is then reached exactly on a count event
+        if let Some(limit) = config.instruction_limit {
+            count = combine(count, limit);
+        }
+
+        if count > 0 {
+            mask.insert(lua::MASKCOUNT);
+        } else {
+            mask.remove(lua::MASKCOUNT);
+        }
+
+        state.set_hook(Some(Self::hook), mask, count);
+
+        (prev, chained)
+    }
+
+    fn unset_hook(state: &mut State, prev: (Hook, HookMask, c_int)) {
+        state.set_hook(prev.0, prev.1, prev.2);
+    }
+
+    extern "C" fn hook(l: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
+        // a panic must not unwind into Lua
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| Self::handle_event(l, ar)));
+
+        let forward = match outcome {
+            Ok(forward) => forward,
+            Err(payload) => {
+                Self::abandon(l, payload);
+
+                None
+            }
+        };
+
+        // A Lua error is a longjmp, which would skip the destructors of the values of the Rust
+        // frames it crossed, so the errors of the event, like the deadline or the ones of the
+        // callbacks, are raised here, once all of them have returned. Nothing here needs dropping.
+        if Self::take_deferred_error(l) {
+            // Safety: guaranteed by Lua
+            unsafe { ffi::lua_error(l) };
+        }
+
+        // the chained hook may raise an error as well, so it's only called now, after the
+        // profiler has handled the event
+        if let Some(hook) = forward {
+            hook(l, ar);
+        }
+    }
+
+    // Pops the error value at the top of the stack, to be raised when the hook returns. Only the
+    // first error of an event is kept.
+    fn defer_error(state: &mut State) {
+        if state.raw_getp(lua::REGISTRYINDEX, &DEFERRED_ERROR_REGISTRY_KEY) != lua::Type::Nil {
+            state.pop(2);
+
+            return;
+        }
+
+        state.pop(1);
+        state.raw_setp(lua::REGISTRYINDEX, &DEFERRED_ERROR_REGISTRY_KEY);
+    }
+
+    // Pushes the error deferred during the event and clears it. Returns false if there's none.
+    fn take_deferred_error(l: *mut ffi::lua_State) -> bool {
+        // Safety: guaranteed by Lua
+        let state = unsafe { &mut State::from_ptr(l) };
+
+        if state.raw_getp(lua::REGISTRYINDEX, &DEFERRED_ERROR_REGISTRY_KEY) == lua::Type::Nil {
+            state.pop(1);
+
+            return false;
+        }
+
+        state.push_nil();
+        state.raw_setp(lua::REGISTRYINDEX, &DEFERRED_ERROR_REGISTRY_KEY);
+
+        true
+    }
+
+    // Stops profiling after a panic in the hook. The data is kept, but may be inconsistent, so the
+    // session ends with a warning. Lua restores the stack after the hook returns, so whatever the
+    // hook left there doesn't matter.
+    fn abandon(l: *mut ffi::lua_State, payload: Box<dyn Any + Send>) {
+        // Safety: guaranteed by Lua
+        let state = unsafe { &mut State::from_ptr(l) };
+
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => (*message).to_owned(),
+            None => payload
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_else(|| "unknown error".to_owned()),
+        };
+
+        state.set_hook(None, HookMask::empty(), 0);
+
+        if Self::get_from_registry(state) {
+            // Safety: the check above
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+            this.failed = true;
+
+            if let Some(result) = this.result.as_mut() {
+                result.abandoned = true;
+            }
+
+            // the chained hook keeps running on its own
+            if let Some(chained) = this.chained.take() {
+                state.set_hook(Some(chained.hook), chained.mask, chained.count);
+            }
+
+            // Safety: the check above
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+
+            if let Some(result) = this.result.as_mut() {
+                result.warnings.push(format!(
+                    "the profiler failed and stopped early: {}; please report this",
+                    message
+                ));
+            }
+
+            state.pop(1);
+        }
+    }
+
+    // Handles the event, and returns the chained hook if the event is to be forwarded to it.
+    fn handle_event(state: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) -> Hook {
+        // Safety: guaranteed by Lua
+        let ar = unsafe { ar.as_mut().unwrap() };
+        let state = unsafe { &mut State::from_ptr(state) };
+
+        let (handled, forward) = Self::forward_event(state, ar);
+
+        if Self::check_instructions(state, ar) {
+            let msg = "the profiling session exceeded the instruction limit";
+            Error::InstructionLimitExceeded.push(state, msg);
+            Self::defer_error(state);
+
+            return forward;
+        }
+
+        if !handled || !Self::accepts_events(state) {
+            return forward;
+        }
+
+        let event = match HookEvent::of(ar) {
+            Some(event) => event,
+            // panicking here would unwind into Lua
+            None => return forward,
+        };
+
+        if Self::counts_calls_only(state) {
+            // the return events come from the chained hook's mask
+            if event.is_call() {
+                Self::count_call(state, ar, event);
+            }
+
+            return forward;
+        }
+
+        if Self::check_clock(state) {
+            let msg = "the profiling session exceeded the deadline";
+            Error::DeadlineExceeded.push(state, msg);
+            Self::defer_error(state);
+
+            return forward;
+        }
+
+        match event {
+            event if event.is_call() => Self::call_event(state, ar, event),
+            event if event.is_return() => Self::return_event(state, ar, event),
+            HookEvent::Count => Self::count_event(state, ar),
+            // line events are never requested
+            _ => return forward,
+        }
+
+        Self::end_event(state);
+
+        forward
+    }
+
+    // Returns whether the profiler asked for the event, and the chained hook if it asked for it
+    // too. The hook isn't called here, since an error it raises must not cross the Rust frames.
+    fn forward_event(state: &mut State, ar: &ffi::lua_Debug) -> (bool, Hook) {
+        if !Self::get_from_registry(state) {
+            return (false, None);
+        }
+
+        // Safety: the check above
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+        let this: &mut Self = &mut **this;
+
+        let (handled, forwarded) = match ar.event {
+            ffi::LUA_HOOKCOUNT => {
+                let handled = this.own_count > 0 && {
+                    this.count_ticks += this.hook_count;
+
+                    if this.count_ticks >= this.own_count {
+                        this.count_ticks = 0;
+                    }
+
+                    this.count_ticks == 0
+                };
+
+                let forwarded = match this.chained.as_mut() {
+                    Some(chained) => chained.count_event(this.hook_count),
+                    None => false,
+                };
+
+                (handled, forwarded)
+            }
+            _ => {
+                let mask = match HookEvent::of(ar) {
+                    Some(event) if event.is_call() => lua::MASKCALL,
+                    Some(event) if event.is_return() => lua::MASKRET,
+                    _ => lua::MASKLINE,
+                };
+
+                // line events are never requested by the profiler
+                let handled = mask != lua::MASKLINE;
+                let forwarded =
+                    matches!(this.chained, Some(chained) if chained.mask.contains(mask));
+
+                (handled, forwarded)
+            }
+        };
+
+        let hook = this.chained.map(|chained| chained.hook);
+        state.pop(1);
+
+        (handled, hook.filter(|_| forwarded))
+    }
+
+    // Counts the instructions of a count event, and returns whether the `instructionLimit` is
+    // reached. They're counted even while the profiling is paused, as the limit guards the
+    // program rather than the profile.
+    fn check_instructions(state: &mut State, ar: &mut ffi::lua_Debug) -> bool {
+        if ar.event != ffi::LUA_HOOKCOUNT || !Self::get_from_registry(state) {
+            return false;
+        }
+
+        // Safety: the check above
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+        let this: &mut Self = &mut **this;
+
+        let exceeded = match this.config.instruction_limit {
+            Some(limit) => {
+                this.instructions = this.instructions.saturating_add(this.hook_count as u64);
+                this.instructions >= limit as u64
+            }
+            None => false,
+        };
+
+        if let (true, Some(result)) = (exceeded, this.result.as_mut()) {
+            result.instruction_limit_exceeded = true;
+        }
+
+        state.pop(1);
+
+        exceeded
+    }
+
+    // Lets the allocator know which function is running now, and adds up the time the event took
+    // if it was measured.
+    fn end_event(state: &mut State) {
+        Self::get_from_registry(state);
+        // Safety: checked by accepts_events
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+
+        if let Some(ref memory) = this.memory {
+            memory.current.set(this.stack.last().map(|frame| frame.key));
+        }
+
+        let clock = this.clock;
+
+        if let (Some(start), Some(result)) = (this.overhead_start.take(), this.result.as_mut()) {
+            let elapsed = clock.now().saturating_duration_since(start);
+            result.saturated |= result.hook_time.add_saturating(elapsed);
+        }
+
+        if let Some(start) = this.event_start.take() {
+            let elapsed = clock.now().saturating_duration_since(start);
+            this.hidden_time = this.hidden_time.saturating_add(elapsed);
+
+            if let Some(result) = this.result.as_mut() {
+                result.saturated |= result.hook_body_time.add_saturating(elapsed)
+                    | result.hook_body_events.add_saturating(1);
+            }
+        }
+
+        state.pop(1);
+    }
+
+    // Prints the progress line if it's due, and returns whether the deadline is exceeded. Reading
+    // the clock on every event is too costly, so it's only read every few events.
+    fn check_clock(state: &mut State) -> bool {
+        Self::get_from_registry(state);
+        // Safety: checked by accepts_events
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+
+        // read first, so that as much of the hook's work as possible is left out of the frames
+        if this.config.hook_time != HookTime::Included {
+            this.event_start = Some(this.clock.now());
+        }
+
+        // the time the hook takes is measured every few events to estimate the overhead; if the
+        // event ends with an error, the measurement is dropped
+        this.overhead_ticks += 1;
+        this.overhead_start = if this.overhead_ticks >= Self::OVERHEAD_SAMPLE_INTERVAL {
+            this.overhead_ticks = 0;
+            Some(this.clock.now())
+        } else {
+            None
+        };
+
+        let flush_interval = this.config.flush_interval();
+        let timed = this.config.deadline.is_some()
+            || this.config.progress.is_some()
+            || flush_interval.is_some();
+
+        let elapsed = match this.session_start {
+            Some(start) if timed => {
+                this.clock_ticks += 1;
+
+                if this.clock_ticks >= Self::CLOCK_CHECK_INTERVAL {
+                    this.clock_ticks = 0;
+                    Some(this.clock.now().saturating_duration_since(start))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let exceeded = match elapsed {
+            Some(elapsed) => {
+                if let (Some(interval), true) =
+                    (this.config.progress, elapsed >= this.next_progress)
+                {
+                    this.print_progress(elapsed);
+                    this.next_progress = elapsed + interval;
+                }
+
+                if let (Some(interval), true) = (flush_interval, elapsed >= this.next_flush) {
+                    this.flush();
+                    this.next_flush = elapsed + interval;
+                }
+
+                matches!(this.config.deadline, Some(deadline) if elapsed > deadline)
+            }
+            None => false,
+        };
+
+        if let Some(events) = this.config.flush_events {
+            this.flush_ticks += 1;
+
+            if this.flush_ticks >= events {
+                this.flush_ticks = 0;
+                this.flush();
+            }
+        }
+
+        if let (true, Some(result)) = (exceeded, this.result.as_mut()) {
+            result.deadline_exceeded = true;
+        }
+
+        state.pop(1);
+
+        exceeded
+    }
+
+    // Appends a snapshot of the data collected so far to the `flushFile`. The hook has nowhere to
+    // report the errors to, so the first one is added to the warnings.
+    fn flush(&mut self) {
+        let (path, snapshot) = match (&self.config.flush_file, self.snapshot()) {
+            (Some(path), Some(snapshot)) => (path, snapshot),
+            _ => return,
+        };
+
+        if let (Err(e), false) = (append_snapshot(path, &snapshot), self.flush_failed) {
+            self.flush_failed = true;
+            let warning = format!("could not write the snapshot to {}: {}", path, e);
+            self.result.as_mut().unwrap().warnings.push(warning);
+        }
+    }
+
+    // Writes a line about the running session to stderr, leaving stdout to the program.
+    fn print_progress(&self, elapsed: Duration) {
+        let functions = self.result.as_ref().map_or(0, |result| result.data.len());
+
+        // there's nowhere to report the failure to write
+        let _ = writeln!(
+            io::stderr(),
+            "lprofile: {:.1} s elapsed, {} functions seen, stack depth {}",
+            elapsed.as_secs_f64(),
+            functions,
+            self.stack.len(),
+        );
+    }
+
+    // Lua does not run hooks while a hook is running, but coroutines resumed by a callback
+    // inherit the hook and would corrupt the stack.
+    fn accepts_events(state: &mut State) -> bool {
+        if !Self::get_from_registry(state) {
+            return false;
+        }
+
+        // Safety: the check above
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+        let result = !this.in_callback && !this.failed;
+        state.pop(1);
+
+        result
+    }
+
+    fn counts_calls_only(state: &mut State) -> bool {
+        Self::get_from_registry(state);
+        // Safety: checked by accepts_events
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+        let result = this.config.calls_only;
+        state.pop(1);
+
+        result
+    }
+
+    // Calls a Lua function from the hook, like `pcall`. The events triggered by the call are
+    // ignored. The errors are caught so that the guard is always cleared; the caller is
+    // responsible for rethrowing them.
+    fn call_from_hook(state: &mut State, nargs: c_int, nresults: c_int) -> ThreadStatus {
+        Self::set_in_callback(state, true);
+        let status = state.pcall(nargs, nresults, 0);
+        Self::set_in_callback(state, false);
+
+        status
+    }
+
+    fn set_in_callback(state: &mut State, value: bool) {
+        if Self::get_from_registry(state) {
+            // Safety: the check above
+            let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+            this.in_callback = value;
+            state.pop(1);
+        }
+    }
+
+    // The number of frames on the stack, the running function's included, so that the first
+    // function a coroutine runs is at level 1.
+    fn get_stack_level(state: &mut State) -> usize {
+        let mut level = 1;
+
+        loop {
+            if state.get_stack(level).is_none() {
+                return level as usize;
+            }
+
+            level += 1;
+        }
+    }
+
+    // This function makes sure the call levels are non-descreasing in the stack. `error` may break
+    // the profiler otherwise.
+    fn set_stack_to(&mut self, level: usize, now: Instant) {
+        self.close_frames_above(level, true, now);
+    }
+
+    // Closes the frames above `level`, which have not received a return event. Unless `unwound`
+    // is false, they are counted as errors: Lua only skips the event when an error unwinds the
+    // stack.
+    fn close_frames_above(&mut self, level: usize, unwound: bool, now: Instant) {
+        while let Some(v) = self.stack.last() {
+            if v.level <= level {
+                // the new frame is not below this entry in the stack
+                return;
+            }
+
+            // this frame was closed, but the hook was not notified (the stack was unwound). The
+            // moment of the error is unknown, so the frame ends at the event that revealed it,
+            // which is the earliest thing known to happen after it: the time in between, spent
+            // unwinding and in the code that caught the error, is charged to the innermost frame.
+            let mut v = self.stack.pop().unwrap();
+            v.resume(now);
+            self.notify_exit(v.key, now);
+
+            let result = self.result.as_mut().unwrap();
+            v.close(result, now);
+
+            // there are no entries when streaming
+            if let (true, Some(entry)) = (unwound, result.data.get_mut(&v.key)) {
+                result.saturated |= entry.errors.add_saturating(1);
+            }
+        }
+    }
+
+    // Takes a raw pointer so that it can be called while the profiler is borrowed from the stack:
+    // lua_getinfo with "nS" does not modify the stack.
+    //
+    // Safety: `l` must be a valid Lua state.
+    unsafe fn determine_name_for(
+        l: *mut ffi::lua_State,
+        ar: &mut lua_Debug,
+    ) -> Option<FunctionName> {
+        match ffi::lua_getinfo(l, b"nS\0".as_ptr() as *const _, ar) {
+            0 => None,
+            // the prescribed requirement is fulfilled
+            _ => Some(FunctionName::fill_from(ar)),
+        }
+    }
+
+    // Like `determine_name_for`, but also reads the names of the upvalues of a Lua function if
+    // they're asked for.
+    //
+    // Safety: `l` must be a valid Lua state running a hook, and `ar` its activation record.
+    unsafe fn name_with_upvalues(
+        l: *mut ffi::lua_State,
+        ar: &mut lua_Debug,
+        config: &Config,
+    ) -> Option<FunctionName> {
+        let mut name = Self::determine_name_for(l, ar)?;
+
+        // the upvalues of C functions have no names
+        if !config.upvalue_names || name.domain == "C" {
+            return Some(name);
+        }
+
+        let mut upvalues = Vec::new();
+        ffi::lua_getinfo(l, b"f\0".as_ptr() as *const _, ar);
+
+        loop {
+            let upvalue = ffi::lua_getupvalue(l, -1, upvalues.len() as c_int + 1);
+
+            if upvalue.is_null() {
+                break;
+            }
+
+            ffi::lua_pop(l, 1);
+            upvalues.push(CStr::from_ptr(upvalue).to_string_lossy().into_owned());
+        }
+
+        ffi::lua_pop(l, 1);
+        name.upvalues = Some(upvalues);
+
+        Some(name)
+    }
+
+    // Checks whether the source of the function that triggered the hook starts with one of the
+    // prefixes, after being made relative to `root`.
+    //
+    // Safety: `l` must be a valid Lua state running a hook, and `ar` its activation record.
+    unsafe fn source_matches(
+        l: *mut ffi::lua_State,
+        ar: &mut lua_Debug,
+        prefixes: &[String],
+        root: Option<&str>,
+    ) -> bool {
+        let mut name = match Self::determine_name_for(l, ar) {
+            Some(name) => name,
+            None => return false,
+        };
+
+        if let Some(root) = root {
+            name.relativize(root);
+        }
+
+        prefixes
+            .iter()
+            .any(|prefix| name.source.starts_with(prefix.as_str()))
+    }
+
+    // Returns the kind of metamethod the function that triggered the hook is invoked as, if it is.
+    //
+    // Safety: `l` must be a valid Lua state running a hook, and `ar` its activation record.
+    unsafe fn metamethod(l: *mut ffi::lua_State, ar: &mut lua_Debug) -> Option<&'static str> {
+        ffi::lua_getinfo(l, b"n\0".as_ptr() as *const _, ar);
+
+        if ar.name.is_null() || CStr::from_ptr(ar.namewhat).to_bytes() != b"metamethod" {
+            return None;
+        }
+
+        let name = metamethod_name(&CStr::from_ptr(ar.name).to_string_lossy());
+
+        METAMETHODS.iter().copied().find(|&kind| kind == name)
+    }
+
+    // Counts the arguments of the function that triggered the call hook. A C function gets the
+    // values on its stack, of which the hook pushed `pushed`. A Lua function has already dropped
+    // the extra arguments and filled the missing ones with nil, so it gets its parameters and the
+    // varargs it received. Lua 5.1 doesn't report the parameters, so they can't be counted
+    // there.
+    //
+    // Safety: `l` must be a valid Lua state running a call hook, and `ar` its activation record.
+    unsafe fn arg_count(
+        l: *mut ffi::lua_State,
+        ar: &mut lua_Debug,
+        pushed: c_int,
+    ) -> Option<usize> {
+        let top = ffi::lua_gettop(l) - pushed;
+
+        ffi::lua_getinfo(l, b"fu\0".as_ptr() as *const _, ar);
+        let c_function = ffi::lua_iscfunction(l, -1) != 0;
+        ffi::lua_pop(l, 1);
+
+        if c_function {
+            return Some(top.max(0) as usize);
+        }
+
+        let (nparams, vararg) = version::parameters(ar)?;
+        let mut varargs = 0;
+
+        if vararg {
+            // the varargs are the locals with the negative indices
+            while !ffi::lua_getlocal(l, ar, -(varargs + 1)).is_null() {
+                ffi::lua_pop(l, 1);
+                varargs += 1;
+            }
+        }
+
+        Some(nparams as usize + varargs as usize)
+    }
+
+    // Returns the line the caller of the function that triggered the hook is running.
+    //
+    // Safety: `l` must be a valid Lua state running a hook.
+    unsafe fn caller_line(l: *mut ffi::lua_State) -> Option<usize> {
+        let mut ar: lua_Debug = std::mem::zeroed();
+
+        if ffi::lua_getstack(l, 1, &mut ar) == 0 {
+            return None;
+        }
+
+        ffi::lua_getinfo(l, b"l\0".as_ptr() as *const _, &mut ar);
+
+        // C functions have no lines
+        match ar.currentline {
+            -1 => None,
+            line => Some(line as usize),
+        }
+    }
+
+    // Returns the size of the function that triggered the hook when dumped without debug
+    // information, or `None` for C functions, which can't be dumped.
+    //
+    // Safety: `l` must be a valid Lua state running a hook, and `ar` its activation record.
+    unsafe fn code_size(l: *mut ffi::lua_State, ar: &mut lua_Debug) -> Option<usize> {
+        unsafe extern "C" fn count(
+            _: *mut ffi::lua_State,
+            _: *const c_void,
+            size: size_t,
+            ud: *mut c_void,
+        ) -> c_int {
+            *(ud as *mut usize) += size;
+
+            0
+        }
+
+        ffi::lua_getinfo(l, b"f\0".as_ptr() as *const _, ar);
+
+        let mut size = 0usize;
+        let dumped = ffi::lua_iscfunction(l, -1) == 0
+            && ffi::lua_dump(l, Some(count), &mut size as *mut usize as *mut c_void, 1) == 0;
+
+        ffi::lua_pop(l, 1);
+
+        if dumped {
+            Some(size)
+        } else {
+            None
+        }
+    }
+
+    // Counts a call with `Config::calls_only`: there are no frames, and the clock isn't read.
+    fn count_call(state: &mut State, ar: &mut ffi::lua_Debug, event: HookEvent) {
+        // Safety: the activation record is passed to the hook
+        let key = match unsafe { FunctionKey::from_ar(state, ar) } {
+            Some(key) => key,
+            None => return,
+        };
+
+        let level = Self::get_stack_level(state);
+        let l = state.as_ptr();
+
+        Self::get_from_registry(state);
+        // Safety: checked by accepts_events
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+        let this: &mut Self = &mut **this;
+        let result = this.result.as_mut().unwrap();
+
+        result.saturated |= if event.is_tail() {
+            result.tail_call_events.add_saturating(1)
+        } else {
+            result.call_events.add_saturating(1)
+        };
+
+        if level <= this.base_level {
+            return;
+        }
+
+        let key = if this.config.merge_c_functions {
+            // Safety: `l` is the state the hook was called with
+            unsafe { result.merged_c_key(l, ar, key) }
+        } else {
+            key
+        };
+        let key = result.tracked_key(key);
+
+        // the first call above the base level is the profiled function's
+        if result.root.is_none() {
+            result.root = Some(key);
+        }
+
+        let mut created = false;
+        let mut saturated = false;
+
+        result
+            .data
+            .entry(key)
+            .and_modify(|entry| saturated = entry.calls.add_saturating(1))
+            .or_insert_with(|| {
+                created = true;
+
+                ProfileEntry::new(None)
+            });
+        result.saturated |= saturated;
+
+        if created && key != FunctionKey::OTHER && !this.config.skip_names {
+            let mut name = match this.names.get(&key) {
+                Some(name) => Some(name.clone()),
+                // Safety: `l` is the state the hook was called with
+                None => unsafe { Self::name_with_upvalues(l, ar, &this.config) },
+            };
 
-        state.set_hook(Some(Self::hook), mask, 0);
+            if let Some(name) = name.as_mut() {
+                if let Some(ref root) = this.config.source_root {
+                    name.relativize(root);
+                }
 
-        prev
-    }
+                name.intern(&mut result.sources);
+            }
 
-    fn unset_hook(state: &mut State, prev: (Hook, HookMask, c_int)) {
-        state.set_hook(prev.0, prev.1, prev.2);
+            result.data.get_mut(&key).unwrap().name = name;
+        }
     }
 
-    extern "C" fn hook(state: *mut ffi::lua_State, ar: *mut ffi::lua_Debug) {
-        // Safety: guaranteed by Lua
-        let ar = unsafe { ar.as_mut().unwrap() };
-        let state = unsafe { &mut State::from_ptr(state) };
+    fn call_event(state: &mut State, ar: &mut ffi::lua_Debug, event: HookEvent) {
+        // Safety: the activation record is passed to the hook
+        let key = match unsafe { FunctionKey::from_ar(state, ar) } {
+            Some(key) => key,
+            // panicking would unwind into Lua; without a frame, the return event is ignored too
+            None => return,
+        };
+
+        let level = Self::get_stack_level(state);
+        let l = state.as_ptr();
+
+        Self::get_from_registry(state);
+        // Safety: the check above
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+        let this: &mut Self = &mut **this;
+        let result = this.result.as_mut().unwrap();
+
+        result.saturated |= if event.is_tail() {
+            result.tail_call_events.add_saturating(1)
+        } else {
+            result.call_events.add_saturating(1)
+        };
 
-        match ar.event {
-            ffi::LUA_HOOKCALL | ffi::LUA_HOOKTAILCALL => Self::call_event(state, ar),
-            ffi::LUA_HOOKRET => Self::return_event(state),
-            _ => unreachable!(),
+        if level <= this.base_level {
+            return;
         }
-    }
 
-    fn get_stack_level(state: &mut State) -> usize {
-        let mut level = 2;
+        // read before anything else is pushed; the profiler is at the top of the stack
+        let arg_count = if this.config.arg_counts {
+            // Safety: `l` is the state the hook was called with
+            unsafe { Self::arg_count(l, ar, 1) }
+        } else {
+            None
+        };
 
-        loop {
-            if state.get_stack(level).is_none() {
-                return (level - 1) as usize;
-            }
+        let now = this.now();
+        let tail_call = event.is_tail();
+        this.enter_level(level, tail_call, now);
 
-            level += 1;
+        // the functions outside of the sources run as part of their caller's body
+        if let Some(ref prefixes) = this.config.sources {
+            let result = this.result.as_mut().unwrap();
+            let root = this.config.source_root.as_deref();
+
+            let matches = *result.source_matches.entry(key).or_insert_with(|| {
+                // Safety: `l` is the state the hook was called with
+                unsafe { Self::source_matches(l, ar, prefixes, root) }
+            });
+
+            if !matches {
+                return;
+            }
         }
-    }
 
-    // This function makes sure the call levels are non-descreasing in the stack. `error` may break
-    // the profiler otherwise.
-    fn set_stack_to(&mut self, level: usize) {
-        while let Some(v) = self.stack.last() {
-            if v.level <= level {
-                // the new frame is not below this entry in the stack
+        this.suspend_caller(now);
+
+        // outside of the targets, nothing is profiled
+        if let (true, Some(targets)) = (this.stack.is_empty(), &this.config.targets) {
+            if !targets.contains(&key) {
                 return;
             }
+        }
 
-            // this frame was closed, but the hook was not notified (the stack was unwound)
-            let mut v = self.stack.pop().unwrap();
-            v.resume();
-            v.close(self.result.as_mut().unwrap());
+        if this.config.stream.is_some() {
+            // the call tree is not built when streaming
+            this.stack.push(CallFrame::new(level, key, 0, 0, now));
+            this.notify_enter(key, now);
+
+            return;
         }
-    }
 
-    fn determine_name_for(state: &mut State, ar: &mut lua_Debug) -> Option<FunctionName> {
-        let what = CString::new("nS").unwrap();
+        let custom = match this.config.key_fn {
+            Some(key_fn) => Self::call_key_fn(state, ar, key_fn),
+            None => None,
+        };
+
+        // the key function leaves the profiler at the top of the stack
+        // Safety: the check above
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+        let this: &mut Self = &mut **this;
+
+        let result = this.result.as_mut().unwrap();
+        let caller = this.stack.last().map(|frame| frame.key);
+        let key = match custom {
+            Some(ref custom) => result.custom_key(custom),
+            // Safety: `l` is the state the hook was called with
+            None if this.config.merge_c_functions => unsafe { result.merged_c_key(l, ar, key) },
+            None => key,
+        };
+        let key = result.tracked_key(key);
 
-        // Safety: `what` is valid; `state` and `ar` are valid due to &mut's guarantees
-        match unsafe { ffi::lua_getinfo(state.as_ptr(), what.as_ptr(), ar) } {
-            0 => None,
-            _ => {
-                // Safety: the prescribed requirement is fulfilled.
-                Some(unsafe { FunctionName::fill_from(ar) })
+        // the frame of the function that made a tail call is gone
+        if this.config.call_lines && !tail_call {
+            // Safety: `l` is the state the hook was called with
+            if let (Some(caller), Some(line)) = (caller, unsafe { Self::caller_line(l) }) {
+                let lines = result.call_lines.entry((caller, key)).or_default();
+                result.saturated |= lines.entry(line).or_default().add_saturating(1);
             }
         }
-    }
 
-    fn call_event(state: &mut State, ar: &mut ffi::lua_Debug) {
-        // Safety: the activation record is passed to the hook
-        let key = unsafe { FunctionKey::from_ar(state, ar).unwrap() };
-        let level = Self::get_stack_level(state);
+        // the time spent looking up the names is left out of both frames
+        let now = this.now();
+        let kind = if tail_call {
+            EventKind::TailCall
+        } else {
+            EventKind::Call
+        };
 
-        Self::get_from_registry(state);
+        this.record(kind, key, level, now);
+
+        // the size doesn't change, so it's only measured on the first call
+        let measure = this.config.code_size && custom.is_none();
+
+        let created = this.push_frame(key, level, now);
+        this.notify_enter(key, now);
+
+        // Lua doesn't name the functions called from a tail call
+        if this.config.metamethods && !tail_call {
+            // Safety: `l` is the state the hook was called with
+            if let Some(kind) = unsafe { Self::metamethod(l, ar) } {
+                this.enter_metamethod(kind);
+            }
+        }
+
+        if let Some(count) = arg_count {
+            let result = this.result.as_mut().unwrap();
+            let entry = result.data.get_mut(&key).unwrap();
+            result.saturated |= entry.arg_counts.entry(count).or_default().add_saturating(1);
+        }
+
+        if !created || key == FunctionKey::OTHER {
+            return;
+        }
+
+        let mut name = match (custom, this.names.get(&key)) {
+            (Some(custom), _) => Some(FunctionName::custom(custom)),
+            // the entries are only told apart by their ids
+            (None, _) if this.config.skip_names => None,
+            (None, Some(name)) => Some(name.clone()),
+            // Safety: `l` is the state the hook was called with
+            (None, None) => unsafe { Self::name_with_upvalues(l, ar, &this.config) },
+        };
+
+        if let (Some(name), Some(root)) = (name.as_mut(), &this.config.source_root) {
+            name.relativize(root);
+        }
+
+        // only Lua functions have a line to tell them apart, and main chunks have a name already
+        if let (Some(resolver), Some(name)) = (this.config.resolve_name, name.as_mut()) {
+            if let (None, Some(line), "Lua") = (&name.name, name.line, name.domain.as_str()) {
+                name.name = Self::resolve_name(state, resolver, &name.source, line);
+            }
+        }
+
+        // the resolver may have been called
         // Safety: the check above
         let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
         let this: &mut Self = &mut **this;
 
-        if let Some(last) = this.stack.last_mut() {
-            last.suspend(this.result.as_mut().unwrap());
+        let result = this.result.as_mut().unwrap();
+        let entry = result.data.get_mut(&key).unwrap();
+
+        if let Some(name) = name.as_mut() {
+            name.intern(&mut result.sources);
         }
 
-        let entry = this
-            .result
-            .as_mut()
-            .unwrap()
+        entry.name = name;
+
+        if measure {
+            // Safety: `l` is the state the hook was called with
+            entry.code_size = unsafe { Self::code_size(l, ar) };
+        }
+
+        if let Some(callback) = this.config.on_new_function {
+            let name = result.name_of(key);
+            Self::announce(state, callback, name, key);
+        }
+    }
+
+    // Asks the `resolveName` callback for the name of an anonymous function seen for the first
+    // time. Returns `None` unless it returns a string.
+    fn resolve_name(
+        state: &mut State,
+        resolver: Reference,
+        source: &str,
+        line: usize,
+    ) -> Option<String> {
+        state.raw_geti(lua::REGISTRYINDEX, resolver.value() as i64);
+        state.push(source);
+        state.push(line as i64);
+
+        if Self::call_from_hook(state, 2, 1).is_err() {
+            Self::defer_error(state);
+
+            return None;
+        }
+
+        let name = match state.type_of(-1) {
+            Some(lua::Type::String) => to_string_lossy(state, -1),
+            _ => None,
+        };
+
+        state.pop(1);
+
+        name
+    }
+
+    // Passes the name and the id of a function seen for the first time to the `onNewFunction`
+    // callback.
+    fn announce(state: &mut State, callback: Reference, name: String, key: FunctionKey) {
+        state.raw_geti(lua::REGISTRYINDEX, callback.value() as i64);
+        state.push(name);
+        state.push(key.id());
+
+        if Self::call_from_hook(state, 2, 0).is_err() {
+            Self::defer_error(state);
+        }
+    }
+
+    // Closes the frames a call at `level` can't be made above: a new call can't be made at the
+    // level of a frame that is still running, so such frames were unwound by an error caught in
+    // a C function (which called lua_pcall) without returning to Lua. A tail call replaces the
+    // frame at its level, which stays in the stack unless `TailCalls::Replace` is set.
+    fn enter_level(&mut self, level: usize, tail_call: bool, now: Instant) {
+        if !tail_call {
+            self.set_stack_to(level - 1, now);
+
+            return;
+        }
+
+        self.set_stack_to(level, now);
+
+        // the frame of the function that made the tail call is gone
+        if self.config.tail_calls == TailCalls::Replace {
+            if let Some(true) = self.stack.last().map(|frame| frame.level == level) {
+                let mut frame = self.stack.pop().unwrap();
+                self.notify_exit(frame.key, now);
+                frame.close(self.result.as_mut().unwrap(), now);
+            }
+        }
+    }
+
+    // Tells the event sink, if any, that a call began or ended.
+    fn notify_enter(&self, key: FunctionKey, now: Instant) {
+        if let Some(ref sink) = self.sink {
+            sink.borrow_mut().on_enter(key, now);
+        }
+    }
+
+    fn notify_exit(&self, key: FunctionKey, now: Instant) {
+        if let Some(ref sink) = self.sink {
+            sink.borrow_mut().on_exit(key, now);
+        }
+    }
+
+    // Stops the self-time of the frame making a call.
+    fn suspend_caller(&mut self, now: Instant) {
+        let result = self.result.as_mut().unwrap();
+
+        if let Some(last) = self.stack.last_mut() {
+            if !result.is_sampled() {
+                last.suspend(result, now);
+            }
+
+            // there are no entries when streaming
+            if let Some(entry) = result.data.get_mut(&last.key) {
+                result.saturated |= entry.child_calls.add_saturating(1);
+            }
+        }
+    }
+
+    // Opens a frame for a call of `key`, which must be tracked already (see `tracked_key`).
+    // Returns whether this is the first call of the function, whose entry has no name yet.
+    fn push_frame(&mut self, key: FunctionKey, level: usize, now: Instant) -> bool {
+        let result = self.result.as_mut().unwrap();
+        let caller = self.stack.last().map(|frame| frame.key);
+
+        if self.stack.is_empty() && result.root.is_none() {
+            result.root = Some(key);
+        }
+
+        let mut created = false;
+        let mut saturated = false;
+
+        let entry = result
             .data
             .entry(key)
             .and_modify(|entry| {
-                entry.calls += 1;
+                saturated = entry.calls.add_saturating(1);
 
                 entry.recursion_depth += 1;
+                entry.max_recursion = entry.max_recursion.max(entry.recursion_depth);
             })
-            .or_insert_with(|| ProfileEntry::new(None));
+            .or_insert_with(|| {
+                created = true;
+
+                ProfileEntry::new(None)
+            });
+
+        if let Some(caller) = caller {
+            entry.callers.insert(caller);
+        }
+
+        if let Some(window) = result.window.as_mut() {
+            saturated |= window.activity(key).calls.add_saturating(1);
+        }
+
+        saturated |= entry
+            .depths
+            .entry(entry.recursion_depth)
+            .or_default()
+            .add_saturating(1);
 
-        let name = if entry.name.is_none() {
-            Self::determine_name_for(state, ar)
+        let parent = self.stack.last().map_or(0, |frame| frame.node);
+        let node = result.tree_child(parent, key);
+        saturated |= result.tree[node].calls.add_saturating(1);
+        result.saturated |= saturated;
+
+        let frame = if result.is_sampled() {
+            CallFrame::untimed(level, key, node, parent, self.session_start.unwrap())
         } else {
-            None
+            CallFrame::new(level, key, node, parent, now)
         };
 
-        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
-        let entry = this.result.as_mut().unwrap().data.get_mut(&key).unwrap();
+        self.stack.push(frame);
+        result.max_stack_depth = result.max_stack_depth.max(self.stack.len());
+
+        created
+    }
+
+    // Counts a call of the metamethod the frame on the top of the stack was invoked as.
+    fn enter_metamethod(&mut self, kind: &'static str) {
+        let result = self.result.as_mut().unwrap();
+
+        if let Some(metamethods) = result.metamethods.as_mut() {
+            let stats = metamethods.entry(kind).or_default();
+            stats.depth += 1;
+            result.saturated |= stats.calls.add_saturating(1);
+        }
+
+        if let Some(frame) = self.stack.last_mut() {
+            frame.metamethod = Some(kind);
+        }
+    }
+
+    // Closes the frames of the function returning from `level`. Returns its total time and
+    // self-time, if it was profiled.
+    fn pop_frames(&mut self, level: usize, now: Instant) -> Option<(Duration, Duration)> {
+        self.set_stack_to(level, now);
+
+        let mut returned = None;
+
+        while let Some(frame) = self.stack.last() {
+            if frame.level != level {
+                break;
+            }
+
+            let mut frame = self.stack.pop().unwrap();
+            frame.resume(now);
+            self.notify_exit(frame.key, now);
+            frame.close(self.result.as_mut().unwrap(), now);
+
+            // the other frames at this level belong to the functions that made a tail call
+            if returned.is_none() {
+                returned = Some((now.saturating_duration_since(frame.entry), frame.self_time));
+            }
+        }
+
+        if let Some(last) = self.stack.last_mut() {
+            last.resume(now);
+        }
+
+        returned
+    }
+
+    // Adds the event to the log of the session, if it's recorded.
+    fn record(&mut self, kind: EventKind, key: FunctionKey, level: usize, now: Instant) {
+        let result = self.result.as_mut().unwrap();
+
+        if let (Some(events), Some(start)) = (result.events.as_mut(), self.session_start) {
+            events.push(Event {
+                kind,
+                function: key.0,
+                level,
+                time: now.saturating_duration_since(start),
+            });
+        }
+    }
+
+    // Calls the key function with the function that triggered the hook and its parameters.
+    // Returns the key the call is attributed to, or `None` if the function returned no string.
+    fn call_key_fn(
+        state: &mut State,
+        ar: &mut ffi::lua_Debug,
+        key_fn: Reference,
+    ) -> Option<String> {
+        let l = state.as_ptr();
+
+        // Safety: the activation record is passed to the hook
+        let parameters = unsafe {
+            ffi::lua_getinfo(l, b"u\0".as_ptr() as *const _, ar);
+            version::parameters(ar)
+        };
+
+        // only LUA_MINSTACK slots are guaranteed; the parameters are skipped if the stack can't
+        // grow to hold them along with the key function and `f`. Lua 5.1 doesn't report their
+        // number, but they're the only active locals when the call begins, so the stack is grown
+        // for each of them instead.
+        let nparams = match parameters {
+            Some((nparams, _)) if state.check_stack(nparams + 2) => nparams,
+            Some(_) => 0,
+            None => c_int::MAX,
+        };
+        let checked = parameters.is_some();
+        state.raw_geti(lua::REGISTRYINDEX, key_fn.value() as i64);
+
+        // Safety: the activation record is passed to the hook, which can read the parameters of
+        // the function being called
+        let nargs = unsafe {
+            ffi::lua_getinfo(l, b"f\0".as_ptr() as *const _, ar);
+            let mut nargs = 1;
+
+            while nargs <= nparams
+                && (checked || ffi::lua_checkstack(l, 2) != 0)
+                && !ffi::lua_getlocal(l, ar, nargs).is_null()
+            {
+                nargs += 1;
+            }
+
+            nargs
+        };
+
+        if Self::call_from_hook(state, nargs, 1).is_err() {
+            Self::defer_error(state);
 
-        if name.is_some() {
-            entry.name = name;
+            return None;
         }
 
-        let frame = CallFrame::new(level, key);
-        this.stack.push(frame);
+        let key = match state.type_of(-1) {
+            Some(lua::Type::String) => to_string_lossy(state, -1),
+            _ => None,
+        };
+
+        state.pop(1);
+
+        key
     }
 
-    fn return_event(state: &mut State) {
+    fn return_event(state: &mut State, ar: &mut ffi::lua_Debug, event: HookEvent) {
         // Safety: the activation record is passed to the hook
         let level = Self::get_stack_level(state);
 
         Self::get_from_registry(state);
         // Safety: the check above
         let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
-        this.set_stack_to(level);
+        let result = this.result.as_mut().unwrap();
 
-        while let Some(frame) = this.stack.last() {
-            if frame.level != level {
-                break;
+        result.saturated |= if event.is_tail() {
+            result.tail_call_events.add_saturating(1)
+        } else {
+            result.return_events.add_saturating(1)
+        };
+
+        let now = this.now();
+
+        if level > this.base_level {
+            this.record(EventKind::Return, FunctionKey::OTHER, level, now);
+        }
+
+        let returned = this.pop_frames(level, now);
+
+        // the activation record of a tail return describes the function that made the tail call
+        // no better than `determine_name_for` could
+        if event.is_tail() {
+            return;
+        }
+
+        if let (Some(stream), Some((total_time, self_time))) = (this.config.stream, returned) {
+            let source_root = this.config.source_root.clone();
+            Self::stream_return(state, ar, stream, source_root, total_time, self_time);
+        }
+    }
+
+    fn stream_return(
+        state: &mut State,
+        ar: &mut ffi::lua_Debug,
+        stream: Reference,
+        source_root: Option<String>,
+        total_time: Duration,
+        self_time: Duration,
+    ) {
+        // Safety: `state` is valid
+        let mut name = unsafe { Self::determine_name_for(state.as_ptr(), ar) };
+
+        if let (Some(name), Some(root)) = (name.as_mut(), source_root) {
+            name.relativize(&root);
+        }
+
+        state.raw_geti(lua::REGISTRYINDEX, stream.value() as i64);
+        state.push(name.map(|v| v.to_string()));
+        state.push(total_time.as_secs_f64());
+        state.push(self_time.as_secs_f64());
+
+        if Self::call_from_hook(state, 3, 0).is_err() {
+            Self::defer_error(state);
+        }
+    }
+
+    fn count_event(state: &mut State, ar: &mut ffi::lua_Debug) {
+        let level = Self::get_stack_level(state);
+        let l = state.as_ptr();
+
+        Self::get_from_registry(state);
+        // Safety: the check above
+        let this: &mut ManuallyDrop<Self> = unsafe { state.to_userdata_typed(-1).unwrap() };
+        let this: &mut Self = &mut **this;
+        let now = this.now();
+        this.set_stack_to(level, now);
+
+        if level <= this.base_level {
+            return;
+        }
+
+        if this.config.count_instructions {
+            if let Some(last) = this.stack.last() {
+                let result = this.result.as_mut().unwrap();
+                let entry = result.data.get_mut(&last.key).unwrap();
+                result.saturated |= entry.instructions.add_saturating(1);
+            }
+
+            // the hook is called on every instruction, so the samples have to be counted
+            match this.config.sample_interval {
+                Some(interval) => {
+                    this.ticks += 1;
+
+                    if this.ticks < interval {
+                        return;
+                    }
+
+                    this.ticks = 0;
+                }
+                None => return,
+            }
+        } else if this.config.sample_interval.is_none() {
+            // the hook only checks the deadline
+            return;
+        }
+
+        // the running function may not be profiled, in which case the line is someone else's
+        let line = match this.stack.last() {
+            // Safety: `l` is the state the hook was called with, and `ar` its activation record
+            Some(last) if last.level == level => unsafe { Self::current_line(l, ar) },
+            _ => None,
+        };
+
+        this.take_sample(line);
+    }
+
+    // Returns the line the function that triggered the hook is running.
+    //
+    // Safety: `l` must be a valid Lua state running a hook, and `ar` its activation record.
+    unsafe fn current_line(l: *mut ffi::lua_State, ar: &mut lua_Debug) -> Option<usize> {
+        ffi::lua_getinfo(l, b"l\0".as_ptr() as *const _, ar);
+
+        // C functions have no lines
+        match ar.currentline {
+            -1 => None,
+            line => Some(line as usize),
+        }
+    }
+
+    // Takes a sample. The time elapsed since the previous sample is attributed to the functions on
+    // the stack, since the intervals between samples are not equal in wall time. `line` is the line
+    // the function at the top of the stack is running, if known.
+    fn take_sample(&mut self, line: Option<usize>) {
+        let now = self.now();
+        let last_sample = self.last_sample.unwrap_or(now);
+        self.last_sample = Some(now);
+
+        let result = self.result.as_mut().unwrap();
+        let elapsed = result.elapsed(last_sample, now);
+        let mut sample = result.samples.unwrap();
+        result.saturated |= sample.add_saturating(1);
+        result.samples = Some(sample);
+
+        // the total times are attributed to the whole stack, or to its top frames with
+        // `maxStackDepth`, the rest going to the `...` entry
+        let stack = if result.self_only {
+            &[][..]
+        } else {
+            let depth = self.config.max_stack_depth.unwrap_or(usize::MAX);
+            let skipped = self.stack.len().saturating_sub(depth);
+
+            if skipped > 0 {
+                result.saturated |= result.truncated_samples.add_saturating(1)
+                    | result.truncated_time.add_saturating(elapsed);
+            }
+
+            &self.stack[skipped..]
+        };
+
+        for frame in stack {
+            result.saturated |= result.tree[frame.node].total_time.add_saturating(elapsed);
+            let entry = result.data.get_mut(&frame.key).unwrap();
+
+            let first = entry.last_sample != sample;
+
+            if first {
+                entry.last_sample = sample;
+                result.saturated |= entry.cumulative_time.add_saturating(elapsed);
             }
 
-            let mut frame = this.stack.pop().unwrap();
-            frame.resume();
-            frame.close(this.result.as_mut().unwrap());
+            if first || result.recursion == Recursion::All {
+                result.saturated |=
+                    entry.samples.add_saturating(1) | entry.total_time.add_saturating(elapsed);
+            }
         }
 
-        if let Some(last) = this.stack.last_mut() {
-            last.resume();
+        if let Some(last) = self.stack.last() {
+            let entry = result.data.get_mut(&last.key).unwrap();
+            result.saturated |= entry.self_samples.add_saturating(1)
+                | result.tree[last.node].self_time.add_saturating(elapsed);
+
+            if let Some(line) = line {
+                let samples = entry.line_samples.entry(line).or_default();
+                result.saturated |= samples.add_saturating(1);
+            }
+
+            result.add_self_time(last.key, elapsed);
         }
     }
 }
 
-static LIBRARY: Lazy<Box<[(&str, Function)]>> =
-    Lazy::new(|| Box::new([("Profiler", lua_func!(Profiler::new))]));
+// The address of this static is the registry key of the running profiler. It has to be a static:
+// a reference to a constant may point to a different copy in each place it's used. All states
+// share the key, but each has its own registry.
+static OPAQUE_REGISTRY_KEY: u8 = 0;
+
+// The registry key of the flag set when profiling is disabled. The value differs from the one
+// above so that the linker can't merge the statics.
+static DISABLED_REGISTRY_KEY: u8 = 1;
+
+// The registry key of the error raised when the hook returns.
+static DEFERRED_ERROR_REGISTRY_KEY: u8 = 2;
+
+// Initialized on first use in a thread-safe way, and never modified afterwards, so states created
+// on different threads can load the module concurrently. Everything else the module needs, like
+// the metatables, is stored in the state.
+static LIBRARY: Lazy<Box<[(&str, Function)]>> = Lazy::new(|| {
+    Box::new([
+        ("Profiler", lua_func!(Profiler::new)),
+        ("profileFile", lua_func!(Profiler::profile_file)),
+        ("profileString", lua_func!(Profiler::profile_string)),
+        ("wrap", lua_func!(Profiler::wrap)),
+        ("setEnabled", lua_func!(Profiler::set_enabled_lua)),
+        ("isEnabled", lua_func!(Profiler::is_enabled_lua)),
+        ("current", lua_func!(Profiler::current)),
+        ("deserialize", lua_func!(serialize::deserialize)),
+        ("merge", lua_func!(serialize::merge)),
+        ("diff", lua_func!(serialize::diff)),
+        ("unpack", lua_func!(pack::unpack)),
+        ("replay", lua_func!(trace::replay)),
+        ("benchmark", lua_func!(Profiler::benchmark)),
+        ("assertUnder", lua_func!(Profiler::assert_under)),
+        ("ignore", lua_func!(Profiler::ignore)),
+        ("functionsOf", lua_func!(chunk::functions_of)),
+    ])
+});
+
+// The modes and formats scripts may want to check for before using them.
+const FEATURES: &[(&str, bool)] = &[
+    ("cpu", true),
+    ("sampling", true),
+    ("instructions", true),
+    ("streaming", true),
+    ("labels", true),
+    ("markers", true),
+    ("lines", true),
+    ("serialize", true),
+    ("pack", true),
+    ("dot", true),
+    ("csv", true),
+    ("diff", true),
+    ("memory", true),
+    ("pprof", cfg!(feature = "pprof")),
+    ("coroutines", false),
+];
 
 // Safety: must only be called using Lua's require.
 #[no_mangle]
@@ -494,5 +5358,25 @@ pub unsafe extern "C" fn luaopen_liblprofile(state: *mut ffi::lua_State) -> c_in
     let mut state = lua::State::from_ptr(state);
     state.new_lib(&LIBRARY);
 
+    // leaves the profiling calls in production code inert
+    if matches!(std::env::var("LPROFILE_DISABLE").as_deref(), Ok(v) if !v.is_empty() && v != "0") {
+        Profiler::set_enabled(&mut state, false);
+    }
+
+    state.push(concat!("lprofile ", env!("CARGO_PKG_VERSION")));
+    state.set_field(-2, "_VERSION");
+
+    state.push(SystemClock.resolution().as_secs_f64());
+    state.set_field(-2, "clockResolution");
+
+    state.create_table(0, FEATURES.len() as i32);
+
+    for &(feature, available) in FEATURES {
+        state.push(available);
+        state.set_field(-2, feature);
+    }
+
+    state.set_field(-2, "features");
+
     1
 }
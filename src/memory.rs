@@ -0,0 +1,174 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ptr;
+
+use lua::ffi;
+use lua::libc::{c_void, size_t};
+
+use crate::FunctionKey;
+
+// The allocator calls attributed to a function.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub(crate) struct MemoryStats {
+    pub allocations: u64,
+    pub reallocations: u64,
+    pub frees: u64,
+    pub bytes_allocated: u64,
+    pub bytes_freed: u64,
+    // the size of the blocks allocated during the session and still allocated at its end, if
+    // their owners are tracked
+    pub live_bytes: u64,
+}
+
+impl MemoryStats {
+    fn record(&mut self, block: *mut c_void, osize: size_t, nsize: size_t) {
+        let (osize, nsize) = (osize as u64, nsize as u64);
+
+        if nsize == 0 {
+            // freeing a null pointer does nothing
+            if !block.is_null() {
+                self.frees = self.frees.saturating_add(1);
+                self.bytes_freed = self.bytes_freed.saturating_add(osize);
+            }
+        } else if block.is_null() {
+            // osize encodes the type of the object being allocated
+            self.allocations = self.allocations.saturating_add(1);
+            self.bytes_allocated = self.bytes_allocated.saturating_add(nsize);
+        } else {
+            self.reallocations = self.reallocations.saturating_add(1);
+
+            if nsize > osize {
+                self.bytes_allocated = self.bytes_allocated.saturating_add(nsize - osize);
+            } else {
+                self.bytes_freed = self.bytes_freed.saturating_add(osize - nsize);
+            }
+        }
+    }
+
+    pub(crate) fn add(&mut self, other: &MemoryStats) {
+        self.allocations += other.allocations;
+        self.reallocations += other.reallocations;
+        self.frees += other.frees;
+        self.bytes_allocated += other.bytes_allocated;
+        self.bytes_freed += other.bytes_freed;
+        self.live_bytes += other.live_bytes;
+    }
+}
+
+// Wraps the allocator of a state during a session. The allocator cannot call into Lua, so the
+// profiler tells it which function is running.
+pub(crate) struct MemoryTracker {
+    original: ffi::lua_Alloc,
+    ud: *mut c_void,
+    // the function at the top of the profiler's stack
+    pub current: Cell<Option<FunctionKey>>,
+    pub stats: RefCell<HashMap<FunctionKey, MemoryStats>>,
+    // all of the allocator calls made during the session, attributed or not
+    pub total: Cell<MemoryStats>,
+    // the function that allocated each block and its size, if tracked; reallocated blocks keep
+    // their owner
+    owners: Option<RefCell<HashMap<usize, (FunctionKey, size_t)>>>,
+}
+
+impl MemoryTracker {
+    // Replaces the allocator of the state. The tracker must not move until `uninstall` is called.
+    // If `owners` is set, the blocks are attributed to the functions that allocated them.
+    //
+    // Safety: `l` must be a valid Lua state.
+    pub(crate) unsafe fn install(l: *mut ffi::lua_State, owners: bool) -> Box<Self> {
+        let mut ud = ptr::null_mut();
+        let original = ffi::lua_getallocf(l, &mut ud);
+
+        let tracker = Box::new(Self {
+            original,
+            ud,
+            current: Cell::new(None),
+            stats: RefCell::new(HashMap::new()),
+            total: Cell::new(MemoryStats::default()),
+            owners: if owners {
+                Some(RefCell::new(HashMap::new()))
+            } else {
+                None
+            },
+        });
+
+        ffi::lua_setallocf(
+            l,
+            Some(Self::alloc),
+            &*tracker as *const Self as *mut c_void,
+        );
+
+        tracker
+    }
+
+    // Restores the allocator the tracker has replaced.
+    //
+    // Safety: `l` must be the state passed to `install`.
+    pub(crate) unsafe fn uninstall(&self, l: *mut ffi::lua_State) {
+        ffi::lua_setallocf(l, self.original, self.ud);
+    }
+
+    // Adds up the sizes of the blocks still allocated by each function.
+    pub(crate) fn live_bytes(&self) -> HashMap<FunctionKey, u64> {
+        let mut live = HashMap::new();
+
+        if let Some(ref owners) = self.owners {
+            for &(key, size) in owners.borrow().values() {
+                *live.entry(key).or_default() += size as u64;
+            }
+        }
+
+        live
+    }
+
+    // Moves the ownership of `block` to `new`, the block the allocator returned for it.
+    fn track_owner(&self, block: *mut c_void, nsize: size_t, new: *mut c_void) {
+        let mut owners = match self.owners.as_ref().map(RefCell::try_borrow_mut) {
+            Some(Ok(owners)) => owners,
+            _ => return,
+        };
+
+        let owner = if block.is_null() {
+            self.current.get()
+        } else {
+            match owners.remove(&(block as usize)) {
+                // a failed reallocation leaves the block as it was
+                Some(owner) if new.is_null() && nsize > 0 => {
+                    owners.insert(block as usize, owner);
+
+                    return;
+                }
+                owner => owner.map(|(key, _)| key),
+            }
+        };
+
+        if let (Some(owner), false, true) = (owner, new.is_null(), nsize > 0) {
+            owners.insert(new as usize, (owner, nsize));
+        }
+    }
+
+    unsafe extern "C" fn alloc(
+        ud: *mut c_void,
+        block: *mut c_void,
+        osize: size_t,
+        nsize: size_t,
+    ) -> *mut c_void {
+        let this = &*(ud as *const Self);
+
+        let mut total = this.total.get();
+        total.record(block, osize, nsize);
+        this.total.set(total);
+
+        if let Some(key) = this.current.get() {
+            // the allocator is never called reentrantly
+            if let Ok(mut stats) = this.stats.try_borrow_mut() {
+                stats.entry(key).or_default().record(block, osize, nsize);
+            }
+        }
+
+        let new = (this.original.unwrap())(this.ud, block, osize, nsize);
+        this.track_owner(block, nsize, new);
+
+        new
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::Duration;
+
+use lua::{ffi, State, Type};
+
+use crate::config::Config;
+use crate::{FunctionKey, FunctionName, ProfileEntry, ProfilingResult, SourceType};
+
+// Packed results are little-endian. After the header come the string table and then fixed-size
+// records that refer to the strings by their index:
+//
+//   header  magic, version
+//   time    u64 nanoseconds, u64::MAX if unknown
+//   strings u32 count, then u32 length and bytes for each
+//   entries u32 count, then per entry: u64 calls, u64 total ns, u64 self ns,
+//           u8 source type (0xff if unnamed), u32 domain, u32 source, u32 line,
+//           u32 function type, u32 name
+//   labels  u32 count, then per label: u32 name, u64 ns
+//   warnings u32 count, then u32 message for each
+//
+// Absent optional fields are stored as u32::MAX.
+const MAGIC: &[u8] = b"LPRB";
+const VERSION: u8 = 1;
+const NONE: u32 = u32::MAX;
+const UNNAMED: u8 = 0xff;
+
+#[derive(Default)]
+struct Strings {
+    indices: HashMap<String, u32>,
+    list: Vec<String>,
+}
+
+impl Strings {
+    fn intern(&mut self, s: &str) -> u32 {
+        if let Some(&i) = self.indices.get(s) {
+            return i;
+        }
+
+        let i = self.list.len() as u32;
+        self.indices.insert(s.to_owned(), i);
+        self.list.push(s.to_owned());
+
+        i
+    }
+
+    fn intern_opt(&mut self, s: Option<&str>) -> u32 {
+        s.map_or(NONE, |s| self.intern(s))
+    }
+}
+
+fn source_type_id(source_type: SourceType) -> u8 {
+    match source_type {
+        SourceType::File => 0,
+        SourceType::String => 1,
+        SourceType::Bytecode => 2,
+        SourceType::C => 3,
+        SourceType::Other => 4,
+    }
+}
+
+fn source_type_from_id(id: u8) -> Option<SourceType> {
+    match id {
+        0 => Some(SourceType::File),
+        1 => Some(SourceType::String),
+        2 => Some(SourceType::Bytecode),
+        3 => Some(SourceType::C),
+        4 => Some(SourceType::Other),
+        _ => None,
+    }
+}
+
+fn nanos(time: Duration) -> u64 {
+    time.as_nanos().try_into().unwrap_or(u64::MAX - 1)
+}
+
+impl ProfilingResult {
+    pub(crate) fn pack(&self) -> Vec<u8> {
+        let mut strings = Strings::default();
+        let mut records = Vec::new();
+
+        records.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+
+        for v in self.data.values() {
+            records.extend_from_slice(&(v.calls as u64).to_le_bytes());
+            records.extend_from_slice(&nanos(v.total_time).to_le_bytes());
+            records.extend_from_slice(&nanos(v.total_self_time).to_le_bytes());
+
+            let fields = match v.name {
+                Some(ref name) => {
+                    records.push(source_type_id(name.source_type));
+
+                    [
+                        strings.intern(&name.domain),
+                        strings.intern(&name.source),
+                        name.line.map_or(NONE, |v| v as u32),
+                        strings.intern_opt(name.function_type.as_deref()),
+                        strings.intern_opt(name.name.as_deref()),
+                    ]
+                }
+                None => {
+                    records.push(UNNAMED);
+
+                    [NONE; 5]
+                }
+            };
+
+            for field in &fields {
+                records.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+
+        records.extend_from_slice(&(self.labels.len() as u32).to_le_bytes());
+
+        for (label, time) in &self.labels {
+            records.extend_from_slice(&strings.intern(label).to_le_bytes());
+            records.extend_from_slice(&nanos(*time).to_le_bytes());
+        }
+
+        records.extend_from_slice(&(self.warnings.len() as u32).to_le_bytes());
+
+        for warning in &self.warnings {
+            records.extend_from_slice(&strings.intern(warning).to_le_bytes());
+        }
+
+        let mut out = Vec::with_capacity(records.len() + 64);
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.total_time.map_or(u64::MAX, nanos).to_le_bytes());
+        out.extend_from_slice(&(strings.list.len() as u32).to_le_bytes());
+
+        for s in &strings.list {
+            out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+
+        out.extend_from_slice(&records);
+
+        out
+    }
+
+    pub(crate) fn unpack(data: &[u8]) -> Option<Self> {
+        let mut reader = Reader { data };
+
+        if reader.bytes(MAGIC.len())? != MAGIC || reader.u8()? != VERSION {
+            return None;
+        }
+
+        let mut result = Self::new(&Config::default());
+
+        result.total_time = match reader.u64()? {
+            u64::MAX => None,
+            time => Some(Duration::from_nanos(time)),
+        };
+
+        let strings = (0..reader.u32()?)
+            .map(|_| {
+                let len = reader.u32()? as usize;
+                String::from_utf8(reader.bytes(len)?.to_vec()).ok()
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let string = |i: u32| strings.get(i as usize).cloned();
+        let string_opt = |i: u32| {
+            if i == NONE {
+                Some(None)
+            } else {
+                string(i).map(Some)
+            }
+        };
+
+        for _ in 0..reader.u32()? {
+            let calls = reader.u64()? as usize;
+            let total_time = Duration::from_nanos(reader.u64()?);
+            let total_self_time = Duration::from_nanos(reader.u64()?);
+            let source_type = reader.u8()?;
+            let [domain, source, line, function_type, name] = [
+                reader.u32()?,
+                reader.u32()?,
+                reader.u32()?,
+                reader.u32()?,
+                reader.u32()?,
+            ];
+
+            let name = match source_type {
+                UNNAMED => None,
+                id => Some(FunctionName {
+                    name: string_opt(name)?,
+                    function_type: string_opt(function_type)?,
+                    source_type: source_type_from_id(id)?,
+                    source: string(source)?.into(),
+                    raw_source: string(source)?.into(),
+                    line: if line == NONE {
+                        None
+                    } else {
+                        Some(line as usize)
+                    },
+                    domain: string(domain)?,
+                    upvalues: None,
+                }),
+            };
+
+            let mut entry = ProfileEntry::new(name);
+            entry.recursion_depth = 0;
+            entry.calls = calls;
+            entry.total_time = total_time;
+            entry.total_self_time = total_self_time;
+
+            let key = FunctionKey(result.data.len() + 1);
+            result.data.insert(key, entry);
+        }
+
+        for _ in 0..reader.u32()? {
+            let label = string(reader.u32()?)?;
+            let time = Duration::from_nanos(reader.u64()?);
+            result.labels.insert(label, time);
+        }
+
+        for _ in 0..reader.u32()? {
+            let warning = string(reader.u32()?)?;
+            result.warnings.push(warning);
+        }
+
+        if !reader.data.is_empty() {
+            return None;
+        }
+
+        Some(result)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.data.len() < n {
+            return None;
+        }
+
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|v| v[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes(8)
+            .map(|v| u64::from_le_bytes(v.try_into().unwrap()))
+    }
+}
+
+// Pushes a binary string, which may not be valid UTF-8.
+pub(crate) fn push_bytes(state: &mut State, bytes: &[u8]) {
+    // Safety: the pointer and the length come from the same slice
+    unsafe {
+        ffi::lua_pushlstring(state.as_ptr(), bytes.as_ptr() as *const _, bytes.len());
+    }
+}
+
+// lprofile.unpack(s): converts a packed result to a table.
+pub fn unpack(state: &mut State) -> i32 {
+    state.check_type(1, Type::String);
+
+    let mut len = 0;
+    // Safety: the value is a string, so the pointer is valid for `len` bytes while it's on the
+    // stack
+    let data = unsafe {
+        let ptr = ffi::lua_tolstring(state.as_ptr(), 1, &mut len);
+        std::slice::from_raw_parts(ptr as *const u8, len).to_vec()
+    };
+
+    match ProfilingResult::unpack(&data) {
+        Some(result) => result.move_to_lua(state, None),
+        None => {
+            state.arg_error(1, "not a packed profiling result");
+            unreachable!()
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use crate::{FunctionKey, ProfilingResult};
+
+// The pprof format is a protobuf message (see profile.proto in github.com/google/pprof). Only a
+// few of its fields are needed, so they are encoded by hand rather than with a protobuf library.
+// The field numbers of the `Profile` message:
+const SAMPLE_TYPE: u32 = 1;
+const SAMPLE: u32 = 2;
+const LOCATION: u32 = 4;
+const FUNCTION: u32 = 5;
+const STRING_TABLE: u32 = 6;
+const DURATION_NANOS: u32 = 10;
+const COMMENT: u32 = 13;
+
+const VARINT: u32 = 0;
+const LENGTH_DELIMITED: u32 = 2;
+
+#[derive(Default)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.buf.push(v as u8 | 0x80);
+            v >>= 7;
+        }
+
+        self.buf.push(v as u8);
+    }
+
+    fn tag(&mut self, field: u32, wire_type: u32) {
+        self.varint((field << 3 | wire_type) as u64);
+    }
+
+    // Zero is the default value, which isn't written.
+    fn uint(&mut self, field: u32, v: u64) {
+        if v != 0 {
+            self.tag(field, VARINT);
+            self.varint(v);
+        }
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.tag(field, LENGTH_DELIMITED);
+        self.varint(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn message(&mut self, field: u32, f: impl FnOnce(&mut Message)) {
+        let mut message = Message::default();
+        f(&mut message);
+        self.bytes(field, &message.buf);
+    }
+
+    fn packed(&mut self, field: u32, values: &[u64]) {
+        let mut packed = Message::default();
+
+        for &v in values {
+            packed.varint(v);
+        }
+
+        self.bytes(field, &packed.buf);
+    }
+}
+
+// The string table of the profile; the first string must be empty.
+struct Strings {
+    indices: HashMap<String, u64>,
+    list: Vec<String>,
+}
+
+impl Strings {
+    fn new() -> Self {
+        Self {
+            indices: HashMap::new(),
+            list: vec![String::new()],
+        }
+    }
+
+    fn intern(&mut self, s: &str) -> u64 {
+        if s.is_empty() {
+            return 0;
+        }
+
+        if let Some(&i) = self.indices.get(s) {
+            return i;
+        }
+
+        let i = self.list.len() as u64;
+        self.indices.insert(s.to_owned(), i);
+        self.list.push(s.to_owned());
+
+        i
+    }
+}
+
+impl ProfilingResult {
+    // Encodes the call tree as a pprof profile. Each node of the tree is a sample whose stack is
+    // the path to it, weighted by the number of calls and the self-time. Every function has a
+    // single location at the line it's defined at, since the lines running aren't tracked.
+    pub(crate) fn to_pprof(&self) -> Vec<u8> {
+        let mut strings = Strings::new();
+        let mut profile = Message::default();
+
+        for &(kind, unit) in &[("samples", "count"), ("cpu", "nanoseconds")] {
+            let kind = strings.intern(kind);
+            let unit = strings.intern(unit);
+
+            profile.message(SAMPLE_TYPE, |value_type| {
+                value_type.uint(1, kind);
+                value_type.uint(2, unit);
+            });
+        }
+
+        // the ids of the functions and their locations, which can't be 0
+        let mut ids = HashMap::<FunctionKey, u64>::new();
+        let mut keys = self
+            .tree
+            .iter()
+            .filter_map(|node| node.key)
+            .collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|key| key.0);
+        keys.dedup();
+
+        for key in keys {
+            let id = ids.len() as u64 + 1;
+            ids.insert(key, id);
+
+            let name = self.data.get(&key).and_then(|v| v.name.as_ref());
+            let display_name = strings.intern(&self.name_of(key));
+            let system_name = strings.intern(name.and_then(|v| v.name.as_deref()).unwrap_or(""));
+            let filename = strings.intern(name.map_or("", |v| &v.source));
+            let line = name.and_then(|v| v.line).unwrap_or(0) as u64;
+
+            profile.message(FUNCTION, |function| {
+                function.uint(1, id);
+                function.uint(2, display_name);
+                function.uint(3, system_name);
+                function.uint(4, filename);
+                function.uint(5, line);
+            });
+
+            profile.message(LOCATION, |location| {
+                location.uint(1, id);
+                location.message(4, |l| {
+                    l.uint(1, id);
+                    l.uint(2, line);
+                });
+            });
+        }
+
+        // the parents precede their children, so the stacks are built in a single pass
+        let mut stacks: Vec<Vec<u64>> = vec![Vec::new(); self.tree.len()];
+
+        for (i, node) in self.tree.iter().enumerate() {
+            // the leaf comes first
+            for &child in node.children.values() {
+                let id = ids[&self.tree[child].key.unwrap()];
+                let mut stack = Vec::with_capacity(stacks[i].len() + 1);
+                stack.push(id);
+                stack.extend_from_slice(&stacks[i]);
+                stacks[child] = stack;
+            }
+
+            if node.key.is_none() {
+                continue;
+            }
+
+            let values = [node.calls as u64, node.self_time.as_nanos() as u64];
+
+            profile.message(SAMPLE, |sample| {
+                sample.packed(1, &stacks[i]);
+                sample.packed(2, &values);
+            });
+        }
+
+        // the metadata is kept as comments, which the string table must have before it's written
+        let comments = self
+            .metadata
+            .iter()
+            .map(|(key, value)| strings.intern(&format!("{}={}", key, value)))
+            .collect::<Vec<_>>();
+
+        for s in &strings.list {
+            profile.bytes(STRING_TABLE, s.as_bytes());
+        }
+
+        if let Some(time) = self.total_time {
+            profile.uint(DURATION_NANOS, time.as_nanos() as u64);
+        }
+
+        if !comments.is_empty() {
+            profile.packed(COMMENT, &comments);
+        }
+
+        profile.buf
+    }
+}
@@ -0,0 +1,433 @@
+use std::fmt::Write;
+
+use lua::{lua_func, State, Type};
+
+use crate::error::Error;
+use crate::saturate::clamped;
+use crate::{check_string_lossy, to_string_lossy, ProfilingResult};
+
+// Names longer than this are truncated to keep the report readable.
+const MAX_NAME_WIDTH: usize = 60;
+
+// the number of decimal places shown for the times unless given
+const DEFAULT_DECIMALS: usize = 6;
+
+const TYPE_NAME: &str = "lprofile.Result";
+
+struct Row {
+    name: String,
+    calls: i64,
+    total_time: f64,
+    total_self_time: f64,
+}
+
+// Sets the metatable of the result table at the top of the stack, which provides the report as
+// `result:report()` and `tostring(result)`, the lookup of the entries as `result:get(name)`,
+// `result:slowestPerCall(n)`, and `result:selfTimeDistribution()`.
+pub(crate) fn set_metatable(state: &mut State) {
+    if state.new_metatable(TYPE_NAME) {
+        state.push_fn(lua_func!(report));
+        state.set_field(-2, "__tostring");
+
+        state.push_fn(lua_func!(report));
+        state.set_field(-2, "report");
+
+        state.push_fn(lua_func!(get));
+        state.set_field(-2, "get");
+
+        state.push_fn(lua_func!(slowest_per_call));
+        state.set_field(-2, "slowestPerCall");
+
+        state.push_fn(lua_func!(self_time_distribution));
+        state.set_field(-2, "selfTimeDistribution");
+
+        state.push_value(-1);
+        state.set_field(-2, "__index");
+    }
+
+    state.set_metatable(-2);
+}
+
+// Reads the entries of the result table at `idx`.
+fn read_rows(state: &mut State, idx: i32) -> Vec<Row> {
+    let len = state.raw_len(idx) as i64;
+    let mut rows = Vec::with_capacity(len as usize);
+
+    for i in 1..=len {
+        if state.raw_geti(idx, i) != Type::Table {
+            state.pop(1);
+            continue;
+        }
+
+        state.get_field(-1, "name");
+        state.get_field(-2, "calls");
+        state.get_field(-3, "totalTime");
+        state.get_field(-4, "totalSelfTime");
+
+        rows.push(Row {
+            name: to_string_lossy(state, -4).unwrap_or_default(),
+            calls: state.to_integerx(-3).unwrap_or(0),
+            total_time: state.to_numberx(-2).unwrap_or(0.0),
+            total_self_time: state.to_numberx(-1).unwrap_or(0.0),
+        });
+
+        state.pop(5);
+    }
+
+    rows
+}
+
+fn truncate(name: &str, width: usize) -> String {
+    if name.chars().count() <= width {
+        return name.to_owned();
+    }
+
+    let mut result = name.chars().take(width - 3).collect::<String>();
+    result.push_str("...");
+
+    result
+}
+
+fn percentage(time: f64, session_time: Option<f64>) -> String {
+    match session_time {
+        Some(total) if total > 0.0 => format!("{:.1}%", time / total * 100.0),
+        _ => "-".to_owned(),
+    }
+}
+
+impl ProfilingResult {
+    // Renders the same report as `result:report()` without building the result table.
+    pub(crate) fn report(&self) -> String {
+        let mut rows = self
+            .data
+            .iter()
+            .map(|(k, v)| Row {
+                name: self.name_of(*k),
+                calls: clamped(v.calls),
+                total_time: v.total_time.as_secs_f64(),
+                total_self_time: v.total_self_time.as_secs_f64(),
+            })
+            .collect::<Vec<_>>();
+
+        if let (Some(host_time), false) = (self.host_time(), self.data.is_empty()) {
+            rows.push(Row {
+                name: "<host>".to_owned(),
+                calls: 0,
+                total_time: host_time.as_secs_f64(),
+                total_self_time: host_time.as_secs_f64(),
+            });
+        }
+
+        let decimals = self.precision.map_or(DEFAULT_DECIMALS, |v| v as usize);
+
+        render(rows, self.total_time.map(|v| v.as_secs_f64()), decimals)
+    }
+}
+
+// result:report([decimals]): renders the result as a text table sorted by the self-time.
+pub fn report(state: &mut State) -> i32 {
+    state.check_type(1, Type::Table);
+
+    let decimals = if state.is_none_or_nil(2) {
+        DEFAULT_DECIMALS
+    } else {
+        let decimals = state.check_integer(2);
+        state.arg_check((0..=9).contains(&decimals), 2, "must be from 0 to 9");
+        decimals as usize
+    };
+
+    state.get_field(1, "totalTime");
+    let session_time = state.to_numberx(-1);
+    state.pop(1);
+
+    let rows = read_rows(state, 1);
+    state.push(render(rows, session_time, decimals));
+
+    1
+}
+
+// result:get(name): returns the entry whose name or id is `name`. The entries of the functions
+// sharing the name are added up in a new table listing them. Returns nil and a NOT_FOUND error if
+// there's none.
+pub fn get(state: &mut State) -> i32 {
+    state.check_type(1, Type::Table);
+    let name = check_string_lossy(state, 2);
+
+    let len = state.raw_len(1) as i64;
+    let mut found = Vec::new();
+
+    for i in 1..=len {
+        if state.raw_geti(1, i) == Type::Table {
+            state.get_field(-1, "name");
+            state.get_field(-2, "id");
+
+            let matches = to_string_lossy(state, -2).as_ref() == Some(&name)
+                || to_string_lossy(state, -1).as_ref() == Some(&name);
+
+            if matches {
+                found.push(i);
+            }
+
+            state.pop(2);
+        }
+
+        state.pop(1);
+    }
+
+    match found[..] {
+        [] => {
+            state.push_nil();
+            Error::NotFound.push(state, &format!("no function named '{}'", name));
+
+            2
+        }
+        [i] => {
+            state.raw_geti(1, i);
+
+            1
+        }
+        _ => {
+            push_aggregate(state, &name, &found);
+
+            1
+        }
+    }
+}
+
+// Pushes the entry adding up the entries of the result table at index 1 at the `indices`. The
+// times are left out if none of the entries has them.
+fn push_aggregate(state: &mut State, name: &str, indices: &[i64]) {
+    let mut calls = 0;
+    let mut total_time = None;
+    let mut total_self_time = None;
+
+    for &i in indices {
+        state.raw_geti(1, i);
+        state.get_field(-1, "calls");
+        state.get_field(-2, "totalTime");
+        state.get_field(-3, "totalSelfTime");
+
+        calls += state.to_integerx(-3).unwrap_or(0);
+
+        if let Some(time) = state.to_numberx(-2) {
+            *total_time.get_or_insert(0.0) += time;
+        }
+
+        if let Some(time) = state.to_numberx(-1) {
+            *total_self_time.get_or_insert(0.0) += time;
+        }
+
+        state.pop(4);
+    }
+
+    state.create_table(0, 5);
+
+    state.push("name");
+    state.push(name);
+    state.set_table(-3);
+
+    state.push("calls");
+    state.push(calls);
+    state.set_table(-3);
+
+    state.push("totalTime");
+    state.push(total_time);
+    state.set_table(-3);
+
+    state.push("totalSelfTime");
+    state.push(total_self_time);
+    state.set_table(-3);
+
+    state.push("entries");
+    state.create_table(indices.len() as i32, 0);
+
+    for (n, &i) in indices.iter().enumerate() {
+        state.raw_geti(1, i);
+        state.seti(-2, (n + 1) as i64);
+    }
+
+    state.set_table(-3);
+}
+
+// result:slowestPerCall([n]): returns the `n` entries with the highest self-time per call, or all
+// of them, slowest first, as tables with the `entry` and its `selfTimePerCall`. The entries
+// without calls, like `<host>`, are left out.
+pub fn slowest_per_call(state: &mut State) -> i32 {
+    state.check_type(1, Type::Table);
+
+    let limit = if state.is_none_or_nil(2) {
+        usize::MAX
+    } else {
+        let n = state.check_integer(2);
+        state.arg_check(n > 0, 2, "must be positive");
+        n as usize
+    };
+
+    let len = state.raw_len(1) as i64;
+    let mut ratios = Vec::new();
+
+    for i in 1..=len {
+        if state.raw_geti(1, i) == Type::Table {
+            state.get_field(-1, "calls");
+            state.get_field(-2, "totalSelfTime");
+
+            let calls = state.to_integerx(-2).unwrap_or(0);
+            let self_time = state.to_numberx(-1).unwrap_or(0.0);
+
+            if calls > 0 {
+                ratios.push((i, self_time / calls as f64));
+            }
+
+            state.pop(2);
+        }
+
+        state.pop(1);
+    }
+
+    ratios.sort_by(|lhs, rhs| rhs.1.total_cmp(&lhs.1));
+    ratios.truncate(limit);
+
+    state.create_table(ratios.len() as i32, 0);
+
+    for (n, &(i, ratio)) in ratios.iter().enumerate() {
+        state.create_table(0, 2);
+
+        state.push("entry");
+        state.raw_geti(1, i);
+        state.set_table(-3);
+
+        state.push("selfTimePerCall");
+        state.push(ratio);
+        state.set_table(-3);
+
+        state.seti(-2, (n + 1) as i64);
+    }
+
+    1
+}
+
+// the ids of the entries that aren't functions, left out of the distribution
+const SPECIAL_IDS: &[&str] = &[
+    "host",
+    "ignored",
+    "filtered",
+    "insignificant",
+    "hook",
+    "truncated",
+];
+
+// the percentiles of the distribution, with their keys
+const PERCENTILES: &[(&str, f64)] = &[("p50", 50.0), ("p90", 90.0), ("p99", 99.0)];
+
+// result:selfTimeDistribution(): describes how the self-time is spread over the functions: their
+// `count`, and the `min`, `max`, and percentiles of their self-times, by the nearest rank. The
+// entries that aren't functions, like `<host>`, are left out.
+pub fn self_time_distribution(state: &mut State) -> i32 {
+    state.check_type(1, Type::Table);
+
+    let len = state.raw_len(1) as i64;
+    let mut times = Vec::new();
+
+    for i in 1..=len {
+        if state.raw_geti(1, i) == Type::Table {
+            state.get_field(-1, "id");
+            state.get_field(-2, "totalSelfTime");
+
+            let id = to_string_lossy(state, -2).unwrap_or_default();
+
+            if let (Some(time), false) = (state.to_numberx(-1), SPECIAL_IDS.contains(&&*id)) {
+                times.push(time);
+            }
+
+            state.pop(2);
+        }
+
+        state.pop(1);
+    }
+
+    times.sort_by(f64::total_cmp);
+
+    state.create_table(0, 6);
+
+    state.push("count");
+    state.push(times.len() as i64);
+    state.set_table(-3);
+
+    if let (Some(&min), Some(&max)) = (times.first(), times.last()) {
+        state.push("min");
+        state.push(min);
+        state.set_table(-3);
+
+        for &(key, percentile) in PERCENTILES {
+            let rank = (percentile / 100.0 * times.len() as f64).ceil() as usize;
+
+            state.push(key);
+            state.push(times[rank.max(1) - 1]);
+            state.set_table(-3);
+        }
+
+        state.push("max");
+        state.push(max);
+        state.set_table(-3);
+    }
+
+    1
+}
+
+fn render(mut rows: Vec<Row>, session_time: Option<f64>, decimals: usize) -> String {
+    rows.sort_by(|lhs, rhs| rhs.total_self_time.total_cmp(&lhs.total_self_time));
+
+    let width = rows
+        .iter()
+        .map(|row| row.name.chars().count())
+        .max()
+        .unwrap_or(0)
+        .clamp("name".len(), MAX_NAME_WIDTH);
+
+    // room for the decimals and a few seconds
+    let time_width = (decimals + 5).max(10);
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<width$}  {:>10}  {:>time_width$}  {:>time_width$}  {:>7}  {:>7}",
+        "name",
+        "calls",
+        "total",
+        "self",
+        "total %",
+        "self %",
+        width = width,
+        time_width = time_width + 2
+    )
+    .unwrap();
+
+    for row in &rows {
+        writeln!(
+            out,
+            "{:<width$}  {:>10}  {:>time_width$.decimals$} s  {:>time_width$.decimals$} s  {:>7}  {:>7}",
+            truncate(&row.name, width),
+            row.calls,
+            row.total_time,
+            row.total_self_time,
+            percentage(row.total_time, session_time),
+            percentage(row.total_self_time, session_time),
+            width = width,
+            time_width = time_width,
+            decimals = decimals
+        )
+        .unwrap();
+    }
+
+    if let Some(time) = session_time {
+        writeln!(
+            out,
+            "session time: {:.decimals$} s",
+            time,
+            decimals = decimals
+        )
+        .unwrap();
+    }
+
+    out
+}
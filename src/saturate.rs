@@ -0,0 +1,45 @@
+use std::convert::TryInto;
+use std::time::Duration;
+
+// Converts a counter to a Lua integer. A counter saturated at the maximum of a wider type reaches
+// Lua as the largest integer rather than wrapping around to a negative one.
+pub(crate) fn clamped(v: impl TryInto<i64>) -> i64 {
+    v.try_into().unwrap_or(i64::MAX)
+}
+
+// Addition that caps the value at its maximum instead of overflowing. Always-on profilers may run
+// long enough for the counters and the times to overflow, and the hook must not panic.
+pub(crate) trait SaturatingAdd: Sized {
+    // Adds `rhs` to the value. Returns true if the sum was capped.
+    fn add_saturating(&mut self, rhs: Self) -> bool;
+}
+
+macro_rules! impl_saturating_add {
+    ($($t:ty => $max:expr),* $(,)?) => {
+        $(
+            impl SaturatingAdd for $t {
+                fn add_saturating(&mut self, rhs: Self) -> bool {
+                    match self.checked_add(rhs) {
+                        Some(sum) => {
+                            *self = sum;
+
+                            false
+                        }
+                        None => {
+                            *self = $max;
+
+                            true
+                        }
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_saturating_add! {
+    usize => usize::MAX,
+    u64 => u64::MAX,
+    u128 => u128::MAX,
+    Duration => Duration::MAX,
+}
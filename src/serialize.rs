@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use lua::State;
+
+use crate::config::{Config, HookTime, MetaValue};
+use crate::saturate::{clamped, SaturatingAdd};
+use crate::window::Window;
+use crate::{
+    check_string_lossy, FunctionKey, FunctionName, ProfileEntry, ProfilingResult, SourceType,
+};
+
+// Serialized results are line-based. The first line is the header, and each of the following lines
+// is a tab-separated record whose first field is its kind.
+const HEADER: &str = "lprofile 1";
+
+impl FunctionName {
+    // Identifies the function across processes, unlike `FunctionKey`.
+    //
+    // Lua functions are identified by the place they are defined at, so functions defined on the
+    // same line share the key. C functions have no location and are identified by their name
+    // instead, which means all anonymous C functions share the key as well.
+    fn merge_key(&self) -> String {
+        match self.line {
+            Some(line) => format!("{}:{}:{}", self.domain, self.source, line),
+            None => format!(
+                "{}:{}:{}",
+                self.domain,
+                self.source,
+                self.name.as_deref().unwrap_or("")
+            ),
+        }
+    }
+}
+
+impl ProfilingResult {
+    pub(crate) fn serialize(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "{}", HEADER).unwrap();
+
+        if let Some(time) = self.total_time {
+            writeln!(out, "time\t{}", time.as_nanos()).unwrap();
+        }
+
+        for v in self.data.values() {
+            write!(
+                out,
+                "function\t{}\t{}\t{}",
+                v.calls,
+                v.total_time.as_nanos(),
+                v.total_self_time.as_nanos()
+            )
+            .unwrap();
+
+            match v.name {
+                Some(ref name) => writeln!(
+                    out,
+                    "\t{}\t{}\t{}\t{}\t{}\t{}",
+                    escape(&name.domain),
+                    name.source_type.as_str(),
+                    escape(&name.source),
+                    name.line.map_or_else(String::new, |v| v.to_string()),
+                    escape(name.function_type.as_deref().unwrap_or("")),
+                    escape(name.name.as_deref().unwrap_or("")),
+                )
+                .unwrap(),
+                None => writeln!(out).unwrap(),
+            }
+        }
+
+        for (label, time) in &self.labels {
+            writeln!(out, "label\t{}\t{}", escape(label), time.as_nanos()).unwrap();
+        }
+
+        for warning in &self.warnings {
+            writeln!(out, "warning\t{}", escape(warning)).unwrap();
+        }
+
+        for (key, value) in &self.metadata {
+            writeln!(
+                out,
+                "meta\t{}\t{}\t{}",
+                escape(key),
+                value.type_name(),
+                escape(&value.to_string())
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    // Parses a serialized result. The function keys of the result are not addresses: they are
+    // assigned sequentially.
+    pub(crate) fn deserialize(s: &str) -> Result<Self, String> {
+        let mut lines = s.lines().enumerate();
+
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            _ => return Err("not a serialized profiling result".to_owned()),
+        }
+
+        let mut result = Self::new(&Config::default());
+
+        for (i, line) in lines {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let malformed = || format!("malformed profiling result (line {})", i + 1);
+
+            match fields[..] {
+                ["time", time] => {
+                    result.total_time = Some(parse_duration(time).ok_or_else(malformed)?);
+                }
+                ["function", calls, total_time, total_self_time, ref name @ ..] => {
+                    let name = match *name {
+                        [] => None,
+                        [domain, source_type, source, line, function_type, name] => {
+                            Some(FunctionName {
+                                name: none_if_empty(unescape(name)),
+                                function_type: none_if_empty(unescape(function_type)),
+                                source_type: SourceType::from_str(source_type)
+                                    .ok_or_else(malformed)?,
+                                source: unescape(source).into(),
+                                raw_source: unescape(source).into(),
+                                line: match line {
+                                    "" => None,
+                                    line => Some(line.parse().map_err(|_| malformed())?),
+                                },
+                                domain: unescape(domain),
+                                upvalues: None,
+                            })
+                        }
+                        _ => return Err(malformed()),
+                    };
+
+                    let mut entry = ProfileEntry::new(name);
+                    entry.recursion_depth = 0;
+                    entry.max_recursion = 0;
+                    entry.calls = calls.parse().map_err(|_| malformed())?;
+                    entry.total_time = parse_duration(total_time).ok_or_else(malformed)?;
+                    entry.total_self_time =
+                        parse_duration(total_self_time).ok_or_else(malformed)?;
+
+                    let key = FunctionKey(result.data.len() + 1);
+                    result.data.insert(key, entry);
+                }
+                ["label", label, time] => {
+                    let time = parse_duration(time).ok_or_else(malformed)?;
+                    result.labels.insert(unescape(label), time);
+                }
+                ["warning", warning] => result.warnings.push(unescape(warning)),
+                ["meta", key, type_name, value] => {
+                    let value = MetaValue::from_type_name(type_name, &unescape(value))
+                        .ok_or_else(malformed)?;
+                    result.metadata.insert(unescape(key), value);
+                }
+                _ => return Err(malformed()),
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Adds up the data of a deserialized result. Functions are matched by `merge_key`; the ones
+    // without a name are never merged.
+    pub(crate) fn merge(&mut self, other: &ProfilingResult) {
+        // maps the keys of `other` to the keys of the merged entries
+        let mut mapping = HashMap::with_capacity(other.data.len());
+
+        let mut keys = self
+            .data
+            .iter()
+            .filter_map(|(k, v)| v.name.as_ref().map(|name| (name.merge_key(), *k)))
+            .collect::<HashMap<_, _>>();
+
+        for (other_key, v) in &other.data {
+            let merge_key = v.name.as_ref().map(FunctionName::merge_key);
+
+            let key = match merge_key.as_ref().and_then(|k| keys.get(k)) {
+                Some(key) => *key,
+                None => {
+                    let key = FunctionKey(self.data.len() + 1);
+                    let mut entry = ProfileEntry::new(v.name.clone());
+                    entry.calls = 0;
+                    entry.recursion_depth = 0;
+                    entry.max_recursion = 0;
+                    self.data.insert(key, entry);
+
+                    if let Some(merge_key) = merge_key {
+                        keys.insert(merge_key, key);
+                    }
+
+                    key
+                }
+            };
+
+            mapping.insert(*other_key, key);
+
+            let entry = self.data.get_mut(&key).unwrap();
+            self.saturated |= entry.calls.add_saturating(v.calls)
+                | entry.total_time.add_saturating(v.total_time)
+                | entry.total_self_time.add_saturating(v.total_self_time)
+                | entry.cumulative_time.add_saturating(v.cumulative_time)
+                | entry.setup_time.add_saturating(v.setup_time)
+                | entry.teardown_time.add_saturating(v.teardown_time);
+            entry.memory.add(&v.memory);
+
+            for (&count, &calls) in &v.arg_counts {
+                self.saturated |= entry
+                    .arg_counts
+                    .entry(count)
+                    .or_default()
+                    .add_saturating(calls);
+            }
+
+            entry.max_recursion = entry.max_recursion.max(v.max_recursion);
+        }
+
+        if let Some(ref window) = other.window {
+            self.window
+                .get_or_insert_with(|| Window::new(window.length))
+                .merge(window, &mapping);
+        }
+
+        self.tracks_memory |= other.tracks_memory;
+        self.tracks_live_bytes |= other.tracks_live_bytes;
+        self.tracks_segments |= other.tracks_segments;
+        self.tracks_arg_counts |= other.tracks_arg_counts;
+        self.saturated |= other.saturated;
+        self.max_stack_depth = self.max_stack_depth.max(other.max_stack_depth);
+        self.deadline_exceeded |= other.deadline_exceeded;
+        self.instruction_limit_exceeded |= other.instruction_limit_exceeded;
+        self.abandoned |= other.abandoned;
+        self.errored |= other.errored;
+        self.clock_anomalies = self.clock_anomalies.saturating_add(other.clock_anomalies);
+        if other.hook_attribution != HookTime::Included {
+            self.hook_attribution = other.hook_attribution;
+        }
+
+        self.saturated |= self.hook_time.add_saturating(other.hook_time)
+            | self.hook_body_time.add_saturating(other.hook_body_time)
+            | self.hook_body_events.add_saturating(other.hook_body_events)
+            | self.ignored_calls.add_saturating(other.ignored_calls)
+            | self.ignored_time.add_saturating(other.ignored_time);
+        self.latencies.add(&other.latencies);
+        self.memory.add(&other.memory);
+
+        for (label, time) in &other.labels {
+            let total = self.labels.entry(label.clone()).or_default();
+            self.saturated |= total.add_saturating(*time);
+        }
+
+        self.total_time = match (self.total_time, other.total_time) {
+            (Some(lhs), Some(rhs)) => Some(lhs.saturating_add(rhs)),
+            (lhs, rhs) => lhs.or(rhs),
+        };
+
+        self.markers.extend(other.markers.iter().cloned());
+        self.warnings.extend(other.warnings.iter().cloned());
+
+        // the values of the first result win
+        for (key, value) in &other.metadata {
+            self.metadata
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    }
+}
+
+// The change of a function between two results.
+struct Delta {
+    name: String,
+    // added, removed, slower, faster, or same
+    change: &'static str,
+    calls: i64,
+    total_time: f64,
+    total_self_time: f64,
+}
+
+impl Delta {
+    fn added(v: &ProfileEntry) -> Self {
+        Delta {
+            name: v.name.as_ref().map_or_else(String::new, |v| v.to_string()),
+            change: "added",
+            calls: clamped(v.calls),
+            total_time: v.total_time.as_secs_f64(),
+            total_self_time: v.total_self_time.as_secs_f64(),
+        }
+    }
+
+    fn removed(mut self) -> Self {
+        self.change = "removed";
+        self.calls = self.calls.saturating_neg();
+        self.total_time = -self.total_time;
+        self.total_self_time = -self.total_self_time;
+
+        self
+    }
+}
+
+impl ProfilingResult {
+    // Adds up the functions by `merge_key`, since the ones defined on the same line share it, and
+    // returns the sums along with the unnamed functions, which have no key.
+    fn by_merge_key(&self) -> (HashMap<String, Delta>, Vec<Delta>) {
+        let mut groups = HashMap::<String, Delta>::new();
+        let mut unnamed = Vec::new();
+
+        for v in self.data.values() {
+            let delta = Delta::added(v);
+
+            let key = match v.name {
+                Some(ref name) => name.merge_key(),
+                None => {
+                    unnamed.push(delta);
+                    continue;
+                }
+            };
+
+            match groups.get_mut(&key) {
+                Some(group) => {
+                    // the first name in order, so that the report doesn't change from run to run
+                    if delta.name < group.name {
+                        group.name = delta.name;
+                    }
+
+                    group.calls = group.calls.saturating_add(delta.calls);
+                    group.total_time += delta.total_time;
+                    group.total_self_time += delta.total_self_time;
+                }
+                None => {
+                    groups.insert(key, delta);
+                }
+            }
+        }
+
+        (groups, unnamed)
+    }
+
+    // Compares the functions with the ones of `after`, matching them by `merge_key`. The deltas
+    // of the functions present in only one of the results are their values, negated if removed.
+    fn diff(&self, after: &ProfilingResult) -> Vec<Delta> {
+        let (before, before_unnamed) = self.by_merge_key();
+        let (mut after_by_key, after_unnamed) = after.by_merge_key();
+
+        let mut deltas = Vec::new();
+
+        for (key, v) in before {
+            let mut d = match after_by_key.remove(&key) {
+                Some(other) => other,
+                None => {
+                    deltas.push(v.removed());
+                    continue;
+                }
+            };
+
+            d.calls = d.calls.saturating_sub(v.calls);
+            d.total_time -= v.total_time;
+            d.total_self_time -= v.total_self_time;
+            d.change = match d.total_self_time {
+                t if t > 0.0 => "slower",
+                t if t < 0.0 => "faster",
+                _ => "same",
+            };
+
+            deltas.push(d);
+        }
+
+        // the unnamed functions can't be matched, so they are all new or all gone
+        deltas.extend(before_unnamed.into_iter().map(Delta::removed));
+        deltas.extend(after_by_key.into_values().chain(after_unnamed));
+
+        // the worst regressions first
+        deltas.sort_by(|lhs, rhs| rhs.total_self_time.total_cmp(&lhs.total_self_time));
+
+        deltas
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+
+    result
+}
+
+fn none_if_empty(s: String) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    s.parse().ok().map(Duration::from_nanos)
+}
+
+fn check_result(state: &mut State, arg: i32) -> ProfilingResult {
+    let s = check_string_lossy(state, arg);
+
+    match ProfilingResult::deserialize(&s) {
+        Ok(result) => result,
+        Err(msg) => {
+            state.arg_error(arg, &msg);
+            unreachable!()
+        }
+    }
+}
+
+// lprofile.deserialize(s): converts a serialized result to a table.
+pub fn deserialize(state: &mut State) -> i32 {
+    check_result(state, 1).move_to_lua(state, None)
+}
+
+// lprofile.diff(before, after): compares two serialized results function by function.
+pub fn diff(state: &mut State) -> i32 {
+    let before = check_result(state, 1);
+    let after = check_result(state, 2);
+    let deltas = before.diff(&after);
+
+    state.create_table(deltas.len() as i32, 0);
+
+    for (i, d) in deltas.iter().enumerate() {
+        state.create_table(0, 5);
+
+        state.push("name");
+        state.push(d.name.as_str());
+        state.set_table(-3);
+
+        state.push("change");
+        state.push(d.change);
+        state.set_table(-3);
+
+        state.push("calls");
+        state.push(d.calls);
+        state.set_table(-3);
+
+        state.push("totalTime");
+        state.push(d.total_time);
+        state.set_table(-3);
+
+        state.push("totalSelfTime");
+        state.push(d.total_self_time);
+        state.set_table(-3);
+
+        state.seti(-2, (i + 1) as i64);
+    }
+
+    1
+}
+
+// lprofile.merge(s, ...): merges serialized results into one.
+pub fn merge(state: &mut State) -> i32 {
+    let mut result = check_result(state, 1);
+
+    for arg in 2..=state.get_top() {
+        let other = check_result(state, arg);
+        result.merge(&other);
+    }
+
+    state.push(result.serialize());
+
+    1
+}
@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Each bucket covers the durations this many times longer than the previous bucket does, so the
+// percentiles are estimated within 2.5%.
+const GROWTH: f64 = 1.05;
+
+// The distribution of the session times, which are the durations of the calls of a wrapped
+// function. The durations are counted in buckets, so the memory used doesn't grow with the number
+// of calls.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Latencies {
+    pub count: u64,
+    pub sum: Duration,
+    pub min: Option<Duration>,
+    pub max: Duration,
+    // the sum of the squared durations in nanoseconds, for the standard deviation
+    sum_squares: u128,
+    buckets: BTreeMap<u32, u64>,
+}
+
+impl Latencies {
+    fn bucket(time: Duration) -> u32 {
+        let nanos = time.as_nanos().max(1) as f64;
+
+        (nanos.ln() / GROWTH.ln()) as u32
+    }
+
+    // the middle of the bucket
+    fn bucket_time(bucket: u32) -> Duration {
+        Duration::from_nanos(GROWTH.powf(bucket as f64 + 0.5) as u64)
+    }
+
+    pub(crate) fn record(&mut self, time: Duration) {
+        self.count += 1;
+        self.sum += time;
+        self.min = Some(self.min.map_or(time, |min| min.min(time)));
+        self.max = self.max.max(time);
+        self.sum_squares += time.as_nanos() * time.as_nanos();
+        *self.buckets.entry(Self::bucket(time)).or_default() += 1;
+    }
+
+    pub(crate) fn add(&mut self, other: &Latencies) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = match (self.min, other.min) {
+            (Some(lhs), Some(rhs)) => Some(lhs.min(rhs)),
+            (lhs, rhs) => lhs.or(rhs),
+        };
+        self.max = self.max.max(other.max);
+        self.sum_squares += other.sum_squares;
+
+        for (&bucket, &count) in &other.buckets {
+            *self.buckets.entry(bucket).or_default() += count;
+        }
+    }
+
+    pub(crate) fn mean(&self) -> Option<Duration> {
+        match self.count {
+            0 => None,
+            count => Some(Duration::from_nanos(
+                (self.sum.as_nanos() / count as u128) as u64,
+            )),
+        }
+    }
+
+    // The population standard deviation of the durations.
+    pub(crate) fn stddev(&self) -> Option<Duration> {
+        let mean = self.mean()?.as_nanos() as f64;
+        let variance = self.sum_squares as f64 / self.count as f64 - mean * mean;
+
+        Some(Duration::from_nanos(variance.max(0.0).sqrt() as u64))
+    }
+
+    // Estimates the duration `q` of the calls took at most, `q` being from 0 to 1.
+    pub(crate) fn quantile(&self, q: f64) -> Option<Duration> {
+        let min = self.min?;
+        let rank = ((q * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+
+        for (&bucket, &count) in &self.buckets {
+            seen += count;
+
+            if seen >= rank {
+                return Some(Self::bucket_time(bucket).clamp(min, self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+}
@@ -0,0 +1,226 @@
+use std::mem::ManuallyDrop;
+use std::time::{Duration, Instant};
+
+use lua::{State, Type};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::saturate::SaturatingAdd;
+use crate::{FunctionKey, Profiler, ProfilingResult};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum EventKind {
+    Call,
+    TailCall,
+    Return,
+}
+
+impl EventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Call => "call",
+            EventKind::TailCall => "tail call",
+            EventKind::Return => "return",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "call" => Some(EventKind::Call),
+            "tail call" => Some(EventKind::TailCall),
+            "return" => Some(EventKind::Return),
+            _ => None,
+        }
+    }
+}
+
+// Receives the calls of the profiled code as they begin and end, for the programs that already
+// collect spans and want the Lua calls among them. The key is the one the call is attributed to,
+// whose entry in the result has the function's name, and the times are the ones the frames are
+// timed with: unless the hook's time is charged to the frames, they leave it out. The calls are
+// always ended in the reverse order they began in.
+pub trait EventSink {
+    fn on_enter(&mut self, key: FunctionKey, time: Instant);
+
+    // Also called for the calls unwound by an error, at the event that revealed they ended, and
+    // for the ones still running when the session ends.
+    fn on_exit(&mut self, key: FunctionKey, time: Instant);
+}
+
+// A hook event seen by a session with the `record` option set. Feeding the events of a session to
+// `ProfilerBuilder::replay` reproduces its calls, call tree, and total times without running any
+// Lua code, which makes the aggregation testable on fixed input. The self-times of the callers
+// differ by the time the hook spent on the calls, which the session leaves out.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    // the key the call is attributed to, which is the `id` of its entry; 0 for returns and for
+    // the entry of the functions beyond `maxFunctions`
+    pub function: usize,
+    // the depth of the Lua stack the event was received at
+    pub level: usize,
+    // the time since the session start
+    pub time: Duration,
+}
+
+impl Profiler {
+    // Runs the events through the same aggregation as the hooks, at the recorded times. The
+    // options that read the clock on their own (sampling, instruction counting, and the window)
+    // are ignored.
+    pub(crate) fn replay(mut config: Config, events: &[Event]) -> ProfilingResult {
+        config.sample_interval = None;
+        config.count_instructions = false;
+        config.window = None;
+
+        let mut this = Self::with_config(config);
+        let start = Instant::now();
+        this.result = Some(ProfilingResult::new(&this.config));
+        this.session_start = Some(start);
+
+        let mut end = start;
+
+        for event in events {
+            let now = start + event.time;
+            end = end.max(now);
+
+            let result = this.result.as_mut().unwrap();
+
+            let counter = match event.kind {
+                EventKind::Call => &mut result.call_events,
+                EventKind::TailCall => &mut result.tail_call_events,
+                EventKind::Return => &mut result.return_events,
+            };
+
+            result.saturated |= counter.add_saturating(1);
+
+            if event.kind == EventKind::Return {
+                this.pop_frames(event.level, now);
+
+                continue;
+            }
+
+            this.enter_level(event.level, event.kind == EventKind::TailCall, now);
+            this.suspend_caller(now);
+
+            let key = this
+                .result
+                .as_ref()
+                .unwrap()
+                .tracked_key(FunctionKey(event.function));
+            this.push_frame(key, event.level, now);
+        }
+
+        // the frames still open ran until the last event
+        this.close_frames_above(0, false, end);
+
+        let mut result = this.result.take().unwrap();
+        let total_time = end - start;
+        result.total_time = Some(total_time);
+        result.latencies.record(total_time);
+
+        result
+    }
+
+    // profiler:events(): returns the events recorded during the last finished session, or nil if
+    // the `record` option is not set.
+    pub(crate) fn events(state: &mut State) -> i32 {
+        // Safety: guaranteed by Lua.
+        let this: &mut ManuallyDrop<Self> =
+            unsafe { state.check_userdata_typed(1, Self::TYPE_NAME) };
+
+        let events = match this.finished.as_ref() {
+            Some(result) => result.events.clone(),
+            None => Error::NotFinished.raise(state, "no finished session"),
+        };
+
+        match events {
+            Some(events) => push_events(state, &events),
+            None => state.push_nil(),
+        }
+
+        1
+    }
+}
+
+fn push_events(state: &mut State, events: &[Event]) {
+    state.create_table(events.len() as i32, 0);
+
+    for (i, event) in events.iter().enumerate() {
+        state.create_table(0, 4);
+
+        state.push("event");
+        state.push(event.kind.as_str());
+        state.set_table(-3);
+
+        if event.kind != EventKind::Return {
+            state.push("id");
+            state.push(FunctionKey(event.function).id());
+            state.set_table(-3);
+        }
+
+        state.push("level");
+        state.push(event.level as i64);
+        state.set_table(-3);
+
+        state.push("nanos");
+        state.push(event.time.as_nanos().min(i64::MAX as u128) as i64);
+        state.set_table(-3);
+
+        state.seti(-2, (i + 1) as i64);
+    }
+}
+
+// Reads the array of event tables at `arg`, in the format of `profiler:events`.
+fn check_events(state: &mut State, arg: i32) -> Vec<Event> {
+    state.check_type(arg, Type::Table);
+
+    let len = state.raw_len(arg);
+    let mut events = Vec::with_capacity(len);
+
+    for i in 1..=len {
+        let malformed = format!("malformed event #{}", i);
+
+        let is_table = state.raw_geti(arg, i as i64) == Type::Table;
+        state.arg_check(is_table, arg, &malformed);
+
+        state.get_field(-1, "event");
+        let kind = state.to_str(-1).and_then(EventKind::from_str);
+        state.get_field(-2, "id");
+        let function = state
+            .to_str(-1)
+            .and_then(FunctionKey::from_id)
+            .map(|key| key.0);
+        state.get_field(-3, "level");
+        let level = state.to_integerx(-1).filter(|&level| level > 0);
+        state.get_field(-4, "nanos");
+        let nanos = state.to_integerx(-1).filter(|&nanos| nanos >= 0);
+        state.pop(5);
+
+        let function = match kind {
+            Some(EventKind::Return) => Some(FunctionKey::OTHER.0),
+            _ => function,
+        };
+
+        match (kind, function, level, nanos) {
+            (Some(kind), Some(function), Some(level), Some(nanos)) => events.push(Event {
+                kind,
+                function,
+                level: level as usize,
+                time: Duration::from_nanos(nanos as u64),
+            }),
+            _ => {
+                state.arg_error(arg, &malformed);
+                unreachable!()
+            }
+        }
+    }
+
+    events
+}
+
+// lprofile.replay(events): builds the result table of the recorded events.
+pub fn replay(state: &mut State) -> i32 {
+    let events = check_events(state, 1);
+
+    Profiler::replay(Config::default(), &events).move_to_lua(state, None)
+}
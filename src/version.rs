@@ -0,0 +1,83 @@
+use lua::ffi::{self, lua_Debug};
+use lua::libc::c_int;
+
+// the event Lua 5.1 reports after the return of a function for each frame its tail calls replaced;
+// later versions gave the code to LUA_HOOKTAILCALL
+#[cfg(feature = "lua51")]
+const LUA_HOOKTAILRET: c_int = 4;
+
+// A hook event, as the version of Lua the module is built for reports it. The module follows the
+// hook events and the debug information of Lua 5.2 and 5.3 unless the `lua51` feature is enabled.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum HookEvent {
+    Call,
+    // Lua 5.2 and later: the called function takes the place of its caller on the stack
+    #[cfg(not(feature = "lua51"))]
+    TailCall,
+    Return,
+    // Lua 5.1: a frame replaced by a tail call ends. Lua 5.1 reports the tail call itself as a
+    // plain call, and the replaced frame still counts as a level of the stack until this event,
+    // so the function that made the tail call stays on the stack below the called one.
+    #[cfg(feature = "lua51")]
+    TailReturn,
+    Count,
+    Line,
+}
+
+impl HookEvent {
+    // Returns the event of the activation record, or `None` if the code is unknown.
+    pub(crate) fn of(ar: &lua_Debug) -> Option<Self> {
+        match ar.event {
+            ffi::LUA_HOOKCALL => Some(HookEvent::Call),
+            #[cfg(not(feature = "lua51"))]
+            ffi::LUA_HOOKTAILCALL => Some(HookEvent::TailCall),
+            ffi::LUA_HOOKRET => Some(HookEvent::Return),
+            #[cfg(feature = "lua51")]
+            LUA_HOOKTAILRET => Some(HookEvent::TailReturn),
+            ffi::LUA_HOOKCOUNT => Some(HookEvent::Count),
+            ffi::LUA_HOOKLINE => Some(HookEvent::Line),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_call(self) -> bool {
+        match self {
+            HookEvent::Call => true,
+            #[cfg(not(feature = "lua51"))]
+            HookEvent::TailCall => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_return(self) -> bool {
+        match self {
+            HookEvent::Return => true,
+            #[cfg(feature = "lua51")]
+            HookEvent::TailReturn => true,
+            _ => false,
+        }
+    }
+
+    // Whether the event is a tail call, or the end of a frame replaced by one.
+    pub(crate) fn is_tail(self) -> bool {
+        match self {
+            #[cfg(not(feature = "lua51"))]
+            HookEvent::TailCall => true,
+            #[cfg(feature = "lua51")]
+            HookEvent::TailReturn => true,
+            _ => false,
+        }
+    }
+}
+
+// Returns the number of the fixed parameters of the function of the activation record filled with
+// the `u` option, and whether it's variadic. Lua 5.1 doesn't report them.
+#[cfg(not(feature = "lua51"))]
+pub(crate) fn parameters(ar: &lua_Debug) -> Option<(c_int, bool)> {
+    Some((ar.nparams as c_int, ar.isvararg != 0))
+}
+
+#[cfg(feature = "lua51")]
+pub(crate) fn parameters(_ar: &lua_Debug) -> Option<(c_int, bool)> {
+    None
+}
@@ -0,0 +1,116 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::FunctionKey;
+
+// The number of buckets a window is split into: the data ages out a tenth of the window at a time.
+const BUCKETS: u32 = 10;
+
+// What a function did during a bucket.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Activity {
+    pub calls: usize,
+    pub self_time: Duration,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Bucket {
+    start: Instant,
+    activity: HashMap<FunctionKey, Activity>,
+}
+
+// The data of the last `length` of the session (or sessions, if merged), kept in time buckets so
+// that the old data can be dropped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Window {
+    pub length: Duration,
+    buckets: VecDeque<Bucket>,
+}
+
+impl Window {
+    pub(crate) fn new(length: Duration) -> Self {
+        Self {
+            length,
+            buckets: VecDeque::new(),
+        }
+    }
+
+    // Returns the activity of the function in the current bucket, starting a new one if the
+    // current bucket is over.
+    pub(crate) fn activity(&mut self, key: FunctionKey) -> &mut Activity {
+        let now = Instant::now();
+        let over = match self.buckets.back() {
+            Some(bucket) => now - bucket.start >= self.length / BUCKETS,
+            None => true,
+        };
+
+        if over {
+            self.prune(now);
+            self.buckets.push_back(Bucket {
+                start: now,
+                activity: HashMap::new(),
+            });
+        }
+
+        let bucket = self.buckets.back_mut().unwrap();
+
+        bucket.activity.entry(key).or_default()
+    }
+
+    // Drops the buckets that started more than a window ago.
+    fn prune(&mut self, now: Instant) {
+        while let Some(bucket) = self.buckets.front() {
+            if now.saturating_duration_since(bucket.start) <= self.length {
+                return;
+            }
+
+            self.buckets.pop_front();
+        }
+    }
+
+    // Adds up the activity within the window, and returns it with the time it covers.
+    pub(crate) fn recent(&self) -> (Duration, HashMap<FunctionKey, Activity>) {
+        let now = Instant::now();
+        let mut result = HashMap::<FunctionKey, Activity>::new();
+        let mut covered = Duration::new(0, 0);
+
+        for bucket in &self.buckets {
+            let age = now.saturating_duration_since(bucket.start);
+
+            if age > self.length {
+                continue;
+            }
+
+            covered = covered.max(age);
+
+            for (key, activity) in &bucket.activity {
+                let total = result.entry(*key).or_default();
+                total.calls += activity.calls;
+                total.self_time += activity.self_time;
+            }
+        }
+
+        (covered, result)
+    }
+
+    // Adds the buckets of another window, whose functions are mapped by `keys`.
+    pub(crate) fn merge(&mut self, other: &Window, keys: &HashMap<FunctionKey, FunctionKey>) {
+        for bucket in &other.buckets {
+            let activity = bucket
+                .activity
+                .iter()
+                .filter_map(|(key, activity)| keys.get(key).map(|key| (*key, *activity)))
+                .collect();
+
+            self.buckets.push_back(Bucket {
+                start: bucket.start,
+                activity,
+            });
+        }
+
+        self.buckets
+            .make_contiguous()
+            .sort_by_key(|bucket| bucket.start);
+        self.prune(Instant::now());
+    }
+}